@@ -1,19 +1,94 @@
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::SinkExt;
+use hmac::{Hmac, Mac};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use simple_logger::SimpleLogger;
 use spin_sdk::{
-    http::{IntoResponse, Request, Response},
+    http::{Fields, Method, OutgoingResponse, Request, Response, ResponseOutparam},
     http_component,
     key_value::Store,
     sqlite::{Connection, Value},
     variables,
 };
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::time::Instant;
 
 const DEFAULT_CLEANUP_INTERVAL_MINUTES: i64 = 5;
 const DEFAULT_DELETE_TIMEOUT_MINUTES: i64 = 30;
+const DEFAULT_UNDELETE_WINDOW_MINUTES: i64 = 60;
+const DEFAULT_COLLECTOR_ACK_WINDOW_MINUTES: i64 = 1440;
 const DEFAULT_UPLOAD_INTERVAL_SECONDS: i64 = 300;
+const DEFAULT_MAX_FUTURE_SKEW_SECONDS: i64 = 300;
+const DEFAULT_MAX_COMMAND_BYTES: i64 = 16384;
+const DEFAULT_MAX_PENDING_COMMANDS: i64 = 100;
+const DEFAULT_WEBHOOK_FAILURE_THRESHOLD: i64 = 5;
+const DEFAULT_WEBHOOK_COOLDOWN_SECONDS: i64 = 300;
+/// Ceiling on a gzip request body once inflated, so a small upload cannot expand without bound
+const MAX_DECOMPRESSED_BODY_BYTES: u64 = 1_048_576;
+/// A full `/export/snapshot` page inflates well past the upload limit
+const MAX_DECOMPRESSED_IMPORT_BYTES: u64 = 64 * 1_048_576;
 const MAX_LOG_ITEMS_PER_DOWNLOAD: i64 = 10000;
+/// Byte budget of one `/download/replay` page, so a window of large messages is paged instead of
+/// being built up in memory whole
+const MAX_REPLAY_PAGE_BYTES: usize = 8 * 1_048_576;
+const MAX_METRIC_POINTS_PER_QUERY: i64 = 10000;
+const DEFAULT_STREAM_MAX_DURATION_SECONDS: i64 = 300;
+const STREAM_POLL_INTERVAL_MS: u64 = 1000;
+const STREAM_KEEPALIVE_SECONDS: u64 = 15;
+const MAX_STREAM_EVENTS_PER_POLL: i64 = 1000;
+const MAINTENANCE_LOCK_TIMEOUT_SECONDS: i64 = 300;
+/// Allowed clock difference when checking JWT `exp` and `nbf`
+const JWT_CLOCK_SKEW_SECONDS: i64 = 60;
+const MAX_ACK_IDS_PER_REQUEST: usize = 1000;
+const MAX_COMMAND_RESULTS: i64 = 1000;
+const MAX_NODE_ID_FILTER: usize = 100;
+const MAX_IMPORT_ROWS_PER_REQUEST: usize = 50000;
+const IMPORT_CHUNK_SIZE: usize = 500;
+const SNAPSHOT_BATCH_SIZE: usize = 1000;
+/// Budget for one `/export/snapshot` page; the rest follows via the returned cursor
+const SNAPSHOT_TIME_BUDGET_MS: u128 = 10000;
+const RENORMALIZE_BATCH_SIZE: i64 = 1000;
+const MAX_RENORMALIZE_ROWS_PER_REQUEST: i64 = 20000;
+const PURGE_BATCH_SIZE: i64 = 10000;
+const CLEANUP_BATCH_SIZE: i64 = 10000;
+/// Budgets for one cleanup run; a larger backlog drains over the following runs
+const MAX_CLEANUP_ROWS_PER_RUN: usize = 100000;
+const CLEANUP_TIME_BUDGET_MS: u128 = 5000;
+const MAX_PURGE_ROWS_PER_REQUEST: usize = 100000;
+const INGESTION_LAG_SAMPLE_ROWS: i64 = 1000;
+const MAX_WEBHOOK_MESSAGES: usize = 20;
+/// A webhook slower than this counts as failed even when it accepts, as it held up the upload
+const WEBHOOK_SLOW_CALL_MS: u128 = 5000;
+const DEFAULT_NODE_STALE_AFTER_INTERVALS: i64 = 2;
+const DEFAULT_NODE_OFFLINE_AFTER_INTERVALS: i64 = 10;
+const NODE_ID_PLACEHOLDER: &str = "{node_id}";
+const REDACTED_TEXT: &str = "[REDACTED]";
+const REDACT_EVERYTHING_PATTERN: &str = "(?s).+";
+/// Key prefixes of short-lived KV entries (idempotency keys, rate-limit windows). Their values
+/// are JSON objects carrying an RFC 3339 `expires_at`; `sweep_transient_kv` removes them once
+/// that time has passed, so the store stays bounded however many are written.
+const TRANSIENT_KV_PREFIXES: [&str; 2] = ["idempotency:", "rate_limit:"];
+/// How long `POST /command` remembers the response to an `Idempotency-Key`
+const IDEMPOTENCY_KEY_TTL_HOURS: i64 = 24;
+/// Node id used by `/selftest` for its synthetic rows; probes and registration refuse it
+const SELFTEST_NODE_ID: u32 = u32::MAX;
+/// Tables rebuilt by `/maintenance/reindex`: the ones that see bulk deletes
+const REINDEX_TABLES: &[&str] = &["log_messages", "commands"];
+const ACCEPTED_UPLOAD_CONTENT_TYPES: &[&str] = &["application/json"];
+/// Body formats `decode_upload` understands for `/update`, each optionally gzip-compressed
+const ACCEPTED_PROBE_UPLOAD_CONTENT_TYPES: &[&str] = &["application/json", "application/x-ndjson"];
+const ACCEPTED_COMMAND_CONTENT_TYPES: &[&str] = &["application/json"];
+const ACCEPTED_IMPORT_CONTENT_TYPES: &[&str] = &["application/json", "application/x-ndjson"];
+const LOG_LEVELS: &[&str] = &["TRACE", "DEBUG", "INFO", "WARN", "ERROR"];
+const DOWNLOAD_LOG_FIELDS: &[&str] = &["item_id", "timestamp", "node_id", "message", "repeat_count", "source"];
 
 // ============================================================================
 // Data Models
@@ -23,6 +98,14 @@ const MAX_LOG_ITEMS_PER_DOWNLOAD: i64 = 10000;
 struct LogEntry {
     timestamp: String,
     message: String,
+    /// Component within the node that produced the line (radio, sensor, ...)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Metric name when the entry is a measurement (e.g. `temperature`), served by `/metrics/query`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metric: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,532 +113,6323 @@ struct ProbeUploadRequest {
     logs: Vec<LogEntry>,
 }
 
+/// One historical row for `/import`; `level` becomes the `[LEVEL]` prefix when the message lacks one.
+/// `/export/snapshot` writes log rows in this form so they re-import unchanged.
+#[derive(Debug, Deserialize, Serialize)]
+struct ImportEntry {
+    node_id: u32,
+    timestamp: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    level: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metric: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    /// Omitted (1) unless the row stands for coalesced repeats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repeat_count: Option<i64>,
+    /// Original id, written by `/export/snapshot`; a row whose id is already taken is skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+}
+
+/// Non-log lines of a snapshot, as `{"snapshot": {...}}` and `{"queued_command": {...}}`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SnapshotRecord {
+    Snapshot(SnapshotHeader),
+    QueuedCommand(SnapshotCommand),
+}
+
+/// First line of every snapshot page.
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotHeader {
+    schema_version: i64,
+    exported_at: String,
+    /// Cursor this page started from
+    cursor: String,
+}
+
+/// A queued command row; `command` and `condition` are the stored JSON text, kept verbatim.
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotCommand {
+    /// Original id, kept so cursor-mode probes see the same ids; a taken id is skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+    node_id: i64,
+    timestamp: String,
+    command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_node_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    broadcast_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_before: Option<String>,
+}
+
+/// A parsed `/import` body.
+#[derive(Debug, Default)]
+struct ImportBatch {
+    entries: Vec<ImportEntry>,
+    /// Queued commands from a snapshot
+    commands: Vec<SnapshotCommand>,
+    rejected: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportResponse {
+    accepted: usize,
+    rejected: usize,
+    commands: usize,
+    /// Snapshot rows and commands whose id is already stored, e.g. when a page is retried
+    duplicates: usize,
+}
+
+/// `/command/ack` body: a single `command_id` or a batch of `command_ids`.
+#[derive(Debug, Deserialize)]
+struct CommandAckRequest {
+    command_id: Option<i64>,
+    #[serde(default)]
+    command_ids: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandAckResponse {
+    acknowledged: Vec<i64>,
+    not_found: Vec<i64>,
+}
+
+/// `/command/result` body: what a probe reports after executing a delivered command.
+#[derive(Debug, Deserialize)]
+struct CommandResultRequest {
+    /// Queue id of the delivered command, when the probe has it
+    command_id: Option<i64>,
+    command: String,
+    result: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResultResponse {
+    id: i64,
+    /// Whether the result was validated against the command's schema (`false` = stored raw)
+    structured: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct StoredCommandResult {
+    id: i64,
+    node_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_id: Option<i64>,
+    /// Broadcast the answered command belonged to, resolved from `command_id` when stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcast_id: Option<String>,
+    command: String,
+    result: serde_json::Value,
+    structured: bool,
+    reported_at: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResultsResponse {
+    results: Vec<StoredCommandResult>,
+}
+
+/// Result schemas of the known commands. Fields beyond these are dropped on normalization.
+#[derive(Debug, Deserialize, Serialize)]
+struct LogLevelResult {
+    log_level: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct FilterResult {
+    value: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RunCommandResult {
+    output: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct UpdateResult {
+    success: bool,
+    /// Version running after the update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RebootResult {
+    success: bool,
+}
+
+/// `/sync` body: an upload plus the download cursor. `logs` is handled exactly as by `/update`.
+#[derive(Debug, Deserialize)]
+struct SyncRequest {
+    last_log_message_id: Option<i64>,
+}
+
+/// `/gateway/sync` body: an upload plus the download position the gateway has durably stored.
+/// `logs` is handled exactly as by `/update`.
+#[derive(Debug, Deserialize)]
+struct GatewaySyncRequest {
+    ack_last_id: Option<i64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Command {
+    /// Queue id of a delivered command, so the probe can reference it (e.g. in `/command/ack`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
     command: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     parameters: Option<serde_json::Value>,
 }
 
+/// A queued command row; `command` is `None` if the stored JSON no longer parses.
+#[derive(Debug, Serialize)]
+struct PendingCommand {
+    id: i64,
+    timestamp: String,
+    command: Option<Command>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_node_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    condition: Option<CommandCondition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcast_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_before: Option<String>,
+}
+
+/// Delivery condition on node metadata; every given clause must match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandCondition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<VersionCondition>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    labels: HashMap<String, String>,
+}
+
+/// Dotted numeric version comparisons against the node's reported version.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VersionCondition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    eq: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ne: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lte: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gte: Option<String>,
+}
+
+/// What the hub knows about a node, as reported on its uploads.
+#[derive(Debug, Default)]
+struct NodeMetadata {
+    version: Option<String>,
+    labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClearQueueResponse {
+    node_id: u32,
+    removed: usize,
+}
+
+/// A cursor-mode command the node has received but not yet acknowledged.
+#[derive(Debug, Serialize)]
+struct UnackedCommand {
+    id: i64,
+    node_id: i64,
+    timestamp: String,
+    delivered_at: String,
+    command: Option<Command>,
+}
+
+#[derive(Debug, Serialize)]
+struct UnackedResponse {
+    commands: Vec<UnackedCommand>,
+}
+
+#[derive(Debug, Serialize)]
+struct PeekResponse {
+    node_id: u32,
+    commands: Vec<PendingCommand>,
+}
+
 #[derive(Debug, Serialize)]
 struct DownloadLogEntry {
     item_id: i64,
     timestamp: String,
     node_id: i64,
     message: String,
+    repeat_count: i64,
+    /// Omitted for rows stored without a source, so existing collectors see unchanged entries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
 }
 
+/// Original `/download` body, served by default (`api_version=v1`) so existing collectors see
+/// no new fields. `T` is `DownloadLogEntry`, or a projected JSON object when `fields=` is requested.
 #[derive(Debug, Serialize)]
-struct DownloadResponse {
-    logs: Vec<DownloadLogEntry>,
+struct DownloadResponseV1<T: Serialize> {
+    #[serde(flatten)]
+    entries: DownloadEntries<T>,
     update_interval: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied_filters: Option<AppliedFilters>,
+}
+
+/// `/download` body with paging metadata, served with `api_version=v2`.
+#[derive(Debug, Serialize)]
+struct DownloadResponseV2<T: Serialize> {
+    #[serde(flatten)]
+    entries: DownloadEntries<T>,
+    update_interval: i64,
+    #[serde(flatten)]
+    paging: PagingInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied_filters: Option<AppliedFilters>,
+}
+
+/// Paging metadata of a v2 `/download` body.
+#[derive(Debug, Serialize)]
+struct PagingInfo {
+    has_more: bool,
+    /// Highest `item_id` in the page, to send as the next `last_log_message_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_item_id: Option<i64>,
+    /// Rows in the page that could not be returned; `last_item_id` still moves past them
+    skipped: usize,
+}
+
+/// JSON shape of `/download`, selected with `api_version=`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ApiVersion {
+    V1,
+    V2,
+}
+
+/// Serialized as `"logs": [...]`, or `"nodes": {"<node_id>": [...]}` with `group_by=node`.
+#[derive(Debug, Serialize)]
+enum DownloadEntries<T: Serialize> {
+    #[serde(rename = "logs")]
+    Flat(Vec<T>),
+    #[serde(rename = "nodes")]
+    ByNode(BTreeMap<i64, Vec<T>>),
+}
+
+impl<T: Serialize> DownloadEntries<T> {
+    /// `node_ids` holds the node of each item, in page order.
+    fn new(node_ids: &[i64], items: Vec<T>, group_by_node: bool) -> Self {
+        if !group_by_node {
+            return DownloadEntries::Flat(items);
+        }
+        // Each node's list keeps the page order
+        let mut nodes: BTreeMap<i64, Vec<T>> = BTreeMap::new();
+        for (&node_id, item) in node_ids.iter().zip(items) {
+            nodes.entry(node_id).or_default().push(item);
+        }
+        DownloadEntries::ByNode(nodes)
+    }
+}
+
+/// Echo of the resolved `/download` query, returned with `debug=true`.
+#[derive(Debug, Serialize)]
+struct AppliedFilters {
+    last_id: i64,
+    cutoff: Option<String>,
+    from: Option<String>,
+    node_ids: Vec<i64>,
+    order: &'static str,
+    limit: i64,
+    fields: Option<Vec<String>>,
+    prefix: Option<String>,
+    q: Option<String>,
+    source: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayResponse {
+    logs: Vec<DownloadLogEntry>,
+    has_more: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct MetricPoint {
+    node_id: i64,
+    timestamp: String,
+    value: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct MetricQueryResponse {
+    metric: String,
+    points: Vec<MetricPoint>,
+    has_more: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandLatencyStats {
+    node_id: i64,
+    deliveries: i64,
+    min_ms: i64,
+    avg_ms: f64,
+    max_ms: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    command_latency: Vec<CommandLatencyStats>,
 }
 
 #[derive(Debug, Deserialize)]
 struct CommandRequest {
+    #[serde(default)]
     command: String,
     parameters: Option<serde_json::Value>,
+    template: Option<String>,
+    overrides: Option<serde_json::Value>,
+    group: Option<String>,
+    min_node_version: Option<String>,
+    condition: Option<serde_json::Value>,
+    not_before: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 struct UpdateResponse {
     commands: Vec<Command>,
     update_interval: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampled_out: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    too_old_rejected: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redacted: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_command_id: Option<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UpdateIntervalConfig {
-    start_time: u64,
-    end_time: u64,
-    active_period: i64,
-    inactive_period: i64,
+/// `/sync` response: the `/update` fields plus one download page.
+#[derive(Debug, Serialize)]
+struct SyncResponse {
+    #[serde(flatten)]
+    update: UpdateResponse,
+    logs: Vec<DownloadLogEntry>,
+    has_more: bool,
 }
 
-// ============================================================================
-// Database Operations
-// ============================================================================
+/// `/gateway/sync` response: the `/update` fields plus the effective ack.
+#[derive(Debug, Serialize)]
+struct GatewaySyncResponse {
+    #[serde(flatten)]
+    update: UpdateResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acked_id: Option<i64>,
+}
 
-fn init_database(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS log_messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp TEXT NOT NULL,
-            node_id INTEGER NOT NULL,
-            message TEXT NOT NULL
-        )",
-        &[],
-    )?;
+#[derive(Debug, Serialize)]
+struct CommandResponse {
+    status: String,
+    commands_created: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcast_id: Option<String>,
+}
 
-    // Create index on timestamp for efficient sorting and filtering
-    conn.execute("CREATE INDEX IF NOT EXISTS idx_log_messages_timestamp ON log_messages(timestamp)", &[])?;
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+    message: String,
+    /// Position of the offending entry in the request, for errors about one entry of a batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+}
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS commands (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp TEXT NOT NULL,
-            node_id INTEGER NOT NULL,
-            command TEXT NOT NULL
-        )",
-        &[],
-    )?;
+#[derive(Debug, Deserialize)]
+struct CursorResetRequest {
+    #[serde(default)]
+    last_log_message_id: i64,
+}
 
-    Ok(())
+#[derive(Debug, Serialize)]
+struct CursorResponse {
+    collector_id: String,
+    last_log_message_id: i64,
 }
 
-fn insert_log_messages(conn: &Connection, node_id: u32, logs: &[LogEntry]) -> Result<()> {
-    for log in logs {
-        log::trace!("Inserting log message for node_id {}: {}", node_id, log.message);
-        _ = conn.execute(
-            "INSERT INTO log_messages (timestamp, node_id, message) VALUES (?, ?, ?)",
-            &[
-                Value::Text(log.timestamp.clone()),
-                Value::Integer(node_id as i64),
-                Value::Text(log.message.clone()),
-            ],
-        )?;
-    }
-    Ok(())
+#[derive(Debug, Serialize)]
+struct CollectorAckResponse {
+    collector_id: String,
+    /// Highest id this collector has acknowledged so far (acks never move backwards)
+    acked_id: i64,
 }
 
-fn get_and_delete_commands(conn: &Connection, node_id: u32) -> Result<Vec<Command>> {
-    let result = conn.execute(
-        "SELECT id, command FROM commands WHERE node_id = ? ORDER BY id",
-        &[Value::Integer(node_id as i64)],
-    )?;
-    let mut commands = Vec::new();
-    for row in result.rows() {
-        if let Some(command_json) = row.get::<&str>("command") {
-            if let Ok(cmd) = serde_json::from_str::<Command>(command_json) {
-                commands.push(cmd);
-            }
-        }
-    }
+/// Push notification target for a node's uploads, stored under `webhook:{node_id}`.
+#[derive(Debug, Serialize, Deserialize)]
+struct WebhookConfig {
+    url: String,
+    /// Only notify for messages at or above this level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_level: Option<String>,
+}
 
-    // Delete the commands
-    conn.execute("DELETE FROM commands WHERE node_id = ?", &[Value::Integer(node_id as i64)])?;
+/// Consecutive failures of one webhook URL, stored under `webhook_circuit:{url}`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WebhookCircuit {
+    consecutive_failures: i64,
+    /// Set while the circuit is open; calls are retried once the cooldown has passed since then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    opened_at: Option<String>,
+}
 
-    Ok(commands)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WebhookCircuitState {
+    Closed,
+    /// Notifications are suppressed until `retry_at`
+    Open { retry_at: DateTime<Utc> },
+    /// Cooldown over: the next call is a trial that closes or re-opens the circuit
+    HalfOpen,
 }
 
-fn cleanup_old_data(conn: &Connection, delete_timeout_minutes: i64) -> Result<()> {
-    log::debug!("Cleaning up old data older than {} minutes.", delete_timeout_minutes);
-    let cutoff_time = Utc::now() - chrono::Duration::minutes(delete_timeout_minutes);
-    let cutoff_str = cutoff_time.to_rfc3339();
+#[derive(Debug, Serialize)]
+struct WebhookPayload {
+    node_id: u32,
+    received_at: String,
+    matched: usize,
+    /// The first `MAX_WEBHOOK_MESSAGES` matching entries, redacted like stored messages
+    logs: Vec<LogEntry>,
+}
 
-    conn.execute(
-        "DELETE FROM log_messages WHERE id IN (SELECT id FROM log_messages WHERE timestamp < ? LIMIT 10000)",
-        &[Value::Text(cutoff_str.clone())],
-    )?;
+#[derive(Debug, Serialize)]
+struct WebhookResponse {
+    status: String,
+    node_id: u32,
+}
 
-    // Count remaining log messages
-    let log_count_result = conn.execute("SELECT COUNT(*) as count FROM log_messages", &[])?;
-    if let Some(row) = log_count_result.rows().next() {
-        if let Some(count) = row.get::<i64>("count") {
-            if count > 0 {
-                log::debug!("Remaining log messages after cleanup: {}", count);
-            }
-        }
-    }
+#[derive(Debug, Serialize)]
+struct TemplateResponse {
+    status: String,
+    template: String,
+}
 
-    conn.execute(
-        "DELETE FROM commands WHERE id IN (SELECT id FROM commands WHERE timestamp < ? LIMIT 10000)",
-        &[Value::Text(cutoff_str)],
-    )?;
+/// Named set of nodes a command can target with `"group"` instead of one `node_id`.
+#[derive(Debug, Serialize, Deserialize)]
+struct NodeGroup {
+    node_ids: Vec<u32>,
+}
 
-    // Count remaining commands
-    let cmd_count_result = conn.execute("SELECT COUNT(*) as count FROM commands", &[])?;
-    if let Some(row) = cmd_count_result.rows().next() {
-        if let Some(count) = row.get::<i64>("count") {
-            if count > 0 {
-                log::debug!("Remaining commands after cleanup: {}", count);
-            }
-        }
-    }
+#[derive(Debug, Serialize)]
+struct NodeGroupResponse {
+    status: String,
+    group: String,
+    node_ids: Vec<u32>,
+}
 
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct RegisterNodeRequest {
+    node_id: u32,
 }
 
-fn get_logs_for_download(conn: &Connection, last_id: i64, max_upload_interval: i64) -> Result<Vec<DownloadLogEntry>> {
-    let cutoff_time = Utc::now() - chrono::Duration::seconds((max_upload_interval as f64 * 1.1) as i64);
-    let cutoff_str = cutoff_time.to_rfc3339();
+#[derive(Debug, Serialize)]
+struct RegisterNodeResponse {
+    node_id: u32,
+    secret: String,
+}
 
-    log::debug!(
-        "Fetching logs for download: last_id={}, cutoff_time={}, current_time={}",
-        last_id,
-        cutoff_str,
-        Utc::now().to_rfc3339()
-    );
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum NodeHealth {
+    Healthy,
+    Stale,
+    Offline,
+}
 
-    let result = conn.execute(
-        "SELECT id, timestamp, node_id, message FROM log_messages 
-         WHERE id > ? AND timestamp < ?
-         ORDER BY timestamp ASC, id ASC LIMIT ?",
-        &[Value::Integer(last_id), Value::Text(cutoff_str), Value::Integer(MAX_LOG_ITEMS_PER_DOWNLOAD)],
-    )?;
+/// Overdue multiples of the expected upload interval at which a node turns stale / offline.
+#[derive(Debug)]
+struct HealthThresholds {
+    stale_after_intervals: i64,
+    offline_after_intervals: i64,
+}
 
-    log::debug!("Fetched {} logs for download.", result.rows().count());
+impl HealthThresholds {
+    fn from_variables() -> Self {
+        HealthThresholds {
+            stale_after_intervals: get_i64_variable("node_stale_after_intervals", DEFAULT_NODE_STALE_AFTER_INTERVALS),
+            offline_after_intervals: get_i64_variable("node_offline_after_intervals", DEFAULT_NODE_OFFLINE_AFTER_INTERVALS),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct NodeInfo {
+    node_id: i64,
+    /// `None` for a node registered but not yet uploading
+    last_seen: Option<String>,
+    version: Option<String>,
+    registered: bool,
+    status: NodeHealth,
+    /// Lifetime counters, kept across retention cleanup
+    logs_received: i64,
+    bytes_ingested: i64,
+    last_upload_bytes: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ingestion_lag: Option<LagStats>,
+}
+
+/// Delay between a log's probe timestamp and its arrival at the hub, over the node's recent rows.
+#[derive(Debug, Serialize)]
+struct LagStats {
+    samples: i64,
+    avg_seconds: f64,
+    max_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct NodesResponse {
+    nodes: Vec<NodeInfo>,
+    update_interval: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct VersionResponse {
+    crate_version: String,
+    schema_version: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct TimeResponse {
+    server_time: String,
+    epoch_ms: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct SelfTestStep {
+    name: &'static str,
+    passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SelfTestResponse {
+    passed: bool,
+    steps: Vec<SelfTestStep>,
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResponse {
+    deleted: usize,
+    complete: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct UndeleteResponse {
+    restored: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct VacuumResponse {
+    mode: String,
+    size_before: i64,
+    size_after: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct ReindexTiming {
+    table: &'static str,
+    duration_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct ReindexResponse {
+    tables: Vec<ReindexTiming>,
+    duration_ms: u128,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RenormalizeResponse {
+    scanned: i64,
+    fixed: i64,
+    unparseable: i64,
+    last_id: i64,
+    complete: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateIntervalConfig {
+    start_time: u64,
+    end_time: u64,
+    active_period: i64,
+    inactive_period: i64,
+}
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+fn get_i64_variable(name: &str, default: i64) -> i64 {
+    variables::get(name).ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(default)
+}
+
+fn get_bool_variable(name: &str, default: bool) -> bool {
+    variables::get(name).ok().and_then(|v| v.parse::<bool>().ok()).unwrap_or(default)
+}
+
+/// Ingest-time validation settings applied by `insert_log_messages`.
+#[derive(Debug)]
+struct IngestSettings {
+    max_future_skew_seconds: i64,
+    reject_future_timestamps: bool,
+    /// Entries older than this are dropped, as cleanup would delete them right away; 0 = off
+    max_upload_age_minutes: i64,
+    coalesce_repeats: bool,
+    /// Keep 1-in-N messages per upper-case level; levels not listed are always kept
+    sample_rates: HashMap<String, u64>,
+    /// Level prefixed to messages without one; `None` leaves them unchanged
+    default_log_level: Option<String>,
+    /// Per-node `default_log_level` overrides
+    node_default_log_levels: HashMap<u32, String>,
+    /// Matches replaced with `[REDACTED]` before storage
+    redaction_patterns: Vec<Regex>,
+}
+
+impl IngestSettings {
+    fn from_variables() -> Self {
+        IngestSettings {
+            max_future_skew_seconds: get_i64_variable("max_future_skew_seconds", DEFAULT_MAX_FUTURE_SKEW_SECONDS),
+            reject_future_timestamps: get_bool_variable("reject_future_timestamps", false),
+            max_upload_age_minutes: get_i64_variable("max_upload_age_minutes", 0).max(0),
+            coalesce_repeats: get_bool_variable("coalesce_repeats", false),
+            sample_rates: get_sample_rates(),
+            default_log_level: get_default_log_level(),
+            node_default_log_levels: get_node_default_log_levels(),
+            redaction_patterns: get_redaction_patterns(),
+        }
+    }
+
+    fn default_log_level_for(&self, node_id: u32) -> Option<&str> {
+        self.node_default_log_levels.get(&node_id).or(self.default_log_level.as_ref()).map(|level| level.as_str())
+    }
+}
+
+/// How cleanup removes log rows.
+#[derive(Debug)]
+struct RetentionSettings {
+    /// Move removed rows to `deleted_log_messages` instead of deleting them outright
+    soft_delete: bool,
+    /// How long soft-deleted rows can be restored before they are purged
+    undelete_window_minutes: i64,
+    /// Collectors that acked within this window hold back deletion of rows they have not
+    /// acknowledged; 0 ignores acks
+    collector_ack_window_minutes: i64,
+}
+
+impl RetentionSettings {
+    fn from_variables() -> Self {
+        RetentionSettings {
+            soft_delete: get_bool_variable("soft_delete", false),
+            undelete_window_minutes: get_i64_variable("undelete_window_minutes", DEFAULT_UNDELETE_WINDOW_MINUTES),
+            collector_ack_window_minutes: get_i64_variable("collector_ack_window_minutes", DEFAULT_COLLECTOR_ACK_WINDOW_MINUTES).max(0),
+        }
+    }
+}
+
+/// Upper-cased level if it is one of `LOG_LEVELS`.
+fn parse_log_level_name(value: &str) -> Option<String> {
+    let level = value.trim().to_ascii_uppercase();
+    LOG_LEVELS.contains(&level.as_str()).then_some(level)
+}
+
+fn get_default_log_level() -> Option<String> {
+    let raw = variables::get("default_log_level").unwrap_or_default();
+    if raw.trim().is_empty() {
+        return None;
+    }
+    let level = parse_log_level_name(&raw);
+    if level.is_none() {
+        log::warn!("Ignoring invalid default_log_level variable: {}", raw);
+    }
+    level
+}
+
+/// Parses a node id list such as `1,2,10-20`; `None` if any entry is malformed.
+fn parse_node_id_ranges(value: &str) -> Option<Vec<RangeInclusive<u32>>> {
+    value
+        .split(',')
+        .map(|entry| {
+            let entry = entry.trim();
+            match entry.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (start.trim().parse::<u32>().ok()?, end.trim().parse::<u32>().ok()?);
+                    (start <= end).then_some(start..=end)
+                }
+                None => entry.parse::<u32>().ok().map(|id| id..=id),
+            }
+        })
+        .collect()
+}
+
+/// Node ids allowed to upload, or `None` when `allowed_node_ids` is unset (any id).
+/// A malformed value allows no node at all, so a typo locks uploads down instead of opening them.
+fn get_allowed_node_ids() -> Option<Vec<RangeInclusive<u32>>> {
+    let raw = variables::get("allowed_node_ids").unwrap_or_default();
+    if raw.trim().is_empty() {
+        return None;
+    }
+    match parse_node_id_ranges(&raw) {
+        Some(ranges) => Some(ranges),
+        None => {
+            log::error!("Invalid allowed_node_ids variable '{}': rejecting all uploads until it is fixed", raw);
+            Some(Vec::new())
+        }
+    }
+}
+
+/// Parses the `node_default_log_levels` variable, a JSON object such as `{"17": "debug"}`.
+fn get_node_default_log_levels() -> HashMap<u32, String> {
+    let raw = variables::get("node_default_log_levels").unwrap_or_default();
+    if raw.trim().is_empty() {
+        return HashMap::new();
+    }
+    match serde_json::from_str::<HashMap<u32, String>>(&raw) {
+        Ok(levels) => levels
+            .into_iter()
+            .filter_map(|(node_id, level)| match parse_log_level_name(&level) {
+                Some(level) => Some((node_id, level)),
+                None => {
+                    log::warn!("Ignoring invalid node_default_log_levels entry for node_id {}: {}", node_id, level);
+                    None
+                }
+            })
+            .collect(),
+        Err(e) => {
+            log::warn!("Ignoring invalid node_default_log_levels variable: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Compiles the `redaction_patterns` variable, a JSON array of regexes such as
+/// `["[\\w.+-]+@[\\w-]+\\.[\\w.]+"]`. An invalid pattern is replaced by one matching the whole
+/// message, so a typo redacts too much rather than storing what it was meant to hide.
+fn get_redaction_patterns() -> Vec<Regex> {
+    let raw = variables::get("redaction_patterns").unwrap_or_default();
+    if raw.trim().is_empty() {
+        return Vec::new();
+    }
+    let patterns = match serde_json::from_str::<Vec<String>>(&raw) {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            log::error!("Invalid redaction_patterns variable: {}; redacting whole messages until it is fixed", e);
+            vec![REDACT_EVERYTHING_PATTERN.to_string()]
+        }
+    };
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).unwrap_or_else(|e| {
+                log::error!("Invalid redaction pattern '{}': {}; redacting whole messages until it is fixed", pattern, e);
+                Regex::new(REDACT_EVERYTHING_PATTERN).expect("valid fallback pattern")
+            })
+        })
+        .collect()
+}
+
+/// Replaces every match of `patterns` in `message` with `[REDACTED]`; `None` when nothing matched.
+fn redact_message(patterns: &[Regex], message: &str) -> (Option<String>, usize) {
+    let mut redacted: Option<String> = None;
+    let mut count = 0;
+    for pattern in patterns {
+        let current = redacted.as_deref().unwrap_or(message);
+        if pattern.is_match(current) {
+            let replaced = pattern.replace_all(current, |_: &regex::Captures| {
+                count += 1;
+                REDACTED_TEXT
+            });
+            redacted = Some(replaced.into_owned());
+        }
+    }
+    (redacted, count)
+}
+
+/// Parses the `sample_rate` variable, a JSON object such as `{"DEBUG": 10, "TRACE": 100}`.
+fn get_sample_rates() -> HashMap<String, u64> {
+    let raw = variables::get("sample_rate").unwrap_or_default();
+    if raw.trim().is_empty() {
+        return HashMap::new();
+    }
+    match serde_json::from_str::<HashMap<String, u64>>(&raw) {
+        Ok(rates) => rates.into_iter().filter(|(_, rate)| *rate > 1).map(|(level, rate)| (level.to_ascii_uppercase(), rate)).collect(),
+        Err(e) => {
+            log::warn!("Ignoring invalid sample_rate variable: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct IngestReport {
+    inserted: usize,
+    future_clamped: usize,
+    future_rejected: usize,
+    too_old_rejected: usize,
+    coalesced: usize,
+    sampled_out: usize,
+    /// Pattern matches replaced before storage
+    redacted: usize,
+}
+
+// ============================================================================
+// Database Operations
+// ============================================================================
+
+/// Schema changes applied in order on top of the base tables created by `init_database`.
+/// The number of applied migrations is stored in `schema_version`; only append to this list.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE log_messages ADD COLUMN repeat_count INTEGER NOT NULL DEFAULT 1",
+    "ALTER TABLE commands ADD COLUMN min_node_version TEXT",
+    "ALTER TABLE nodes ADD COLUMN secret TEXT",
+    // Rebuild `nodes` so `last_seen` may be NULL for nodes registered before their first upload
+    "CREATE TABLE nodes_rebuilt (
+        node_id INTEGER PRIMARY KEY,
+        last_seen TEXT,
+        version TEXT,
+        secret TEXT
+    )",
+    "INSERT INTO nodes_rebuilt (node_id, last_seen, version, secret) SELECT node_id, last_seen, version, secret FROM nodes",
+    "DROP TABLE nodes",
+    "ALTER TABLE nodes_rebuilt RENAME TO nodes",
+    "INSERT OR IGNORE INTO nodes (node_id, last_seen) SELECT node_id, MAX(timestamp) FROM log_messages GROUP BY node_id",
+    "ALTER TABLE command_deliveries ADD COLUMN command_id INTEGER",
+    // One row per command: cursor-mode redeliveries are not new deliveries (older rows have no id)
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_command_deliveries_command_id ON command_deliveries(command_id)",
+    "ALTER TABLE nodes ADD COLUMN labels TEXT",
+    "ALTER TABLE commands ADD COLUMN condition TEXT",
+    "ALTER TABLE commands ADD COLUMN broadcast_id TEXT",
+    "ALTER TABLE log_messages ADD COLUMN received_at TEXT",
+    "ALTER TABLE commands ADD COLUMN not_before TEXT",
+    "CREATE TABLE IF NOT EXISTS deleted_log_messages (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        node_id INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        repeat_count INTEGER NOT NULL DEFAULT 1,
+        received_at TEXT,
+        deleted_at TEXT NOT NULL
+    )",
+    "ALTER TABLE commands ADD COLUMN delivered_at TEXT",
+    // Single row; the primary key conflict is what makes claiming it atomic
+    "CREATE TABLE IF NOT EXISTS maintenance_lock (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        token TEXT NOT NULL,
+        locked_at TEXT NOT NULL
+    )",
+    "ALTER TABLE nodes ADD COLUMN logs_received INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE nodes ADD COLUMN bytes_ingested INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE nodes ADD COLUMN last_upload_bytes INTEGER NOT NULL DEFAULT 0",
+    "CREATE TABLE IF NOT EXISTS collector_acks (
+        collector_id TEXT PRIMARY KEY,
+        last_id INTEGER NOT NULL,
+        acked_at TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS command_results (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        node_id INTEGER NOT NULL,
+        command_id INTEGER,
+        command TEXT NOT NULL,
+        result TEXT NOT NULL,
+        structured INTEGER NOT NULL DEFAULT 0,
+        reported_at TEXT NOT NULL
+    )",
+    "ALTER TABLE log_messages ADD COLUMN source TEXT",
+    "ALTER TABLE deleted_log_messages ADD COLUMN source TEXT",
+    "ALTER TABLE log_messages ADD COLUMN metric TEXT",
+    "ALTER TABLE log_messages ADD COLUMN value REAL",
+    "ALTER TABLE deleted_log_messages ADD COLUMN metric TEXT",
+    "ALTER TABLE deleted_log_messages ADD COLUMN value REAL",
+    // Partial, so plain log rows cost nothing extra to insert
+    "CREATE INDEX IF NOT EXISTS idx_log_messages_metric ON log_messages(metric, timestamp) WHERE metric IS NOT NULL",
+    "ALTER TABLE command_deliveries ADD COLUMN broadcast_id TEXT",
+    "ALTER TABLE command_results ADD COLUMN broadcast_id TEXT",
+    "ALTER TABLE log_messages ADD COLUMN tenant TEXT",
+    "ALTER TABLE deleted_log_messages ADD COLUMN tenant TEXT",
+    // Partial, so deployments without tenancy do not pay for it on insert
+    "CREATE INDEX IF NOT EXISTS idx_log_messages_tenant ON log_messages(tenant, id) WHERE tenant IS NOT NULL",
+    "ALTER TABLE nodes ADD COLUMN tenant TEXT",
+];
+
+fn get_schema_version(conn: &Connection) -> Result<i64> {
+    let result = conn.execute("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version", &[])?;
+    let version = result.rows().next().and_then(|row| row.get::<i64>("version")).unwrap_or(0);
+    Ok(version)
+}
+
+fn apply_pending_migrations(conn: &Connection) -> Result<()> {
+    let current = get_schema_version(conn)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        log::info!("Applying schema migration {}: {}", index + 1, migration);
+        conn.execute(migration, &[])?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (?)", &[Value::Integer(index as i64 + 1)])?;
+    }
+    Ok(())
+}
+
+fn migrate_database(conn: &Connection) -> Result<()> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", &[])?;
+    if get_schema_version(conn)? >= MIGRATIONS.len() as i64 {
+        return Ok(());
+    }
+
+    // Serialize concurrent migrators; the version is re-read once the write lock is held
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+    match apply_pending_migrations(conn) {
+        Ok(()) => {
+            conn.execute("COMMIT", &[])?;
+            Ok(())
+        }
+        Err(e) => {
+            _ = conn.execute("ROLLBACK", &[]);
+            Err(e)
+        }
+    }
+}
+
+/// Applies the `sqlite_auto_vacuum` and `sqlite_journal_mode` variables. Unset or unknown values
+/// leave the database default. `auto_vacuum` only changes on a database without tables (or after a
+/// full VACUUM), so it must run before the tables are created.
+fn apply_pragmas(conn: &Connection) {
+    const AUTO_VACUUM_MODES: &[&str] = &["none", "full", "incremental"];
+    const JOURNAL_MODES: &[&str] = &["delete", "truncate", "persist", "memory", "wal", "off"];
+
+    let pragmas = [("auto_vacuum", "sqlite_auto_vacuum", AUTO_VACUUM_MODES), ("journal_mode", "sqlite_journal_mode", JOURNAL_MODES)];
+    for (pragma, variable, allowed) in pragmas {
+        let value = variables::get(variable).unwrap_or_default().trim().to_ascii_lowercase();
+        if value.is_empty() {
+            continue;
+        }
+        if !allowed.contains(&value.as_str()) {
+            log::warn!("Ignoring invalid {} '{}': expected one of {}", variable, value, allowed.join(", "));
+            continue;
+        }
+        // Values are whitelisted above; PRAGMA arguments cannot be bound as parameters
+        if let Err(e) = conn.execute(&format!("PRAGMA {} = {}", pragma, value), &[]) {
+            log::warn!("Failed to set PRAGMA {} = {}: {}", pragma, value, e);
+        }
+    }
+}
+
+fn init_database(conn: &Connection) -> Result<()> {
+    apply_pragmas(conn);
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS log_messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            node_id INTEGER NOT NULL,
+            message TEXT NOT NULL
+        )",
+        &[],
+    )?;
+
+    // Create index on timestamp for efficient sorting and filtering
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_log_messages_timestamp ON log_messages(timestamp)", &[])?;
+
+    // Optional index serving prefix= filters; NOCASE because LIKE is case-insensitive by default
+    if get_bool_variable("enable_message_index", false) {
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_log_messages_message ON log_messages(message COLLATE NOCASE)", &[])?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS commands (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            node_id INTEGER NOT NULL,
+            command TEXT NOT NULL
+        )",
+        &[],
+    )?;
+
+    // One row per delivered command, used for the queue latency statistics
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS command_deliveries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            node_id INTEGER NOT NULL,
+            delivered_at TEXT NOT NULL,
+            latency_ms INTEGER NOT NULL
+        )",
+        &[],
+    )?;
+
+    // Metadata about every node that has uploaded, maintained by /update
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS nodes (
+            node_id INTEGER PRIMARY KEY,
+            last_seen TEXT NOT NULL,
+            version TEXT
+        )",
+        &[],
+    )?;
+
+    migrate_database(conn)?;
+
+    // Optional index serving source= filters; created after the migration that adds the column
+    if get_bool_variable("enable_source_index", false) {
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_log_messages_source ON log_messages(source)", &[])?;
+    }
+
+    Ok(())
+}
+
+/// Canonical form of a stored timestamp: UTC with a `Z` suffix, so rows sort correctly as text.
+/// Fractional seconds are kept (in groups of 3 digits, none when zero). Accepts RFC 3339 with any
+/// offset, and `YYYY-MM-DD[T ]HH:MM:SS[.fff]` without one (taken as UTC).
+fn normalize_timestamp(value: &str) -> Option<String> {
+    let value = value.trim();
+    let parsed = match DateTime::parse_from_rfc3339(value) {
+        Ok(parsed) => parsed.with_timezone(&Utc),
+        Err(_) => ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+            .iter()
+            .find_map(|format| chrono::NaiveDateTime::parse_from_str(value, format).ok())?
+            .and_utc(),
+    };
+    Some(parsed.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+/// Extracts the level from a message's `[LEVEL]` prefix.
+fn log_level(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix('[')?;
+    rest.find(']').map(|end| &rest[..end])
+}
+
+/// Deterministic 1-in-`rate` sampling decision, so a retried upload keeps the same entries.
+fn keep_sampled(node_id: u32, log: &LogEntry, rate: u64) -> bool {
+    // FNV-1a over the entry's identity
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let node_bytes = node_id.to_le_bytes();
+    for byte in node_bytes.iter().chain(log.timestamp.as_bytes()).chain(log.message.as_bytes()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash.is_multiple_of(rate)
+}
+
+/// Trimmed source name; blank counts as no source.
+fn normalize_source(source: Option<&str>) -> Option<&str> {
+    source.map(|source| source.trim()).filter(|source| !source.is_empty())
+}
+
+/// One row to store in `log_messages`.
+#[derive(Debug)]
+struct LogRow<'a> {
+    timestamp: String,
+    message: Cow<'a, str>,
+    source: Option<&'a str>,
+    /// Set only for measurements; pure log rows leave both columns NULL
+    metric: Option<&'a str>,
+    value: Option<f64>,
+    /// Set when tenancy is enabled; NULL rows are only visible without tenancy
+    tenant: Option<&'a str>,
+    repeat_count: i64,
+    /// Original id of a restored snapshot row; `None` lets `AUTOINCREMENT` assign one
+    id: Option<i64>,
+}
+
+impl<'a> LogRow<'a> {
+    fn new(timestamp: String, message: Cow<'a, str>, source: Option<&'a str>, metric: Option<&'a str>, value: Option<f64>) -> Self {
+        LogRow {
+            timestamp,
+            message,
+            source: normalize_source(source),
+            // Metric names share the source rules: trimmed, blank means none
+            metric: normalize_source(metric),
+            value,
+            tenant: None,
+            repeat_count: 1,
+            id: None,
+        }
+    }
+}
+
+/// Index of the first entry carrying only one of `metric` and `value`. Such a row could never be
+/// served by `/metrics/query`, so the upload is refused rather than storing it half-specified.
+fn first_incomplete_metric_index(logs: &[LogEntry]) -> Option<usize> {
+    logs.iter().position(|log| log.metric.is_some() != log.value.is_some())
+}
+
+/// Index of the first entry timestamped before its predecessor. Entries whose timestamp does not
+/// parse are left out of the comparison, as they are stored unchanged today.
+fn first_out_of_order_index(logs: &[LogEntry]) -> Option<usize> {
+    let mut previous: Option<DateTime<Utc>> = None;
+    for (index, log) in logs.iter().enumerate() {
+        let Ok(parsed) = DateTime::parse_from_rfc3339(&log.timestamp) else {
+            continue;
+        };
+        let parsed = parsed.with_timezone(&Utc);
+        if previous.is_some_and(|previous| parsed < previous) {
+            return Some(index);
+        }
+        previous = Some(parsed);
+    }
+    None
+}
+
+/// Stores one row; `received_at` is `None` for imported history, which has no meaningful arrival time.
+/// Returns `false` when a row with the requested `id` already exists, leaving it untouched.
+fn insert_log_row(conn: &Connection, node_id: u32, received_at: Option<&str>, row: LogRow) -> Result<bool> {
+    log::trace!("Inserting log message for node_id {} (x{}): {}", node_id, row.repeat_count, row.message);
+    let received_at = match received_at {
+        Some(received_at) => Value::Text(received_at.to_string()),
+        None => Value::Null,
+    };
+    let result = conn.execute(
+        "INSERT INTO log_messages (id, timestamp, node_id, message, repeat_count, received_at, source, metric, value, tenant) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(id) DO NOTHING RETURNING id",
+        &[
+            row.id.map_or(Value::Null, Value::Integer),
+            Value::Text(row.timestamp),
+            Value::Integer(node_id as i64),
+            Value::Text(row.message.into_owned()),
+            Value::Integer(row.repeat_count),
+            received_at,
+            row.source.map_or(Value::Null, |source| Value::Text(source.to_string())),
+            row.metric.map_or(Value::Null, |metric| Value::Text(metric.to_string())),
+            row.value.map_or(Value::Null, Value::Real),
+            row.tenant.map_or(Value::Null, |tenant| Value::Text(tenant.to_string())),
+        ],
+    )?;
+    let stored = result.rows().next().is_some();
+    Ok(stored)
+}
+
+fn insert_log_messages(conn: &Connection, node_id: u32, logs: &[LogEntry], settings: &IngestSettings, tenant: Option<&str>) -> Result<IngestReport> {
+    let received_at = Utc::now();
+    let max_timestamp = received_at + chrono::Duration::seconds(settings.max_future_skew_seconds);
+    let min_timestamp = (settings.max_upload_age_minutes > 0).then(|| received_at - chrono::Duration::minutes(settings.max_upload_age_minutes));
+    let mut report = IngestReport::default();
+
+    let mut rows: Vec<LogRow> = Vec::with_capacity(logs.len());
+    let default_level = settings.default_log_level_for(node_id);
+    for log in logs {
+        // Timestamps too far ahead of server time would never pass the download cutoff nor age out
+        let mut timestamp = log.timestamp.clone();
+        if let Ok(parsed) = DateTime::parse_from_rfc3339(&log.timestamp) {
+            if parsed.with_timezone(&Utc) > max_timestamp {
+                if settings.reject_future_timestamps {
+                    log::debug!("Rejecting future timestamp {} from node_id {}", log.timestamp, node_id);
+                    report.future_rejected += 1;
+                    continue;
+                }
+                log::debug!("Clamping future timestamp {} from node_id {} to receive time", log.timestamp, node_id);
+                timestamp = received_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+                report.future_clamped += 1;
+            }
+            // Replayed backlog this old would be purged by the next cleanup anyway
+            if min_timestamp.is_some_and(|min_timestamp| parsed.with_timezone(&Utc) < min_timestamp) {
+                report.too_old_rejected += 1;
+                continue;
+            }
+        }
+
+        // Level-less messages (older probes) get the configured default level
+        let message = match default_level {
+            Some(level) if log_level(&log.message).is_none() => Cow::Owned(format!("[{}] {}", level, log.message)),
+            _ => Cow::Borrowed(log.message.as_str()),
+        };
+
+        // Chatty levels keep only a fraction of their entries
+        if let Some(rate) = log_level(&message).and_then(|level| settings.sample_rates.get(&level.to_ascii_uppercase())) {
+            if !keep_sampled(node_id, log, *rate) {
+                report.sampled_out += 1;
+                continue;
+            }
+        }
+
+        // Sensitive substrings never reach the database
+        let message = match redact_message(&settings.redaction_patterns, &message) {
+            (Some(redacted), count) => {
+                report.redacted += count;
+                Cow::Owned(redacted)
+            }
+            (None, _) => message,
+        };
+
+        // Consecutive identical messages from the same source collapse into the first occurrence;
+        // measurements never do, as each one is a separate point
+        let row = LogRow {
+            tenant,
+            ..LogRow::new(timestamp, message, log.source.as_deref(), log.metric.as_deref(), log.value)
+        };
+        if settings.coalesce_repeats && row.metric.is_none() && row.value.is_none() {
+            if let Some(last) = rows.last_mut() {
+                if last.message == row.message && last.source == row.source && last.metric.is_none() && last.value.is_none() {
+                    last.repeat_count += 1;
+                    report.coalesced += 1;
+                    continue;
+                }
+            }
+        }
+        rows.push(row);
+    }
+
+    let attempted = rows.len();
+    let received_at_str = received_at.to_rfc3339();
+    for row in rows {
+        let result = insert_log_row(conn, node_id, Some(&received_at_str), row);
+        // One summary line per failed batch, distinguishable from auth and parse rejections
+        if let Err(e) = result {
+            log::warn!(
+                "Log insert failed for node_id {}: {} of {} rows stored before SQLite error: {}",
+                node_id,
+                report.inserted,
+                attempted,
+                e
+            );
+            return Err(e);
+        }
+        report.inserted += 1;
+    }
+    Ok(report)
+}
+
+/// Parses an `/import` body: a JSON array, or one JSON object per line (NDJSON). Snapshot
+/// header lines are skipped and snapshot command lines collected; neither counts toward the row limit.
+fn parse_import_body(body: &[u8]) -> Result<ImportBatch, String> {
+    let text = std::str::from_utf8(body).map_err(|_| "Body is not valid UTF-8".to_string())?;
+    let values: Vec<serde_json::Value> = if text.trim_start().starts_with('[') {
+        serde_json::from_str(text).map_err(|e| format!("Invalid JSON array: {}", e))?
+    } else {
+        // A line that is not JSON at all still counts as one rejected row
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).unwrap_or(serde_json::Value::Null))
+            .collect()
+    };
+    let is_snapshot_record = |value: &serde_json::Value| value.get("snapshot").is_some() || value.get("queued_command").is_some();
+    let rows = values.iter().filter(|value| !is_snapshot_record(value)).count();
+    if rows > MAX_IMPORT_ROWS_PER_REQUEST {
+        return Err(format!("Too many rows: {} exceeds the limit of {}", rows, MAX_IMPORT_ROWS_PER_REQUEST));
+    }
+
+    let mut batch = ImportBatch {
+        entries: Vec::with_capacity(rows),
+        ..ImportBatch::default()
+    };
+    for value in values {
+        if is_snapshot_record(&value) {
+            match serde_json::from_value::<SnapshotRecord>(value) {
+                Ok(SnapshotRecord::Snapshot(header)) => log::debug!("Importing snapshot page (schema {}, cursor {})", header.schema_version, header.cursor),
+                Ok(SnapshotRecord::QueuedCommand(command)) => batch.commands.push(command),
+                Err(_) => batch.rejected += 1,
+            }
+            continue;
+        }
+        // Stored in the canonical UTC form so imported rows sort and filter alongside uploaded ones
+        let entry = serde_json::from_value::<ImportEntry>(value).ok().and_then(|mut entry| {
+            entry.timestamp = normalize_timestamp(&entry.timestamp)?;
+            Some(entry)
+        });
+        // A measurement needs both halves, as on `/update`
+        let entry = entry.filter(|entry| entry.metric.is_some() == entry.value.is_some());
+        match entry {
+            Some(entry) => batch.entries.push(entry),
+            None => batch.rejected += 1,
+        }
+    }
+    Ok(batch)
+}
+
+/// Returns how many rows were stored; rows with an id that is already taken are skipped.
+fn insert_import_chunk(conn: &Connection, entries: &[ImportEntry]) -> Result<usize> {
+    let mut stored = 0;
+    for entry in entries {
+        let message = match &entry.level {
+            Some(level) if log_level(&entry.message).is_none() => format!("[{}] {}", level.to_ascii_uppercase(), entry.message),
+            _ => entry.message.clone(),
+        };
+        let row = LogRow {
+            tenant: normalize_source(entry.tenant.as_deref()),
+            repeat_count: entry.repeat_count.unwrap_or(1).max(1),
+            id: entry.id,
+            ..LogRow::new(entry.timestamp.clone(), Cow::Owned(message), entry.source.as_deref(), entry.metric.as_deref(), entry.value)
+        };
+        if insert_log_row(conn, entry.node_id, None, row)? {
+            stored += 1;
+        }
+    }
+    Ok(stored)
+}
+
+/// Bulk-inserts historical rows as given: no node header, skew clamp, sampling or coalescing.
+/// Each chunk is its own transaction, so a failure keeps the chunks already committed.
+/// Returns the rows stored; the rest of `entries` were duplicates of stored ids.
+fn import_log_messages(conn: &Connection, entries: &[ImportEntry]) -> Result<usize> {
+    let mut imported = 0;
+    for chunk in entries.chunks(IMPORT_CHUNK_SIZE) {
+        conn.execute("BEGIN IMMEDIATE", &[])?;
+        match insert_import_chunk(conn, chunk) {
+            Ok(stored) => {
+                conn.execute("COMMIT", &[])?;
+                imported += stored;
+            }
+            Err(e) => {
+                _ = conn.execute("ROLLBACK", &[]);
+                log::warn!("Import failed after {} of {} rows: {}", imported, entries.len(), e);
+                return Err(e);
+            }
+        };
+    }
+    Ok(imported)
+}
+
+/// Re-queues commands from a snapshot as stored, in one transaction, and returns how many were
+/// queued. Commands whose id is already taken are skipped, so a retried page does not queue them
+/// twice. The pending-command limit does not apply: a restore puts back a queue that already existed.
+fn restore_commands(conn: &Connection, commands: &[SnapshotCommand]) -> Result<usize> {
+    let text = |value: &Option<String>| value.clone().map_or(Value::Null, Value::Text);
+    let mut restored = 0;
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+    for command in commands {
+        let inserted = conn.execute(
+            "INSERT INTO commands (id, timestamp, node_id, command, min_node_version, condition, broadcast_id, not_before) VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO NOTHING RETURNING id",
+            &[
+                command.id.map_or(Value::Null, Value::Integer),
+                Value::Text(command.timestamp.clone()),
+                Value::Integer(command.node_id),
+                Value::Text(command.command.clone()),
+                text(&command.min_node_version),
+                text(&command.condition),
+                text(&command.broadcast_id),
+                text(&command.not_before),
+            ],
+        );
+        match inserted {
+            Ok(result) if result.rows().next().is_some() => restored += 1,
+            Ok(_) => {}
+            Err(e) => {
+                _ = conn.execute("ROLLBACK", &[]);
+                return Err(e.into());
+            }
+        }
+    }
+    conn.execute("COMMIT", &[])?;
+    Ok(restored)
+}
+
+/// Position of a snapshot export: the table being written and the last id already written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SnapshotCursor {
+    Logs(i64),
+    Commands(i64),
+}
+
+impl SnapshotCursor {
+    /// Parses `logs:{id}` or `commands:{id}`.
+    fn parse(value: &str) -> Option<Self> {
+        let (table, id) = value.trim().split_once(':')?;
+        let id = id.parse::<i64>().ok().filter(|id| *id >= 0)?;
+        match table {
+            "logs" => Some(SnapshotCursor::Logs(id)),
+            "commands" => Some(SnapshotCursor::Commands(id)),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotCursor::Logs(id) => write!(f, "logs:{}", id),
+            SnapshotCursor::Commands(id) => write!(f, "commands:{}", id),
+        }
+    }
+}
+
+/// Writes log rows, then queued commands, from `cursor` on as NDJSON, fetching
+/// `SNAPSHOT_BATCH_SIZE` rows at a time. Stops after `MAX_IMPORT_ROWS_PER_REQUEST` rows (so the
+/// page re-imports in one request) or `SNAPSHOT_TIME_BUDGET_MS`, returning the cursor to resume
+/// from; `None` once both tables are done.
+fn write_snapshot_page<W: Write>(conn: &Connection, out: &mut W, mut cursor: SnapshotCursor) -> Result<Option<SnapshotCursor>> {
+    let started = Instant::now();
+    let mut written = 0;
+    loop {
+        if written >= MAX_IMPORT_ROWS_PER_REQUEST || started.elapsed().as_millis() >= SNAPSHOT_TIME_BUDGET_MS {
+            return Ok(Some(cursor));
+        }
+        let limit = Value::Integer(SNAPSHOT_BATCH_SIZE.min(MAX_IMPORT_ROWS_PER_REQUEST - written) as i64);
+        match cursor {
+            SnapshotCursor::Logs(after_id) => {
+                let result = conn.execute(
+                    "SELECT id, timestamp, node_id, message, repeat_count, source, metric, value, tenant FROM log_messages WHERE id > ? ORDER BY id LIMIT ?",
+                    &[Value::Integer(after_id), limit],
+                )?;
+                let mut last_id = None;
+                for row in result.rows() {
+                    let Some(id) = row.get::<i64>("id") else {
+                        continue;
+                    };
+                    last_id = Some(id);
+                    written += 1;
+                    // Rows /import could not take back are left out rather than failing the export
+                    let Some(node_id) = row.get::<i64>("node_id").and_then(|node_id| u32::try_from(node_id).ok()) else {
+                        log::warn!("Leaving log row {} out of the snapshot: unusable node_id", id);
+                        continue;
+                    };
+                    let entry = ImportEntry {
+                        id: Some(id),
+                        node_id,
+                        timestamp: row.get::<&str>("timestamp").unwrap_or_default().to_string(),
+                        message: row.get::<&str>("message").unwrap_or_default().to_string(),
+                        level: None,
+                        source: row.get::<&str>("source").map(|v| v.to_string()),
+                        metric: row.get::<&str>("metric").map(|v| v.to_string()),
+                        value: row.get::<f64>("value"),
+                        tenant: row.get::<&str>("tenant").map(|v| v.to_string()),
+                        repeat_count: row.get::<i64>("repeat_count").filter(|&count| count > 1),
+                    };
+                    serde_json::to_writer(&mut *out, &entry)?;
+                    out.write_all(b"\n")?;
+                }
+                cursor = match last_id {
+                    Some(id) => SnapshotCursor::Logs(id),
+                    None => SnapshotCursor::Commands(0),
+                };
+            }
+            SnapshotCursor::Commands(after_id) => {
+                let result = conn.execute(
+                    "SELECT id, timestamp, node_id, command, min_node_version, condition, broadcast_id, not_before FROM commands WHERE id > ? ORDER BY id LIMIT ?",
+                    &[Value::Integer(after_id), limit],
+                )?;
+                let mut last_id = None;
+                for row in result.rows() {
+                    let (Some(id), Some(node_id)) = (row.get::<i64>("id"), row.get::<i64>("node_id")) else {
+                        continue;
+                    };
+                    last_id = Some(id);
+                    written += 1;
+                    let record = SnapshotRecord::QueuedCommand(SnapshotCommand {
+                        id: Some(id),
+                        node_id,
+                        timestamp: row.get::<&str>("timestamp").unwrap_or_default().to_string(),
+                        command: row.get::<&str>("command").unwrap_or_default().to_string(),
+                        min_node_version: row.get::<&str>("min_node_version").map(|v| v.to_string()),
+                        condition: row.get::<&str>("condition").map(|v| v.to_string()),
+                        broadcast_id: row.get::<&str>("broadcast_id").map(|v| v.to_string()),
+                        not_before: row.get::<&str>("not_before").map(|v| v.to_string()),
+                    });
+                    serde_json::to_writer(&mut *out, &record)?;
+                    out.write_all(b"\n")?;
+                }
+                match last_id {
+                    Some(id) => cursor = SnapshotCursor::Commands(id),
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+}
+
+/// Queued commands for a node in delivery order, without removing them.
+fn peek_commands(conn: &Connection, node_id: u32) -> Result<Vec<PendingCommand>> {
+    let result = conn.execute(
+        "SELECT id, timestamp, command, min_node_version, condition, broadcast_id, not_before FROM commands WHERE node_id = ? ORDER BY timestamp, id",
+        &[Value::Integer(node_id as i64)],
+    )?;
+
+    let mut commands = Vec::new();
+    for row in result.rows() {
+        if let Some(id) = row.get::<i64>("id") {
+            commands.push(PendingCommand {
+                id,
+                timestamp: row.get::<&str>("timestamp").unwrap_or_default().to_string(),
+                command: row.get::<&str>("command").and_then(|json| serde_json::from_str::<Command>(json).ok()),
+                min_node_version: row.get::<&str>("min_node_version").map(|v| v.to_string()),
+                condition: row.get::<&str>("condition").and_then(|json| serde_json::from_str(json).ok()),
+                broadcast_id: row.get::<&str>("broadcast_id").map(|v| v.to_string()),
+                not_before: row.get::<&str>("not_before").map(|v| v.to_string()),
+            });
+        }
+    }
+
+    Ok(commands)
+}
+
+fn version_condition_matches(condition: &VersionCondition, version: Option<&str>) -> bool {
+    let Some(version) = version else {
+        // Nothing to compare against until the node reports a version
+        return false;
+    };
+    let eq = |other: &str| version_at_least(version, other) && version_at_least(other, version);
+    condition.eq.as_deref().is_none_or(eq)
+        && condition.ne.as_deref().is_none_or(|other| !eq(other))
+        && condition.lt.as_deref().is_none_or(|other| !version_at_least(version, other))
+        && condition.lte.as_deref().is_none_or(|other| version_at_least(other, version))
+        && condition.gt.as_deref().is_none_or(|other| !version_at_least(other, version))
+        && condition.gte.as_deref().is_none_or(|other| version_at_least(version, other))
+}
+
+fn condition_matches(condition: &CommandCondition, node: &NodeMetadata) -> bool {
+    condition.version.as_ref().is_none_or(|version| version_condition_matches(version, node.version.as_deref()))
+        && condition.labels.iter().all(|(key, value)| node.labels.get(key) == Some(value))
+}
+
+/// Whether a queued command may be delivered to the node now; otherwise it stays queued
+/// until the node's metadata qualifies or retention cleanup removes it.
+fn is_deliverable(pending: &PendingCommand, node_id: u32, node: &NodeMetadata) -> bool {
+    if let Some(not_before) = &pending.not_before {
+        // Stored canonical, so a text comparison orders correctly
+        if Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true) < *not_before {
+            log::debug!("Holding command {} for node_id {}: scheduled for {}", pending.id, node_id, not_before);
+            return false;
+        }
+    }
+    if let Some(min_version) = &pending.min_node_version {
+        if !node.version.as_deref().is_some_and(|version| version_at_least(version, min_version)) {
+            log::debug!("Holding command {} for node_id {}: requires version {}", pending.id, node_id, min_version);
+            return false;
+        }
+    }
+    if let Some(condition) = &pending.condition {
+        if !condition_matches(condition, node) {
+            log::debug!("Holding command {} for node_id {}: condition not met", pending.id, node_id);
+            return false;
+        }
+    }
+    true
+}
+
+/// Delivers and deletes the node's queued commands. Commands whose version requirement or
+/// condition the node does not meet stay queued until it does or they age out. Only rows that
+/// were actually returned are deleted; one whose stored JSON no longer parses stays queued for
+/// inspection via `/command/peek` instead of being dropped with the rest.
+fn get_and_delete_commands(conn: &Connection, node_id: u32, node: &NodeMetadata) -> Result<Vec<Command>> {
+    let delivered_at = Utc::now();
+    let mut commands = Vec::new();
+    let mut consumed_ids = Vec::new();
+    for mut pending in peek_commands(conn, node_id)? {
+        if !is_deliverable(&pending, node_id, node) {
+            continue;
+        }
+        let Some(mut cmd) = pending.command.take() else {
+            log::warn!("Leaving command {} for node_id {} queued: stored JSON does not parse", pending.id, node_id);
+            continue;
+        };
+        cmd.id = Some(pending.id);
+        commands.push(cmd);
+        consumed_ids.push(pending.id);
+        if let Ok(enqueued_at) = DateTime::parse_from_rfc3339(&pending.timestamp) {
+            record_command_delivery(conn, node_id as i64, &pending, delivered_at, enqueued_at.with_timezone(&Utc))?;
+        }
+    }
+
+    // Delete the delivered commands
+    for id in consumed_ids {
+        conn.execute("DELETE FROM commands WHERE id = ?", &[Value::Integer(id)])?;
+    }
+
+    Ok(commands)
+}
+
+/// Non-destructive delivery for probes that track their own cursor: returns the commands after
+/// `after_id` and the cursor to send next time. Delivery stops before the first held command,
+/// so the cursor never skips past it.
+fn get_commands_after(conn: &Connection, node_id: u32, node: &NodeMetadata, after_id: i64) -> Result<(Vec<Command>, i64)> {
+    let delivered_at = Utc::now();
+    let mut commands = Vec::new();
+    let mut cursor = after_id;
+    for pending in peek_commands(conn, node_id)?.into_iter().filter(|pending| pending.id > after_id) {
+        if !is_deliverable(&pending, node_id, node) {
+            break;
+        }
+        cursor = cursor.max(pending.id);
+        if let Ok(enqueued_at) = DateTime::parse_from_rfc3339(&pending.timestamp) {
+            record_command_delivery(conn, node_id as i64, &pending, delivered_at, enqueued_at.with_timezone(&Utc))?;
+        }
+        if let Some(mut cmd) = pending.command {
+            cmd.id = Some(pending.id);
+            commands.push(cmd);
+        }
+        // Keep the first delivery time so redeliveries do not reset the unacked age
+        conn.execute(
+            "UPDATE commands SET delivered_at = ? WHERE id = ? AND delivered_at IS NULL",
+            &[Value::Text(delivered_at.to_rfc3339()), Value::Integer(pending.id)],
+        )?;
+    }
+
+    Ok((commands, cursor))
+}
+
+/// Commands delivered in cursor mode at or before `older_than` and still queued (not acked),
+/// oldest delivery first.
+fn get_unacked_commands(conn: &Connection, older_than: DateTime<Utc>) -> Result<Vec<UnackedCommand>> {
+    let result = conn.execute(
+        "SELECT id, node_id, timestamp, delivered_at, command FROM commands
+         WHERE delivered_at IS NOT NULL AND delivered_at <= ? ORDER BY delivered_at, id",
+        &[Value::Text(older_than.to_rfc3339())],
+    )?;
+
+    let mut commands = Vec::new();
+    for row in result.rows() {
+        if let (Some(id), Some(node_id)) = (row.get::<i64>("id"), row.get::<i64>("node_id")) {
+            commands.push(UnackedCommand {
+                id,
+                node_id,
+                timestamp: row.get::<&str>("timestamp").unwrap_or_default().to_string(),
+                delivered_at: row.get::<&str>("delivered_at").unwrap_or_default().to_string(),
+                command: row.get::<&str>("command").and_then(|json| serde_json::from_str::<Command>(json).ok()),
+            });
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Drops every command still queued for the node; returns how many were removed.
+fn clear_commands(conn: &Connection, node_id: u32) -> Result<usize> {
+    let result = conn.execute("DELETE FROM commands WHERE node_id = ? RETURNING id", &[Value::Integer(node_id as i64)])?;
+    let removed = result.rows().count();
+    Ok(removed)
+}
+
+/// Ids are handled one by one, so an id that is gone (or belongs to another node) does not
+/// stop the others from being acknowledged.
+fn delete_acknowledged_commands(conn: &Connection, node_id: u32, command_ids: &[i64]) -> Result<(Vec<i64>, Vec<i64>)> {
+    let mut acknowledged = Vec::new();
+    let mut not_found = Vec::new();
+    for &id in command_ids {
+        let deleted = conn.execute(
+            "DELETE FROM commands WHERE id = ? AND node_id = ? RETURNING id",
+            &[Value::Integer(id), Value::Integer(node_id as i64)],
+        )?;
+        if deleted.rows().next().is_some() {
+            acknowledged.push(id);
+        } else {
+            not_found.push(id);
+        }
+    }
+    Ok((acknowledged, not_found))
+}
+
+/// Deletes the node's acknowledged commands in one transaction; returns the ids removed and the
+/// ids no longer queued.
+fn acknowledge_commands(conn: &Connection, node_id: u32, command_ids: &[i64]) -> Result<(Vec<i64>, Vec<i64>)> {
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+    match delete_acknowledged_commands(conn, node_id, command_ids) {
+        Ok(outcome) => {
+            conn.execute("COMMIT", &[])?;
+            Ok(outcome)
+        }
+        Err(e) => {
+            _ = conn.execute("ROLLBACK", &[]);
+            Err(e)
+        }
+    }
+}
+
+/// Records the first delivery of a command, with its broadcast so a result reported after the
+/// queue row is gone can still be attributed to it. A command re-sent in cursor mode until the
+/// probe moves its cursor keeps its first row, so redeliveries do not skew the latency statistics.
+fn record_command_delivery(conn: &Connection, node_id: i64, pending: &PendingCommand, delivered_at: DateTime<Utc>, enqueued_at: DateTime<Utc>) -> Result<()> {
+    let latency_ms = (delivered_at - enqueued_at).num_milliseconds().max(0);
+    conn.execute(
+        "INSERT OR IGNORE INTO command_deliveries (node_id, command_id, delivered_at, latency_ms, broadcast_id) VALUES (?, ?, ?, ?, ?)",
+        &[
+            Value::Integer(node_id),
+            Value::Integer(pending.id),
+            Value::Text(delivered_at.to_rfc3339()),
+            Value::Integer(latency_ms),
+            pending.broadcast_id.clone().map_or(Value::Null, Value::Text),
+        ],
+    )?;
+    Ok(())
+}
+
+fn normalize_command_result<T: serde::de::DeserializeOwned + Serialize>(raw: serde_json::Value) -> Result<serde_json::Value, String> {
+    let parsed: T = serde_json::from_value(raw).map_err(|e| e.to_string())?;
+    serde_json::to_value(parsed).map_err(|e| e.to_string())
+}
+
+/// Validates a reported result against the schema of its command and returns the normalized
+/// form; `None` for commands without a schema, whose results are stored as sent.
+fn parse_command_result(command: &str, raw: serde_json::Value) -> Result<Option<serde_json::Value>, String> {
+    let parsed = match command {
+        "set_log_level" => {
+            let result: LogLevelResult = serde_json::from_value(raw).map_err(|e| e.to_string())?;
+            let log_level = parse_log_level_name(&result.log_level).ok_or_else(|| format!("unknown log_level '{}'", result.log_level))?;
+            serde_json::to_value(LogLevelResult { log_level }).map_err(|e| e.to_string())?
+        }
+        "set_filter" => normalize_command_result::<FilterResult>(raw)?,
+        "run_command" => normalize_command_result::<RunCommandResult>(raw)?,
+        "update_node" | "update_probe" => normalize_command_result::<UpdateResult>(raw)?,
+        "reboot_probe" => normalize_command_result::<RebootResult>(raw)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(parsed))
+}
+
+/// The broadcast is looked up from the command's delivery record, or from the queue while a
+/// cursor-mode command is still unacknowledged.
+fn insert_command_result(conn: &Connection, node_id: u32, command_id: Option<i64>, command: &str, result: &serde_json::Value, structured: bool) -> Result<i64> {
+    let command_id = command_id.map_or(Value::Null, Value::Integer);
+    let inserted = conn.execute(
+        "INSERT INTO command_results (node_id, command_id, command, result, structured, reported_at, broadcast_id) VALUES (?, ?, ?, ?, ?, ?,
+             COALESCE(
+                 (SELECT broadcast_id FROM command_deliveries WHERE command_id = ? AND broadcast_id IS NOT NULL LIMIT 1),
+                 (SELECT broadcast_id FROM commands WHERE id = ?)
+             )) RETURNING id",
+        &[
+            Value::Integer(node_id as i64),
+            command_id.clone(),
+            Value::Text(command.to_string()),
+            Value::Text(serde_json::to_string(result)?),
+            Value::Integer(structured as i64),
+            Value::Text(Utc::now().to_rfc3339()),
+            command_id.clone(),
+            command_id,
+        ],
+    )?;
+    let id = inserted.rows().next().and_then(|row| row.get::<i64>("id"));
+    id.ok_or_else(|| anyhow!("Command result insert returned no id"))
+}
+
+/// Reported results, newest first, optionally narrowed to one command and/or node.
+fn get_command_results(conn: &Connection, command: Option<&str>, node_id: Option<u32>) -> Result<Vec<StoredCommandResult>> {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    if let Some(command) = command {
+        conditions.push("command = ?");
+        params.push(Value::Text(command.to_string()));
+    }
+    if let Some(node_id) = node_id {
+        conditions.push("node_id = ?");
+        params.push(Value::Integer(node_id as i64));
+    }
+    let where_clause = if conditions.is_empty() { String::new() } else { format!("WHERE {}", conditions.join(" AND ")) };
+    params.push(Value::Integer(MAX_COMMAND_RESULTS));
+    let result = conn.execute(
+        &format!(
+            "SELECT id, node_id, command_id, broadcast_id, command, result, structured, reported_at FROM command_results {} ORDER BY id DESC LIMIT ?",
+            where_clause
+        ),
+        &params,
+    )?;
+    Ok(stored_command_results(&result))
+}
+
+/// Results of one broadcast reported after `after_id`, oldest first, for the result stream.
+fn get_broadcast_results_after(conn: &Connection, broadcast_id: &str, after_id: i64) -> Result<Vec<StoredCommandResult>> {
+    let result = conn.execute(
+        "SELECT id, node_id, command_id, broadcast_id, command, result, structured, reported_at FROM command_results
+         WHERE broadcast_id = ? AND id > ? ORDER BY id LIMIT ?",
+        &[Value::Text(broadcast_id.to_string()), Value::Integer(after_id), Value::Integer(MAX_STREAM_EVENTS_PER_POLL)],
+    )?;
+    Ok(stored_command_results(&result))
+}
+
+fn stored_command_results(result: &spin_sdk::sqlite::QueryResult) -> Vec<StoredCommandResult> {
+    let mut results = Vec::new();
+    for row in result.rows() {
+        if let (Some(id), Some(node_id), Some(command)) = (row.get::<i64>("id"), row.get::<i64>("node_id"), row.get::<&str>("command")) {
+            results.push(StoredCommandResult {
+                id,
+                node_id,
+                command_id: row.get::<i64>("command_id"),
+                broadcast_id: row.get::<&str>("broadcast_id").map(|v| v.to_string()),
+                command: command.to_string(),
+                result: row.get::<&str>("result").and_then(|json| serde_json::from_str(json).ok()).unwrap_or_default(),
+                structured: row.get::<i64>("structured").unwrap_or(0) != 0,
+                reported_at: row.get::<&str>("reported_at").unwrap_or_default().to_string(),
+            });
+        }
+    }
+    results
+}
+
+/// Queue latency per node over the deliveries still within the retention window.
+fn get_command_latency_stats(conn: &Connection) -> Result<Vec<CommandLatencyStats>> {
+    let result = conn.execute(
+        "SELECT node_id, COUNT(*) AS deliveries, MIN(latency_ms) AS min_ms, AVG(latency_ms) AS avg_ms, MAX(latency_ms) AS max_ms
+         FROM command_deliveries GROUP BY node_id ORDER BY node_id",
+        &[],
+    )?;
+
+    let mut stats = Vec::new();
+    for row in result.rows() {
+        if let (Some(node_id), Some(deliveries), Some(min_ms), Some(avg_ms), Some(max_ms)) = (
+            row.get::<i64>("node_id"),
+            row.get::<i64>("deliveries"),
+            row.get::<i64>("min_ms"),
+            row.get::<f64>("avg_ms"),
+            row.get::<i64>("max_ms"),
+        ) {
+            stats.push(CommandLatencyStats {
+                node_id,
+                deliveries,
+                min_ms,
+                avg_ms,
+                max_ms,
+            });
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Highest stored log id, 0 for an empty table.
+fn get_max_log_id(conn: &Connection) -> Result<i64> {
+    let result = conn.execute("SELECT COALESCE(MAX(id), 0) AS max_id FROM log_messages", &[])?;
+    let max_id = result.rows().next().and_then(|row| row.get::<i64>("max_id")).unwrap_or(0);
+    Ok(max_id)
+}
+
+fn count_log_messages(conn: &Connection) -> Result<i64> {
+    let result = conn.execute("SELECT COUNT(*) as count FROM log_messages", &[])?;
+    let count = result.rows().next().and_then(|row| row.get::<i64>("count")).unwrap_or(0);
+    Ok(count)
+}
+
+fn move_log_rows_to_trash(conn: &Connection, selection: &str, params: &[Value]) -> Result<usize> {
+    let mut insert_params = vec![Value::Text(Utc::now().to_rfc3339())];
+    insert_params.extend_from_slice(params);
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO deleted_log_messages (id, timestamp, node_id, message, repeat_count, received_at, source, metric, value, tenant, deleted_at)
+             SELECT id, timestamp, node_id, message, repeat_count, received_at, source, metric, value, tenant, ? FROM log_messages WHERE id IN ({})",
+            selection
+        ),
+        &insert_params,
+    )?;
+    let result = conn.execute(&format!("DELETE FROM log_messages WHERE id IN ({}) RETURNING id", selection), params)?;
+    Ok(result.rows().count())
+}
+
+/// Deletes the log rows whose ids `selection` (a subquery on `log_messages`) returns; returns how
+/// many were removed. With soft delete the rows are first copied to `deleted_log_messages`, in
+/// one transaction so both statements see the same rows.
+fn remove_log_rows(conn: &Connection, selection: &str, params: &[Value], retention: &RetentionSettings) -> Result<usize> {
+    if !retention.soft_delete {
+        let result = conn.execute(&format!("DELETE FROM log_messages WHERE id IN ({}) RETURNING id", selection), params)?;
+        return Ok(result.rows().count());
+    }
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+    match move_log_rows_to_trash(conn, selection, params) {
+        Ok(removed) => {
+            conn.execute("COMMIT", &[])?;
+            Ok(removed)
+        }
+        Err(e) => {
+            _ = conn.execute("ROLLBACK", &[]);
+            Err(e)
+        }
+    }
+}
+
+/// Outcome of one retention cleanup run, kept in the KV store as `last_cleanup_report`.
+#[derive(Debug, Serialize)]
+struct CleanupReport {
+    logs_deleted: usize,
+    logs_still_eligible: i64,
+    finished_at: String,
+}
+
+fn cleanup_old_data(conn: &Connection, delete_timeout_minutes: i64, retention: &RetentionSettings) -> Result<CleanupReport> {
+    log::debug!("Cleaning up old data older than {} minutes.", delete_timeout_minutes);
+    let cutoff_time = Utc::now() - chrono::Duration::minutes(delete_timeout_minutes);
+    let cutoff_str = cutoff_time.to_rfc3339();
+
+    // Rows an active collector has not acknowledged yet are kept past their age limit
+    let acked_bound = match retention.collector_ack_window_minutes {
+        0 => None,
+        window => min_active_collector_ack(conn, Utc::now() - chrono::Duration::minutes(window))?,
+    };
+    let mut condition = "timestamp < ?".to_string();
+    let mut params = vec![Value::Text(cutoff_str.clone())];
+    if let Some(acked_id) = acked_bound {
+        log::debug!("Limiting log cleanup to ids up to {} acknowledged by all active collectors", acked_id);
+        condition.push_str(" AND id <= ?");
+        params.push(Value::Integer(acked_id));
+    }
+
+    // Delete in batches until nothing eligible is left or the run's budget is spent, so a
+    // backlog larger than one batch drains instead of trickling out one batch per interval
+    let selection = format!("SELECT id FROM log_messages WHERE {} LIMIT {}", condition, CLEANUP_BATCH_SIZE);
+    let started = Instant::now();
+    let mut logs_deleted = 0;
+    let drained = loop {
+        let removed = remove_log_rows(conn, &selection, &params, retention)?;
+        logs_deleted += removed;
+        if (removed as i64) < CLEANUP_BATCH_SIZE {
+            break true;
+        }
+        if logs_deleted >= MAX_CLEANUP_ROWS_PER_RUN || started.elapsed().as_millis() >= CLEANUP_TIME_BUDGET_MS {
+            break false;
+        }
+    };
+    let logs_still_eligible = if drained {
+        0
+    } else {
+        let result = conn.execute(&format!("SELECT COUNT(*) AS count FROM log_messages WHERE {}", condition), &params)?;
+        let count = result.rows().next().and_then(|row| row.get::<i64>("count")).unwrap_or(0);
+        count
+    };
+    if logs_still_eligible > 0 {
+        log::info!("Cleanup deleted {} log messages; {} still eligible for the next run", logs_deleted, logs_still_eligible);
+    } else if logs_deleted > 0 {
+        log::info!("Cleanup deleted {} log messages", logs_deleted);
+    }
+
+    // Soft-deleted rows are purged for good once their undelete window has passed
+    let purge_before = Utc::now() - chrono::Duration::minutes(retention.undelete_window_minutes);
+    conn.execute(
+        "DELETE FROM deleted_log_messages WHERE id IN (SELECT id FROM deleted_log_messages WHERE deleted_at < ? LIMIT 10000)",
+        &[Value::Text(purge_before.to_rfc3339())],
+    )?;
+
+    // Count remaining log messages (kept plus any still eligible)
+    let count = count_log_messages(conn)?;
+    if count > 0 {
+        log::debug!("Remaining log messages after cleanup: {}", count);
+    }
+
+    // Scheduled commands age from their not_before time instead of their submission
+    conn.execute(
+        "DELETE FROM commands WHERE id IN (
+             SELECT id FROM commands WHERE timestamp < ? AND (not_before IS NULL OR not_before < ?) LIMIT 10000
+         )",
+        &[Value::Text(cutoff_str.clone()), Value::Text(cutoff_time.to_rfc3339_opts(SecondsFormat::Secs, true))],
+    )?;
+
+    conn.execute(
+        "DELETE FROM command_deliveries WHERE id IN (SELECT id FROM command_deliveries WHERE delivered_at < ? LIMIT 10000)",
+        &[Value::Text(cutoff_str.clone())],
+    )?;
+
+    conn.execute(
+        "DELETE FROM command_results WHERE id IN (SELECT id FROM command_results WHERE reported_at < ? LIMIT 10000)",
+        &[Value::Text(cutoff_str)],
+    )?;
+
+    // Count remaining commands
+    let cmd_count_result = conn.execute("SELECT COUNT(*) as count FROM commands", &[])?;
+    if let Some(row) = cmd_count_result.rows().next() {
+        if let Some(count) = row.get::<i64>("count") {
+            if count > 0 {
+                log::debug!("Remaining commands after cleanup: {}", count);
+            }
+        }
+    }
+
+    Ok(CleanupReport {
+        logs_deleted,
+        logs_still_eligible,
+        finished_at: Utc::now().to_rfc3339(),
+    })
+}
+
+/// Records that a collector has durably stored every row up to `last_id`. Acks only move
+/// forward, so a late or replayed ack cannot hold back cleanup further than before.
+fn record_collector_ack(conn: &Connection, tenant: Option<&str>, collector_id: &str, last_id: i64) -> Result<i64> {
+    let result = conn.execute(
+        "INSERT INTO collector_acks (collector_id, last_id, acked_at) VALUES (?, ?, ?)
+         ON CONFLICT(collector_id) DO UPDATE SET last_id = MAX(collector_acks.last_id, excluded.last_id), acked_at = excluded.acked_at
+         RETURNING last_id",
+        &[Value::Text(scoped_collector_id(tenant, collector_id)), Value::Integer(last_id), Value::Text(Utc::now().to_rfc3339())],
+    )?;
+    let acked_id = result.rows().next().and_then(|row| row.get::<i64>("last_id")).unwrap_or(last_id);
+    Ok(acked_id)
+}
+
+/// Lowest id acknowledged by the collectors that acked since `active_since`; `None` when no
+/// collector is active, in which case cleanup goes by age alone.
+fn min_active_collector_ack(conn: &Connection, active_since: DateTime<Utc>) -> Result<Option<i64>> {
+    let result = conn.execute(
+        "SELECT MIN(last_id) AS acked_id FROM collector_acks WHERE acked_at >= ?",
+        &[Value::Text(active_since.to_rfc3339())],
+    )?;
+    let acked_id = result.rows().next().and_then(|row| row.get::<i64>("acked_id"));
+    Ok(acked_id)
+}
+
+/// Last id handed out for `log_messages`, from SQLite's `AUTOINCREMENT` bookkeeping (0 before the first row).
+fn get_log_id_sequence(conn: &Connection) -> Result<i64> {
+    let result = conn.execute("SELECT seq FROM sqlite_sequence WHERE name = 'log_messages'", &[])?;
+    let seq = result.rows().next().and_then(|row| row.get::<i64>("seq")).unwrap_or(0);
+    Ok(seq)
+}
+
+/// Reconciles the database's id sequence with the `log_id_high_water` KV entry in both
+/// directions: a sequence behind the mark (the database was recreated) is moved up to it, and
+/// a sequence ahead of it is recorded. Called before and after inserts in `sequence` mode; the
+/// first call on an existing database simply records its current sequence.
+fn sync_log_id_sequence(conn: &Connection, store: &Store) -> Result<()> {
+    let high_water = store
+        .get("log_id_high_water")?
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(0);
+    let sequence = get_log_id_sequence(conn)?;
+    if high_water > sequence {
+        log::warn!("Log id sequence {} is behind the stored high-water mark (database recreated?); continuing after {}", sequence, high_water);
+        conn.execute("UPDATE sqlite_sequence SET seq = ? WHERE name = 'log_messages'", &[Value::Integer(high_water)])?;
+        conn.execute(
+            "INSERT INTO sqlite_sequence (name, seq) SELECT 'log_messages', ? WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'log_messages')",
+            &[Value::Integer(high_water)],
+        )?;
+    } else if sequence > high_water {
+        store.set("log_id_high_water", sequence.to_string().as_bytes())?;
+    }
+    Ok(())
+}
+
+/// `(node_id, row_count)` for every node holding log rows, largest first.
+fn count_log_messages_per_node(conn: &Connection) -> Result<Vec<(i64, i64)>> {
+    let result = conn.execute(
+        "SELECT node_id, COUNT(*) AS count FROM log_messages GROUP BY node_id ORDER BY count DESC, node_id",
+        &[],
+    )?;
+
+    let mut counts = Vec::new();
+    for row in result.rows() {
+        if let (Some(node_id), Some(count)) = (row.get::<i64>("node_id"), row.get::<i64>("count")) {
+            counts.push((node_id, count));
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Targeted reclaim: while more than `threshold` log rows are stored, deletes the oldest rows of the
+/// nodes holding the most, only down to the next-largest node, so one runaway node cannot evict
+/// everyone else's recent logs. Nodes tied for largest are trimmed together, each by an even share
+/// of the excess. Counts are taken once per run and tracked in memory. Returns the number of rows deleted.
+fn reclaim_largest_nodes(conn: &Connection, threshold: i64, retention: &RetentionSettings) -> Result<i64> {
+    let mut counts = count_log_messages_per_node(conn)?;
+    let mut excess = counts.iter().map(|&(_, count)| count).sum::<i64>() - threshold;
+    let mut deleted = 0;
+    // Bounded like the age-based cleanup; the rest is reclaimed on the next run
+    for _ in 0..100 {
+        if excess <= 0 {
+            break;
+        }
+        let Some(&(_, largest)) = counts.first().filter(|&&(_, count)| count > 0) else {
+            break;
+        };
+        let tied = counts.iter().take_while(|&&(_, count)| count == largest).count() as i64;
+        let next_largest = counts.get(tied as usize).map(|&(_, count)| count).unwrap_or(0);
+        let share = (excess + tied - 1) / tied;
+        let batch = share.min(largest - next_largest).min(PURGE_BATCH_SIZE);
+
+        let mut removed_this_round = 0;
+        for (node_id, count) in counts.iter_mut().take(tied as usize) {
+            if excess <= 0 {
+                break;
+            }
+            log::info!("Reclaiming {} oldest log rows from node_id {} ({} rows stored)", batch, node_id, count);
+            let removed = remove_log_rows(
+                conn,
+                "SELECT id FROM log_messages WHERE node_id = ? ORDER BY timestamp, id LIMIT ?",
+                &[Value::Integer(*node_id), Value::Integer(batch)],
+                retention,
+            )? as i64;
+            *count -= removed;
+            excess -= removed;
+            deleted += removed;
+            removed_this_round += removed;
+        }
+        // Counts went stale (e.g. a concurrent purge); the next run starts from fresh ones
+        if removed_this_round == 0 {
+            break;
+        }
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    }
+    Ok(deleted)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogOrder {
+    Timestamp,
+    /// Same-timestamp rows grouped by node, then insertion order
+    TimestampNode,
+    Id,
+    /// Arrival order at the hub; imported rows (no receive time) sort first
+    ReceivedAt,
+}
+
+impl LogOrder {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogOrder::Timestamp => "timestamp,id",
+            LogOrder::TimestampNode => "timestamp,node_id,id",
+            LogOrder::Id => "id",
+            LogOrder::ReceivedAt => "received_at,id",
+        }
+    }
+}
+
+/// How `log_messages` ids stay monotonic, from the `log_id_strategy` variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogIdStrategy {
+    /// SQLite `AUTOINCREMENT` alone; ids restart at 1 if the database is recreated
+    Rowid,
+    /// `AUTOINCREMENT` seeded from a high-water mark kept in the KV store, so ids keep growing
+    /// across a recreated database and collector cursors stay valid
+    Sequence,
+}
+
+fn get_log_id_strategy() -> LogIdStrategy {
+    let raw = variables::get("log_id_strategy").unwrap_or_default();
+    match raw.trim() {
+        "" | "rowid" => LogIdStrategy::Rowid,
+        "sequence" => LogIdStrategy::Sequence,
+        other => {
+            log::warn!("Ignoring invalid log_id_strategy variable '{}': expected rowid or sequence", other);
+            LogIdStrategy::Rowid
+        }
+    }
+}
+
+/// Primary `/download` sort column from the `download_order_by` variable (default `timestamp`).
+fn get_download_order_by() -> LogOrder {
+    let raw = variables::get("download_order_by").unwrap_or_default();
+    match raw.trim() {
+        "" | "timestamp" => LogOrder::Timestamp,
+        "id" => LogOrder::Id,
+        "received_at" => LogOrder::ReceivedAt,
+        other => {
+            log::warn!("Ignoring invalid download_order_by variable '{}': expected timestamp, id or received_at", other);
+            LogOrder::Timestamp
+        }
+    }
+}
+
+/// Filters for a `log_messages` fetch; `query_logs_page` turns them into a single SELECT.
+#[derive(Debug, Clone)]
+struct LogQuery {
+    after_id: i64,
+    /// Empty means all nodes
+    node_ids: Vec<i64>,
+    cutoff: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// Message starts with this text (index-assisted with `enable_message_index`)
+    prefix: Option<String>,
+    /// Message contains this text (always a scan)
+    search: Option<String>,
+    /// Exact source (index-assisted with `enable_source_index`)
+    source: Option<String>,
+    /// Only this tenant's rows; `None` when tenancy is disabled
+    tenant: Option<String>,
+    order: LogOrder,
+    limit: i64,
+    /// Stop the page before its entries' estimated JSON size exceeds this many bytes
+    max_bytes: Option<usize>,
+}
+
+/// Escapes LIKE wildcards so user text matches literally with `ESCAPE '\'`.
+fn escape_like(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Text of a column, rendering numbers as text; `None` for NULL or a blob.
+fn column_text(row: &spin_sdk::sqlite::Row<'_>, column: &str) -> Option<String> {
+    row.get::<&str>(column)
+        .map(|v| v.to_string())
+        .or_else(|| row.get::<i64>(column).map(|v| v.to_string()))
+        .or_else(|| row.get::<f64>(column).map(|v| v.to_string()))
+}
+
+/// One fetched page of `/download`-style results.
+#[derive(Debug)]
+struct LogPage {
+    logs: Vec<DownloadLogEntry>,
+    /// Rows that matched but could not be mapped to an entry (no usable id or node_id)
+    skipped: usize,
+    /// Highest id among all rows of the page, including skipped ones
+    max_id: Option<i64>,
+    has_more: bool,
+}
+
+/// Runs `query` for one page: fetches a single extra row to tell whether more rows match, and
+/// maps at most `query.limit` rows. NULL or mistyped text columns become empty strings rather
+/// than dropping the row; rows without a usable id or node_id are counted in `skipped`.
+/// With `query.max_bytes` the page ends early (with `has_more`) once the next entry would not
+/// fit; the first entry is always included so a single oversize row cannot stall the cursor.
+fn query_logs_page(conn: &Connection, query: &LogQuery) -> Result<LogPage> {
+    let mut conditions = vec!["id > ?"];
+    let mut params = vec![Value::Integer(query.after_id)];
+    let node_condition = format!("node_id IN ({})", vec!["?"; query.node_ids.len()].join(", "));
+    if !query.node_ids.is_empty() {
+        conditions.push(&node_condition);
+        params.extend(query.node_ids.iter().map(|&node_id| Value::Integer(node_id)));
+    }
+    if let Some(cutoff) = &query.cutoff {
+        conditions.push("timestamp < ?");
+        params.push(Value::Text(cutoff.clone()));
+    }
+    if let Some(from) = &query.from {
+        conditions.push("timestamp >= ?");
+        params.push(Value::Text(from.clone()));
+    }
+    if let Some(to) = &query.to {
+        conditions.push("timestamp < ?");
+        params.push(Value::Text(to.clone()));
+    }
+    // The whole pattern is bound as one parameter: SQLite only uses an index for LIKE when the
+    // right-hand side is a literal or parameter without a leading wildcard, not an expression
+    if let Some(prefix) = &query.prefix {
+        conditions.push("message LIKE ? ESCAPE '\\'");
+        params.push(Value::Text(format!("{}%", escape_like(prefix))));
+    }
+    if let Some(search) = &query.search {
+        conditions.push("message LIKE ? ESCAPE '\\'");
+        params.push(Value::Text(format!("%{}%", escape_like(search))));
+    }
+    if let Some(source) = &query.source {
+        conditions.push("source = ?");
+        params.push(Value::Text(source.clone()));
+    }
+    if let Some(tenant) = &query.tenant {
+        conditions.push("tenant = ?");
+        params.push(Value::Text(tenant.clone()));
+    }
+    params.push(Value::Integer(query.limit.saturating_add(1)));
+
+    let order_by = match query.order {
+        LogOrder::Timestamp => "timestamp ASC, id ASC",
+        LogOrder::TimestampNode => "timestamp ASC, node_id ASC, id ASC",
+        LogOrder::Id => "id ASC",
+        LogOrder::ReceivedAt => "received_at ASC, id ASC",
+    };
+    let sql = format!(
+        "SELECT id, timestamp, node_id, message, repeat_count, source FROM log_messages WHERE {} ORDER BY {} LIMIT ?",
+        conditions.join(" AND "),
+        order_by
+    );
+    let result = conn.execute(&sql, &params)?;
+
+    let fetched = result.rows().count();
+    log::debug!("Fetched {} logs.", fetched);
+
+    let mut page = LogPage {
+        logs: Vec::new(),
+        skipped: 0,
+        max_id: None,
+        has_more: fetched as i64 > query.limit,
+    };
+    let mut page_bytes = 0;
+    for row in result.rows().take(query.limit as usize) {
+        let id = row.get::<i64>("id");
+        let node_id = row.get::<i64>("node_id").or_else(|| row.get::<&str>("node_id").and_then(|v| v.trim().parse().ok()));
+
+        let (Some(id), Some(node_id)) = (id, node_id) else {
+            page.max_id = page.max_id.max(id);
+            page.skipped += 1;
+            continue;
+        };
+        let entry = DownloadLogEntry {
+            item_id: id,
+            timestamp: column_text(&row, "timestamp").unwrap_or_default(),
+            node_id,
+            message: column_text(&row, "message").unwrap_or_default(),
+            repeat_count: row.get::<i64>("repeat_count").unwrap_or(1),
+            source: row.get::<&str>("source").map(|v| v.to_string()),
+        };
+        if let Some(max_bytes) = query.max_bytes {
+            // Entry plus its separating comma; max_id stops here, so the cursor resumes at this row
+            let entry_bytes = serde_json::to_vec(&entry)?.len() + 1;
+            if !page.logs.is_empty() && page_bytes + entry_bytes > max_bytes {
+                log::debug!("Ending page after {} entries ({} bytes): byte budget reached", page.logs.len(), page_bytes);
+                page.has_more = true;
+                break;
+            }
+            page_bytes += entry_bytes;
+        }
+        page.max_id = page.max_id.max(Some(id));
+        page.logs.push(entry);
+    }
+    if page.skipped > 0 {
+        log::warn!("Skipped {} log rows without a usable id or node_id (page up to id {:?})", page.skipped, page.max_id);
+    }
+
+    Ok(page)
+}
+
+/// With a tenant, a row of another tenant is reported as missing.
+fn get_log_by_id(conn: &Connection, id: i64, tenant: Option<&str>) -> Result<Option<DownloadLogEntry>> {
+    let result = match tenant {
+        Some(tenant) => conn.execute(
+            "SELECT id, timestamp, node_id, message, repeat_count, source FROM log_messages WHERE id = ? AND tenant = ?",
+            &[Value::Integer(id), Value::Text(tenant.to_string())],
+        )?,
+        None => conn.execute(
+            "SELECT id, timestamp, node_id, message, repeat_count, source FROM log_messages WHERE id = ?",
+            &[Value::Integer(id)],
+        )?,
+    };
+    let entry = result.rows().next().and_then(|row| {
+        Some(DownloadLogEntry {
+            item_id: row.get::<i64>("id")?,
+            timestamp: row.get::<&str>("timestamp")?.to_string(),
+            node_id: row.get::<i64>("node_id")?,
+            message: row.get::<&str>("message")?.to_string(),
+            repeat_count: row.get::<i64>("repeat_count").unwrap_or(1),
+            source: row.get::<&str>("source").map(|v| v.to_string()),
+        })
+    });
+    Ok(entry)
+}
+
+/// Time-ordered points of one metric, optionally for one node and within `[from, to)`. Fetches one
+/// extra row to tell whether the series was cut at `MAX_METRIC_POINTS_PER_QUERY`.
+fn query_metric_points(conn: &Connection, metric: &str, node_id: Option<u32>, from: Option<&str>, to: Option<&str>, tenant: Option<&str>) -> Result<(Vec<MetricPoint>, bool)> {
+    let mut sql = "SELECT node_id, timestamp, value FROM log_messages WHERE metric = ? AND value IS NOT NULL".to_string();
+    let mut params = vec![Value::Text(metric.to_string())];
+    if let Some(node_id) = node_id {
+        sql.push_str(" AND node_id = ?");
+        params.push(Value::Integer(node_id as i64));
+    }
+    if let Some(from) = from {
+        sql.push_str(" AND timestamp >= ?");
+        params.push(Value::Text(from.to_string()));
+    }
+    if let Some(to) = to {
+        sql.push_str(" AND timestamp < ?");
+        params.push(Value::Text(to.to_string()));
+    }
+    if let Some(tenant) = tenant {
+        sql.push_str(" AND tenant = ?");
+        params.push(Value::Text(tenant.to_string()));
+    }
+    sql.push_str(" ORDER BY timestamp, id LIMIT ?");
+    params.push(Value::Integer(MAX_METRIC_POINTS_PER_QUERY + 1));
+
+    let result = conn.execute(&sql, &params)?;
+    let mut points: Vec<MetricPoint> = result
+        .rows()
+        .filter_map(|row| {
+            Some(MetricPoint {
+                node_id: row.get::<i64>("node_id")?,
+                timestamp: row.get::<&str>("timestamp")?.to_string(),
+                value: row.get::<f64>("value")?,
+            })
+        })
+        .collect();
+    let has_more = points.len() as i64 > MAX_METRIC_POINTS_PER_QUERY;
+    points.truncate(MAX_METRIC_POINTS_PER_QUERY as usize);
+    Ok((points, has_more))
+}
+
+/// Optional `/download` query parameters that shape the fetch and the response.
+#[derive(Debug)]
+struct DownloadOptions {
+    include_unsettled: bool,
+    order: LogOrder,
+    fields: Option<Vec<String>>,
+    node_ids: Vec<i64>,
+    /// Lower timestamp bound resolved from `since=`
+    since: Option<String>,
+    prefix: Option<String>,
+    search: Option<String>,
+    source: Option<String>,
+    /// Add the offsets of the `q=` matches to each entry
+    highlight: bool,
+    group_by_node: bool,
+    /// Page size, already clamped to `[1, download_max_limit]`
+    limit: i64,
+    debug: bool,
+    api_version: ApiVersion,
+}
+
+impl DownloadOptions {
+    fn from_uri(uri: &str) -> Result<Self, String> {
+        // Opt-in: serve rows newer than the settlement cutoff (collector handles late/re-ordered tail rows)
+        let include_unsettled = get_query_param(uri, "include_unsettled").is_some_and(|v| v == "true");
+
+        let tiebreak_node = match get_query_param(uri, "tiebreak").as_deref() {
+            None | Some("id") => false,
+            Some("node_id") => true,
+            Some(other) => return Err(format!("Invalid tiebreak '{}': expected id or node_id", other)),
+        };
+        // The primary column is a deployment choice; tiebreak only refines timestamp order
+        let order = match get_download_order_by() {
+            LogOrder::Timestamp if tiebreak_node => LogOrder::TimestampNode,
+            order => order,
+        };
+
+        // Optional projection to a subset of entry fields
+        let fields = match get_query_param(uri, "fields") {
+            Some(value) => Some(parse_fields_param(&value)?),
+            None => None,
+        };
+
+        // Optional node filter: node_id=1,2,3 and/or node_id=1&node_id=2
+        let node_ids = parse_node_id_params(&get_query_params(uri, "node_id"))?;
+
+        // Optional relative lower bound: since=15m means rows timestamped in the last 15 minutes
+        let since = match get_query_param(uri, "since") {
+            Some(value) => {
+                let duration = parse_duration_param(&value)?;
+                let since = Utc::now().checked_sub_signed(duration).ok_or_else(|| format!("Invalid since '{}': duration too large", value))?;
+                Some(since.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+            None => None,
+        };
+
+        // Optional message filters: prefix= (start of message) and q= (anywhere in it)
+        let prefix = get_query_param(uri, "prefix").filter(|v| !v.is_empty());
+        let search = get_query_param(uri, "q").filter(|v| !v.is_empty());
+        // Optional component filter, matched exactly
+        let source = get_query_param(uri, "source").map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let highlight = get_query_param(uri, "highlight").is_some_and(|v| v == "true");
+        if highlight && search.is_none() {
+            return Err("highlight=true requires q=".to_string());
+        }
+
+        // Optional response shape: entries grouped per node instead of one flat list
+        let group_by_node = match get_query_param(uri, "group_by").as_deref() {
+            None => false,
+            Some("node") => true,
+            Some(other) => return Err(format!("Invalid group_by '{}': expected node", other)),
+        };
+
+        // Optional page size, never above the configured maximum
+        let max_limit = get_i64_variable("download_max_limit", MAX_LOG_ITEMS_PER_DOWNLOAD).max(1);
+        let limit = match get_query_param(uri, "limit") {
+            Some(value) => parse_limit_param(&value, max_limit)?,
+            None => max_limit,
+        };
+
+        // Echo the resolved filters in the response
+        let debug = get_query_param(uri, "debug").is_some_and(|v| v == "true");
+
+        // Response shape; v1 stays the default so older collectors never see new fields
+        let api_version = match get_query_param(uri, "api_version").as_deref() {
+            None | Some("v1") => ApiVersion::V1,
+            Some("v2") => ApiVersion::V2,
+            Some(other) => return Err(format!("Invalid api_version '{}': expected v1 or v2", other)),
+        };
+
+        Ok(DownloadOptions {
+            include_unsettled,
+            order,
+            fields,
+            node_ids,
+            since,
+            prefix,
+            search,
+            source,
+            highlight,
+            group_by_node,
+            limit,
+            debug,
+            api_version,
+        })
+    }
+}
+
+/// Parses a duration such as `90`, `90s`, `15m` or `2h` (no suffix means seconds).
+fn parse_duration_param(value: &str) -> Result<chrono::Duration, String> {
+    let value = value.trim();
+    let invalid = || format!("Invalid since '{}': expected a duration like 30s, 15m or 2h", value);
+    let (digits, unit_seconds) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 3600),
+        _ => (value, 1),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    digits
+        .parse::<i64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(unit_seconds))
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or_else(|| format!("Invalid since '{}': duration too large", value))
+}
+
+/// Parses `limit=` and clamps it to `[1, max]`. Integers too large for `i64` clamp like any
+/// other out-of-range value; anything that is not an integer is rejected.
+fn parse_limit_param(value: &str, max: i64) -> Result<i64, String> {
+    let value = value.trim();
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("Invalid limit '{}': expected an integer", value));
+    }
+    let limit = match value.parse::<i64>() {
+        Ok(limit) => limit,
+        Err(_) if value.starts_with('-') => i64::MIN,
+        Err(_) => i64::MAX,
+    };
+    Ok(limit.clamp(1, max))
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Serializes download entries as CSV with a header row.
+fn to_csv(logs: &[DownloadLogEntry]) -> String {
+    let mut csv = DOWNLOAD_LOG_FIELDS.join(",");
+    csv.push_str("\r\n");
+    for log in logs {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\r\n",
+            log.item_id,
+            csv_field(&log.timestamp),
+            log.node_id,
+            csv_field(&log.message),
+            log.repeat_count,
+            csv_field(log.source.as_deref().unwrap_or_default())
+        ));
+    }
+    csv
+}
+
+/// Resolves a `/download` request into the `LogQuery` it runs, applying the settlement cutoff.
+fn download_query(last_id: i64, max_upload_interval: i64, options: &DownloadOptions, tenant: Option<&str>) -> LogQuery {
+    let cutoff_time = Utc::now() - chrono::Duration::seconds((max_upload_interval as f64 * 1.1) as i64);
+    let cutoff_str = cutoff_time.to_rfc3339();
+
+    log::debug!(
+        "Fetching logs for download: last_id={}, cutoff_time={}, options={:?}, current_time={}",
+        last_id,
+        cutoff_str,
+        options,
+        Utc::now().to_rfc3339()
+    );
+
+    LogQuery {
+        after_id: last_id,
+        node_ids: options.node_ids.clone(),
+        cutoff: if options.include_unsettled { None } else { Some(cutoff_str) },
+        from: options.since.clone(),
+        to: None,
+        prefix: options.prefix.clone(),
+        search: options.search.clone(),
+        source: options.source.clone(),
+        tenant: tenant.map(|tenant| tenant.to_string()),
+        order: options.order,
+        limit: options.limit,
+        max_bytes: usize::try_from(get_i64_variable("max_response_bytes", 0)).ok().filter(|&max_bytes| max_bytes > 0),
+    }
+}
+
+/// Replaces every `{node_id}` placeholder inside string values with the target node's id.
+fn substitute_node_id(value: &serde_json::Value, node_id: i64) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => serde_json::Value::String(s.replace(NODE_ID_PLACEHOLDER, &node_id.to_string())),
+        serde_json::Value::Array(items) => serde_json::Value::Array(items.iter().map(|v| substitute_node_id(v, node_id)).collect()),
+        serde_json::Value::Object(map) => {
+            serde_json::Value::Object(map.iter().map(|(k, v)| (k.clone(), substitute_node_id(v, node_id))).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+/// Delivery constraints stored alongside a queued command.
+#[derive(Debug, Default)]
+struct CommandOptions {
+    min_node_version: Option<String>,
+    condition: Option<CommandCondition>,
+    /// Shared by every copy of one broadcast
+    broadcast_id: Option<String>,
+    /// Canonical UTC time before which the command is held
+    not_before: Option<String>,
+}
+
+/// Queues one copy of a command. `issued_at` is computed once per issuance, so all copies of a
+/// broadcast carry the same timestamp. Returns `false` without queueing when the node already
+/// has `max_pending_commands` undelivered (0 disables the limit).
+fn insert_command(conn: &Connection, node_id: i64, command_json: &str, issued_at: &str, options: &CommandOptions) -> Result<bool> {
+    let max_pending = get_i64_variable("max_pending_commands", DEFAULT_MAX_PENDING_COMMANDS);
+    if max_pending > 0 {
+        let result = conn.execute("SELECT COUNT(*) AS pending FROM commands WHERE node_id = ?", &[Value::Integer(node_id)])?;
+        let pending = result.rows().next().and_then(|row| row.get::<i64>("pending")).unwrap_or(0);
+        if pending >= max_pending {
+            log::warn!("Not queueing command for node_id {}: {} commands already pending (limit {})", node_id, pending, max_pending);
+            return Ok(false);
+        }
+    }
+    let min_node_version = match &options.min_node_version {
+        Some(version) => Value::Text(version.clone()),
+        None => Value::Null,
+    };
+    let condition = match &options.condition {
+        Some(condition) => Value::Text(serde_json::to_string(condition)?),
+        None => Value::Null,
+    };
+    let broadcast_id = match &options.broadcast_id {
+        Some(broadcast_id) => Value::Text(broadcast_id.clone()),
+        None => Value::Null,
+    };
+    let not_before = match &options.not_before {
+        Some(not_before) => Value::Text(not_before.clone()),
+        None => Value::Null,
+    };
+    conn.execute(
+        "INSERT INTO commands (timestamp, node_id, command, min_node_version, condition, broadcast_id, not_before) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        &[
+            Value::Text(issued_at.to_string()),
+            Value::Integer(node_id),
+            Value::Text(command_json.to_string()),
+            min_node_version,
+            condition,
+            broadcast_id,
+            not_before,
+        ],
+    )?;
+    Ok(true)
+}
+
+/// Records an upload: last-seen time, plus the version and labels when the probe reported them.
+/// With tenancy the first tenant to upload for the node becomes its owner; the owner never changes.
+fn upsert_node(conn: &Connection, node_id: i64, version: Option<&str>, labels: Option<&HashMap<String, String>>, tenant: Option<&str>) -> Result<()> {
+    let version = match version {
+        Some(version) => Value::Text(version.to_string()),
+        None => Value::Null,
+    };
+    let labels = match labels {
+        Some(labels) => Value::Text(serde_json::to_string(labels)?),
+        None => Value::Null,
+    };
+    let tenant = match tenant {
+        Some(tenant) => Value::Text(tenant.to_string()),
+        None => Value::Null,
+    };
+    conn.execute(
+        "INSERT INTO nodes (node_id, last_seen, version, labels, tenant) VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(node_id) DO UPDATE SET last_seen = excluded.last_seen,
+             version = COALESCE(excluded.version, nodes.version), labels = COALESCE(excluded.labels, nodes.labels),
+             tenant = COALESCE(nodes.tenant, excluded.tenant)",
+        &[Value::Integer(node_id), Value::Text(Utc::now().to_rfc3339()), version, labels, tenant],
+    )?;
+    Ok(())
+}
+
+/// Adds one upload to the node's lifetime counters; `upsert_node` has already created the row.
+fn record_upload_stats(conn: &Connection, node_id: i64, logs: usize, bytes: usize) -> Result<()> {
+    conn.execute(
+        "UPDATE nodes SET logs_received = logs_received + ?, bytes_ingested = bytes_ingested + ?, last_upload_bytes = ? WHERE node_id = ?",
+        &[Value::Integer(logs as i64), Value::Integer(bytes as i64), Value::Integer(bytes as i64), Value::Integer(node_id)],
+    )?;
+    Ok(())
+}
+
+fn get_node_metadata(conn: &Connection, node_id: i64) -> Result<NodeMetadata> {
+    let result = conn.execute("SELECT version, labels FROM nodes WHERE node_id = ?", &[Value::Integer(node_id)])?;
+    let metadata = match result.rows().next() {
+        Some(row) => NodeMetadata {
+            version: row.get::<&str>("version").map(|v| v.to_string()),
+            labels: row.get::<&str>("labels").and_then(|json| serde_json::from_str(json).ok()).unwrap_or_default(),
+        },
+        None => NodeMetadata::default(),
+    };
+    Ok(metadata)
+}
+
+/// Parses `X-Node-Labels: site=lab,role=gateway`; `None` when malformed.
+fn parse_node_labels(value: &str) -> Option<HashMap<String, String>> {
+    let mut labels = HashMap::new();
+    for pair in value.split(',').map(|pair| pair.trim()).filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=')?;
+        let key = key.trim();
+        if key.is_empty() {
+            return None;
+        }
+        labels.insert(key.to_string(), value.trim().to_string());
+    }
+    Some(labels)
+}
+
+/// Whether the node has uploaded or been registered.
+fn node_is_known(conn: &Connection, node_id: i64) -> Result<bool> {
+    let result = conn.execute("SELECT 1 AS known FROM nodes WHERE node_id = ?", &[Value::Integer(node_id)])?;
+    let known = result.rows().next().is_some();
+    Ok(known)
+}
+
+fn get_node_version(conn: &Connection, node_id: i64) -> Result<Option<String>> {
+    let result = conn.execute("SELECT version FROM nodes WHERE node_id = ?", &[Value::Integer(node_id)])?;
+    let version = result.rows().next().and_then(|row| row.get::<&str>("version").map(|v| v.to_string()));
+    Ok(version)
+}
+
+fn get_node_tenant(conn: &Connection, node_id: i64) -> Result<Option<String>> {
+    let result = conn.execute("SELECT tenant FROM nodes WHERE node_id = ?", &[Value::Integer(node_id)])?;
+    let tenant = result.rows().next().and_then(|row| row.get::<&str>("tenant").map(|v| v.to_string()));
+    Ok(tenant)
+}
+
+fn get_node_secret(conn: &Connection, node_id: i64) -> Result<Option<String>> {
+    let result = conn.execute("SELECT secret FROM nodes WHERE node_id = ?", &[Value::Integer(node_id)])?;
+    let secret = result.rows().next().and_then(|row| row.get::<&str>("secret").map(|v| v.to_string()));
+    Ok(secret)
+}
+
+/// Stored form of a node secret: the hex SHA-256 digest, so a database copy does not leak usable secrets.
+fn hash_node_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares without stopping at the first differing byte, so response timing does not reveal
+/// how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Checks a presented secret against the stored digest.
+fn node_secret_matches(stored: &str, presented: &str) -> bool {
+    constant_time_eq(stored.as_bytes(), hash_node_secret(presented).as_bytes())
+}
+
+/// Hex string of `len` random bytes.
+fn random_hex(len: usize) -> Result<String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow!("Failed to generate random bytes: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Mints a new random secret for the node, replacing any previous one. Only its hash is stored,
+/// and `last_seen` stays unset until the node's first upload.
+fn register_node(conn: &Connection, node_id: i64) -> Result<String> {
+    let secret = random_hex(32)?;
+
+    conn.execute(
+        "INSERT INTO nodes (node_id, secret) VALUES (?, ?)
+         ON CONFLICT(node_id) DO UPDATE SET secret = excluded.secret",
+        &[Value::Integer(node_id), Value::Text(hash_node_secret(&secret))],
+    )?;
+    Ok(secret)
+}
+
+/// `healthy` within `stale_after_intervals` upload intervals of the last upload, `stale` until
+/// `offline_after_intervals`, `offline` after that.
+fn classify_node_health(last_seen: DateTime<Utc>, interval_seconds: i64, now: DateTime<Utc>, thresholds: &HealthThresholds) -> NodeHealth {
+    let silent_seconds = (now - last_seen).num_seconds();
+    if silent_seconds <= interval_seconds * thresholds.stale_after_intervals {
+        NodeHealth::Healthy
+    } else if silent_seconds <= interval_seconds * thresholds.offline_after_intervals {
+        NodeHealth::Stale
+    } else {
+        NodeHealth::Offline
+    }
+}
+
+/// Average and maximum `received_at - timestamp` over each node's last `INGESTION_LAG_SAMPLE_ROWS`
+/// uploaded rows, in one grouped query. Nodes without rows that have a receive time (imported or
+/// pre-migration rows) are absent.
+fn compute_ingestion_lags(conn: &Connection) -> Result<HashMap<i64, LagStats>> {
+    let result = conn.execute(
+        "SELECT node_id, COUNT(*) AS samples, AVG(lag) AS avg_lag, MAX(lag) AS max_lag FROM (
+             SELECT node_id, (julianday(received_at) - julianday(timestamp)) * 86400.0 AS lag,
+                 ROW_NUMBER() OVER (PARTITION BY node_id ORDER BY id DESC) AS recency
+             FROM log_messages WHERE received_at IS NOT NULL
+         ) WHERE recency <= ? AND lag IS NOT NULL GROUP BY node_id",
+        &[Value::Integer(INGESTION_LAG_SAMPLE_ROWS)],
+    )?;
+
+    let mut lags = HashMap::new();
+    for row in result.rows() {
+        if let (Some(node_id), Some(samples)) = (row.get::<i64>("node_id"), row.get::<i64>("samples")) {
+            lags.insert(
+                node_id,
+                LagStats {
+                    samples,
+                    avg_seconds: row.get::<f64>("avg_lag").unwrap_or(0.0),
+                    max_seconds: row.get::<f64>("max_lag").unwrap_or(0.0),
+                },
+            );
+        }
+    }
+    Ok(lags)
+}
+
+fn get_nodes(conn: &Connection, interval_seconds: i64, thresholds: &HealthThresholds) -> Result<Vec<NodeInfo>> {
+    let result = conn.execute(
+        "SELECT node_id, last_seen, version, secret, logs_received, bytes_ingested, last_upload_bytes FROM nodes ORDER BY node_id",
+        &[],
+    )?;
+    let now = Utc::now();
+    let mut lags = compute_ingestion_lags(conn)?;
+
+    let mut nodes = Vec::new();
+    for row in result.rows() {
+        if let Some(node_id) = row.get::<i64>("node_id") {
+            // A node that never uploaded, or whose last-seen time is unparseable, cannot be vouched for
+            let last_seen = row.get::<&str>("last_seen");
+            let status = match last_seen.map(DateTime::parse_from_rfc3339) {
+                Some(Ok(last_seen)) => classify_node_health(last_seen.with_timezone(&Utc), interval_seconds, now, thresholds),
+                _ => NodeHealth::Offline,
+            };
+            nodes.push(NodeInfo {
+                node_id,
+                last_seen: last_seen.map(|v| v.to_string()),
+                version: row.get::<&str>("version").map(|v| v.to_string()),
+                registered: row.get::<&str>("secret").is_some(),
+                status,
+                logs_received: row.get::<i64>("logs_received").unwrap_or(0),
+                bytes_ingested: row.get::<i64>("bytes_ingested").unwrap_or(0),
+                last_upload_bytes: row.get::<i64>("last_upload_bytes").unwrap_or(0),
+                ingestion_lag: lags.remove(&node_id),
+            });
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// Compares dotted numeric versions (`2.10.1` >= `2.9`); missing components count as 0.
+fn version_at_least(version: &str, minimum: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split('.')
+            .map(|part| part.chars().take_while(|c| c.is_ascii_digit()).collect::<String>().parse().unwrap_or(0))
+            .collect()
+    };
+    let (version, minimum) = (parse(version), parse(minimum));
+    for i in 0..version.len().max(minimum.len()) {
+        let (a, b) = (version.get(i).copied().unwrap_or(0), minimum.get(i).copied().unwrap_or(0));
+        if a != b {
+            return a > b;
+        }
+    }
+    true
+}
+
+fn get_database_size(conn: &Connection) -> Result<i64> {
+    let result = conn.execute(
+        "SELECT page_count * page_size AS size FROM pragma_page_count(), pragma_page_size()",
+        &[],
+    )?;
+    let size = result.rows().next().and_then(|row| row.get::<i64>("size")).unwrap_or(0);
+    Ok(size)
+}
+
+/// Reclaims free pages. Uses `incremental_vacuum` when the database was created with
+/// `auto_vacuum = INCREMENTAL`, otherwise falls back to a full `VACUUM`.
+fn vacuum_database(conn: &Connection) -> Result<&'static str> {
+    let result = conn.execute("SELECT auto_vacuum FROM pragma_auto_vacuum()", &[])?;
+    let auto_vacuum = result.rows().next().and_then(|row| row.get::<i64>("auto_vacuum")).unwrap_or(0);
+
+    if auto_vacuum == 2 {
+        conn.execute("PRAGMA incremental_vacuum", &[])?;
+        Ok("incremental_vacuum")
+    } else {
+        conn.execute("VACUUM", &[])?;
+        Ok("vacuum")
+    }
+}
+
+/// Rebuilds every index of `REINDEX_TABLES`, timing each table.
+fn reindex_tables(conn: &Connection) -> Result<Vec<ReindexTiming>> {
+    let mut timings = Vec::with_capacity(REINDEX_TABLES.len());
+    for &table in REINDEX_TABLES {
+        let started = Instant::now();
+        // Table names come from the constant above; REINDEX cannot take a bound parameter
+        conn.execute(&format!("REINDEX {}", table), &[])?;
+        timings.push(ReindexTiming {
+            table,
+            duration_ms: started.elapsed().as_millis(),
+        });
+    }
+    Ok(timings)
+}
+
+/// Rewrites non-canonical timestamps among the next `RENORMALIZE_BATCH_SIZE` rows after `after_id`.
+fn renormalize_batch(conn: &Connection, after_id: i64, report: &mut RenormalizeResponse) -> Result<i64> {
+    let result = conn.execute(
+        "SELECT id, timestamp FROM log_messages WHERE id > ? ORDER BY id LIMIT ?",
+        &[Value::Integer(after_id), Value::Integer(RENORMALIZE_BATCH_SIZE)],
+    )?;
+    let mut scanned = 0;
+    for row in result.rows() {
+        let id = row.get::<i64>("id").unwrap_or(0);
+        let timestamp = row.get::<&str>("timestamp").unwrap_or("");
+        scanned += 1;
+        report.last_id = id;
+        match normalize_timestamp(timestamp) {
+            Some(canonical) if canonical != timestamp => {
+                conn.execute("UPDATE log_messages SET timestamp = ? WHERE id = ?", &[Value::Text(canonical), Value::Integer(id)])?;
+                report.fixed += 1;
+            }
+            Some(_) => {}
+            None => {
+                log::debug!("Leaving unparseable timestamp '{}' on log message {}", timestamp, id);
+                report.unparseable += 1;
+            }
+        }
+    }
+    report.scanned += scanned;
+    Ok(scanned)
+}
+
+/// Normalizes stored timestamps from `after_id` on, one transaction per batch, stopping after
+/// `MAX_RENORMALIZE_ROWS_PER_REQUEST` rows. Canonical rows are left alone, so reruns are harmless.
+fn renormalize_timestamps(conn: &Connection, after_id: i64) -> Result<RenormalizeResponse> {
+    let mut report = RenormalizeResponse {
+        last_id: after_id,
+        ..Default::default()
+    };
+    while report.scanned < MAX_RENORMALIZE_ROWS_PER_REQUEST {
+        conn.execute("BEGIN IMMEDIATE", &[])?;
+        let scanned = match renormalize_batch(conn, report.last_id, &mut report) {
+            Ok(scanned) => {
+                conn.execute("COMMIT", &[])?;
+                scanned
+            }
+            Err(e) => {
+                _ = conn.execute("ROLLBACK", &[]);
+                return Err(e);
+            }
+        };
+        if scanned < RENORMALIZE_BATCH_SIZE {
+            report.complete = true;
+            break;
+        }
+    }
+    Ok(report)
+}
+
+/// Deletes logs timestamped in `[from, to)`, optionally of one node, in bounded batches; stops
+/// after `MAX_PURGE_ROWS_PER_REQUEST` rows. Returns the count and whether the range is now empty.
+fn purge_logs(conn: &Connection, from: &str, to: &str, node_id: Option<u32>, retention: &RetentionSettings) -> Result<(usize, bool)> {
+    let mut selection = "SELECT id FROM log_messages WHERE timestamp >= ? AND timestamp < ?".to_string();
+    let mut params = vec![Value::Text(from.to_string()), Value::Text(to.to_string())];
+    if let Some(node_id) = node_id {
+        selection.push_str(" AND node_id = ?");
+        params.push(Value::Integer(node_id as i64));
+    }
+    selection.push_str(&format!(" LIMIT {}", PURGE_BATCH_SIZE));
+
+    let mut deleted = 0;
+    while deleted < MAX_PURGE_ROWS_PER_REQUEST {
+        let removed = remove_log_rows(conn, &selection, &params, retention)?;
+        deleted += removed;
+        if (removed as i64) < PURGE_BATCH_SIZE {
+            return Ok((deleted, true));
+        }
+    }
+    Ok((deleted, false))
+}
+
+fn restore_log_rows(conn: &Connection, from: &str, to: &str) -> Result<usize> {
+    let window = [Value::Text(from.to_string()), Value::Text(to.to_string())];
+    conn.execute(
+        "INSERT OR IGNORE INTO log_messages (id, timestamp, node_id, message, repeat_count, received_at, source, metric, value, tenant)
+         SELECT id, timestamp, node_id, message, repeat_count, received_at, source, metric, value, tenant FROM deleted_log_messages
+         WHERE timestamp >= ? AND timestamp < ?",
+        &window,
+    )?;
+    let result = conn.execute("DELETE FROM deleted_log_messages WHERE timestamp >= ? AND timestamp < ? RETURNING id", &window)?;
+    let restored = result.rows().count();
+    Ok(restored)
+}
+
+/// Moves soft-deleted rows with a timestamp in `[from, to)` back into `log_messages` under their
+/// original ids; returns how many were restored.
+fn undelete_log_messages(conn: &Connection, from: &str, to: &str) -> Result<usize> {
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+    match restore_log_rows(conn, from, to) {
+        Ok(restored) => {
+            conn.execute("COMMIT", &[])?;
+            Ok(restored)
+        }
+        Err(e) => {
+            _ = conn.execute("ROLLBACK", &[]);
+            Err(e)
+        }
+    }
+}
+
+/// Every node known to the hub, read from the `nodes` table instead of scanning `log_messages`.
+fn get_all_node_ids(conn: &Connection) -> Result<Vec<i64>> {
+    let result = conn.execute("SELECT node_id FROM nodes ORDER BY node_id", &[])?;
+
+    let mut node_ids = Vec::new();
+    for row in result.rows() {
+        if let Some(node_id) = row.get::<i64>("node_id") {
+            node_ids.push(node_id);
+        }
+    }
+
+    Ok(node_ids)
+}
+
+/// Nodes whose last upload is within the past `window_minutes`.
+fn get_active_node_ids(conn: &Connection, window_minutes: i64) -> Result<Vec<i64>> {
+    let since = Utc::now() - chrono::Duration::minutes(window_minutes);
+    let result = conn.execute(
+        "SELECT node_id FROM nodes WHERE last_seen >= ? ORDER BY node_id",
+        &[Value::Text(since.to_rfc3339())],
+    )?;
+
+    let mut node_ids = Vec::new();
+    for row in result.rows() {
+        if let Some(node_id) = row.get::<i64>("node_id") {
+            node_ids.push(node_id);
+        }
+    }
+
+    Ok(node_ids)
+}
+
+/// Target set for broadcast commands, per the `broadcast_scope` variable (`all` or `active`).
+fn get_broadcast_node_ids(conn: &Connection) -> Result<Vec<i64>> {
+    match variables::get("broadcast_scope").unwrap_or_default().as_str() {
+        "active" => {
+            let delete_timeout = get_i64_variable("delete_timeout_minutes", DEFAULT_DELETE_TIMEOUT_MINUTES);
+            let window = get_i64_variable("broadcast_active_window_minutes", delete_timeout);
+            get_active_node_ids(conn, window)
+        }
+        "" | "all" => get_all_node_ids(conn),
+        other => {
+            log::warn!("Unknown broadcast_scope '{}', broadcasting to all nodes", other);
+            get_all_node_ids(conn)
+        }
+    }
+}
+
+// ============================================================================
+// Key-Value Store Operations
+// ============================================================================
+
+/// Opens the default KV store. It only holds non-critical scheduling state, so an outage is
+/// logged and the hot paths continue without cleanup scheduling and with the default interval.
+fn open_store() -> Option<Store> {
+    match Store::open_default() {
+        Ok(store) => Some(store),
+        Err(e) => {
+            log::warn!("Key-value store unavailable, continuing without it: {}", e);
+            None
+        }
+    }
+}
+
+fn should_cleanup(store: &Store, cleanup_interval_minutes: i64) -> Result<bool> {
+    match store.get("last_cleanup_time") {
+        Ok(Some(bytes)) => {
+            let last_cleanup_str = String::from_utf8(bytes)?;
+            let last_cleanup: DateTime<Utc> = last_cleanup_str.parse()?;
+            let now = Utc::now();
+            Ok((now - last_cleanup).num_minutes() >= cleanup_interval_minutes)
+        }
+        _ => Ok(true),
+    }
+}
+
+fn save_cleanup_report(store: &Store, report: &CleanupReport) -> Result<()> {
+    let json = serde_json::to_string(report)?;
+    store.set("last_cleanup_report", json.as_bytes())?;
+    Ok(())
+}
+
+fn update_last_cleanup_time(store: &Store) -> Result<()> {
+    let now = Utc::now().to_rfc3339();
+    store.set("last_cleanup_time", now.as_bytes())?;
+    Ok(())
+}
+
+/// Claims the maintenance lock shared by cleanup and the maintenance endpoints; returns the
+/// claim token to release it with, or `None` if another run holds it. A lock older than
+/// `MAINTENANCE_LOCK_TIMEOUT_SECONDS` is considered abandoned and taken over.
+///
+/// The KV store has no compare-and-swap, so the lock is a row in SQLite: one upsert either
+/// inserts it, replaces a stale claim, or changes nothing, and only a caller whose own token
+/// comes back from `RETURNING` holds it.
+fn try_acquire_maintenance_lock(conn: &Connection) -> Result<Option<String>> {
+    let now = Utc::now();
+    let token = random_hex(8)?;
+    let stale_before = now - chrono::Duration::seconds(MAINTENANCE_LOCK_TIMEOUT_SECONDS);
+    // Fixed-width UTC timestamps, so the staleness check can compare them as text
+    let result = conn.execute(
+        "INSERT INTO maintenance_lock (id, token, locked_at) VALUES (1, ?, ?)
+         ON CONFLICT(id) DO UPDATE SET token = excluded.token, locked_at = excluded.locked_at
+             WHERE maintenance_lock.locked_at < ?
+         RETURNING token",
+        &[
+            Value::Text(token.clone()),
+            Value::Text(now.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            Value::Text(stale_before.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        ],
+    )?;
+    let claimed = result.rows().next().and_then(|row| row.get::<&str>("token").map(|v| v == token)).unwrap_or(false);
+    Ok(claimed.then_some(token))
+}
+
+/// Releases the lock if `token` still holds it; a run whose lock was taken over as stale
+/// must not drop the new holder's claim.
+fn release_maintenance_lock(conn: &Connection, token: &str) -> Result<()> {
+    let result = conn.execute("DELETE FROM maintenance_lock WHERE token = ? RETURNING token", &[Value::Text(token.to_string())])?;
+    if result.rows().next().is_none() {
+        log::warn!("Maintenance lock was taken over while held; leaving the new claim in place");
+    }
+    Ok(())
+}
+
+fn command_template_key(name: &str) -> String {
+    format!("command_template:{}", name)
+}
+
+fn node_group_key(name: &str) -> String {
+    format!("node_group:{}", name)
+}
+
+/// Every KV entry as a JSON object: values that are JSON (interval config, templates, cursors)
+/// are embedded parsed, anything else (timestamps) as a string.
+fn dump_kv_state(store: &Store) -> Result<serde_json::Value> {
+    let mut keys = store.get_keys()?;
+    keys.sort();
+
+    let mut state = serde_json::Map::new();
+    for key in keys {
+        let Some(bytes) = store.get(&key)? else {
+            continue;
+        };
+        let value = serde_json::from_slice::<serde_json::Value>(&bytes)
+            .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        state.insert(key, value);
+    }
+    Ok(serde_json::Value::Object(state))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TransientKvEntry {
+    expires_at: String,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+/// KV key listing the live keys under one of `TRANSIENT_KV_PREFIXES`. The KV store cannot list
+/// keys by prefix, so this index is what lets the sweep skip the rest of the store.
+fn transient_kv_index_key(prefix: &str) -> String {
+    format!("transient_index:{}", prefix)
+}
+
+fn load_transient_kv_index(store: &Store, prefix: &str) -> Result<Vec<String>> {
+    match store.get(transient_kv_index_key(prefix))? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            log::warn!("Ignoring invalid transient KV index for '{}': {}", prefix, e);
+            Vec::new()
+        })),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Writes a short-lived entry (`prefix` must be one of `TRANSIENT_KV_PREFIXES`) and records its key
+/// in the prefix's index so the cleanup cycle evicts it after `expires_at`. The index is updated
+/// read-modify-write, so readers must still treat an entry past `expires_at` as absent.
+fn put_transient_kv(store: &Store, prefix: &str, name: &str, expires_at: DateTime<Utc>, value: serde_json::Value) -> Result<()> {
+    let key = format!("{}{}", prefix, name);
+    let entry = TransientKvEntry {
+        expires_at: expires_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        value,
+    };
+    store.set(&key, &serde_json::to_vec(&entry)?)?;
+    let mut index = load_transient_kv_index(store, prefix)?;
+    if !index.contains(&key) {
+        index.push(key);
+        store.set(transient_kv_index_key(prefix), &serde_json::to_vec(&index)?)?;
+    }
+    Ok(())
+}
+
+/// Reads the value of a transient entry. An entry past its `expires_at` that the sweep has not
+/// removed yet counts as absent.
+fn get_transient_kv(store: &Store, prefix: &str, name: &str, now: DateTime<Utc>) -> Result<Option<serde_json::Value>> {
+    let Some(bytes) = store.get(format!("{}{}", prefix, name))? else {
+        return Ok(None);
+    };
+    if transient_kv_expired(&bytes, now) {
+        return Ok(None);
+    }
+    Ok(serde_json::from_slice::<TransientKvEntry>(&bytes).ok().map(|entry| entry.value))
+}
+
+/// Whether a transient entry has expired at `now`. Entries that are not a valid envelope count
+/// as expired: nothing else writes under these prefixes, so they can only be leftovers.
+fn transient_kv_expired(bytes: &[u8], now: DateTime<Utc>) -> bool {
+    serde_json::from_slice::<TransientKvEntry>(bytes)
+        .ok()
+        .and_then(|entry| DateTime::parse_from_rfc3339(&entry.expires_at).ok())
+        .is_none_or(|expires_at| expires_at.with_timezone(&Utc) <= now)
+}
+
+/// Deletes expired idempotency and rate-limit entries and returns how many were removed. Only the
+/// keys listed in each prefix's index are read; a prefix never written costs one lookup.
+fn sweep_transient_kv(store: &Store, now: DateTime<Utc>) -> Result<usize> {
+    let mut removed = 0;
+    for prefix in TRANSIENT_KV_PREFIXES {
+        let index = load_transient_kv_index(store, prefix)?;
+        if index.is_empty() {
+            continue;
+        }
+        let mut evicted = Vec::new();
+        for key in &index {
+            match store.get(key)? {
+                Some(bytes) if transient_kv_expired(&bytes, now) => {
+                    store.delete(key)?;
+                    removed += 1;
+                    evicted.push(key.as_str());
+                }
+                Some(_) => {}
+                // Already gone (e.g. deleted on read) since it was indexed
+                None => evicted.push(key.as_str()),
+            }
+        }
+        if evicted.is_empty() {
+            continue;
+        }
+        // Re-read right before writing back, so keys indexed during the sweep are kept
+        let remaining: Vec<String> = load_transient_kv_index(store, prefix)?.into_iter().filter(|key| !evicted.contains(&key.as_str())).collect();
+        if remaining.is_empty() {
+            store.delete(transient_kv_index_key(prefix))?;
+        } else {
+            store.set(transient_kv_index_key(prefix), &serde_json::to_vec(&remaining)?)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Collector ids are picked per deployment, so with tenancy two tenants may both run a "c1".
+/// Their cursors and acks are kept apart by storing them as `{tenant}:{collector_id}`.
+fn scoped_collector_id(tenant: Option<&str>, collector_id: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{}:{}", tenant, collector_id),
+        None => collector_id.to_string(),
+    }
+}
+
+fn collector_cursor_key(tenant: Option<&str>, collector_id: &str) -> String {
+    format!("collector_cursor:{}", scoped_collector_id(tenant, collector_id))
+}
+
+fn save_collector_cursor(store: &Store, tenant: Option<&str>, collector_id: &str, last_id: i64) -> Result<()> {
+    store.set(collector_cursor_key(tenant, collector_id), last_id.to_string().as_bytes())?;
+    Ok(())
+}
+
+fn load_collector_cursor(store: &Store, tenant: Option<&str>, collector_id: &str) -> Result<Option<i64>> {
+    let cursor = store
+        .get(collector_cursor_key(tenant, collector_id))?
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|value| value.parse::<i64>().ok());
+    Ok(cursor)
+}
+
+fn webhook_key(node_id: u32) -> String {
+    format!("webhook:{}", node_id)
+}
+
+fn save_webhook(store: &Store, node_id: u32, config: &WebhookConfig) -> Result<()> {
+    let json = serde_json::to_string(config)?;
+    store.set(webhook_key(node_id), json.as_bytes())?;
+    Ok(())
+}
+
+fn load_webhook(store: &Store, node_id: u32) -> Result<Option<WebhookConfig>> {
+    match store.get(webhook_key(node_id))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+fn delete_webhook(store: &Store, node_id: u32) -> Result<bool> {
+    let key = webhook_key(node_id);
+    if !store.exists(&key)? {
+        return Ok(false);
+    }
+    store.delete(&key)?;
+    Ok(true)
+}
+
+fn webhook_circuit_key(url: &str) -> String {
+    format!("webhook_circuit:{}", url)
+}
+
+fn load_webhook_circuit(store: &Store, url: &str) -> Result<WebhookCircuit> {
+    match store.get(webhook_circuit_key(url))? {
+        // An unreadable entry starts over rather than blocking notifications for good
+        Some(bytes) => Ok(serde_json::from_slice(&bytes).unwrap_or_default()),
+        None => Ok(WebhookCircuit::default()),
+    }
+}
+
+/// Whether notifications to `url` go out, from its failure record and `webhook_cooldown_seconds`.
+fn webhook_circuit_state(store: &Store, url: &str) -> Result<WebhookCircuitState> {
+    let circuit = load_webhook_circuit(store, url)?;
+    let Some(opened_at) = circuit.opened_at.as_deref().and_then(|value| DateTime::parse_from_rfc3339(value).ok()) else {
+        return Ok(WebhookCircuitState::Closed);
+    };
+    let cooldown_seconds = get_i64_variable("webhook_cooldown_seconds", DEFAULT_WEBHOOK_COOLDOWN_SECONDS).max(0);
+    let retry_at = opened_at.with_timezone(&Utc) + chrono::Duration::seconds(cooldown_seconds);
+    if Utc::now() < retry_at {
+        Ok(WebhookCircuitState::Open { retry_at })
+    } else {
+        Ok(WebhookCircuitState::HalfOpen)
+    }
+}
+
+/// Counts the outcome of a call to `url`. A success closes the circuit; the
+/// `webhook_failure_threshold`-th consecutive failure, or a failed trial call, opens it (0 never opens).
+fn record_webhook_outcome(store: &Store, url: &str, succeeded: bool) -> Result<()> {
+    let key = webhook_circuit_key(url);
+    if succeeded {
+        if let Some(bytes) = store.get(&key)? {
+            store.delete(&key)?;
+            if serde_json::from_slice::<WebhookCircuit>(&bytes).is_ok_and(|circuit| circuit.opened_at.is_some()) {
+                log::info!("Webhook {} recovered; notifications resumed", url);
+            }
+        }
+        return Ok(());
+    }
+
+    let threshold = get_i64_variable("webhook_failure_threshold", DEFAULT_WEBHOOK_FAILURE_THRESHOLD);
+    let mut circuit = load_webhook_circuit(store, url)?;
+    circuit.consecutive_failures += 1;
+    if threshold > 0 && (circuit.opened_at.is_some() || circuit.consecutive_failures >= threshold) {
+        circuit.opened_at = Some(Utc::now().to_rfc3339());
+        log::warn!(
+            "Webhook {} failed {} times in a row; suppressing notifications for {} seconds",
+            url,
+            circuit.consecutive_failures,
+            get_i64_variable("webhook_cooldown_seconds", DEFAULT_WEBHOOK_COOLDOWN_SECONDS).max(0)
+        );
+    }
+    store.set(&key, serde_json::to_string(&circuit)?.as_bytes())?;
+    Ok(())
+}
+
+/// Position in `LOG_LEVELS`, so a higher rank is more severe.
+fn log_level_rank(level: &str) -> Option<usize> {
+    LOG_LEVELS.iter().position(|known| known.eq_ignore_ascii_case(level))
+}
+
+/// POSTs a summary of the upload's matching entries to the node's webhook, if one is configured.
+/// Only logs failures: a broken webhook must never fail the probe's upload. While the URL's
+/// circuit is open the call is skipped, so a failing endpoint stops adding latency to uploads.
+fn notify_webhook(store: &Store, node_id: u32, logs: &[LogEntry], default_level: Option<&str>, redaction_patterns: &[Regex]) {
+    let config = match load_webhook(store, node_id) {
+        Ok(Some(config)) => config,
+        Ok(None) => return,
+        Err(e) => {
+            log::warn!("Failed to load webhook for node_id {}: {}", node_id, e);
+            return;
+        }
+    };
+    let min_rank = config.min_level.as_deref().and_then(log_level_rank);
+    let matching: Vec<&LogEntry> = logs
+        .iter()
+        .filter(|log| match min_rank {
+            Some(min_rank) => log_level(&log.message).or(default_level).and_then(log_level_rank).is_some_and(|rank| rank >= min_rank),
+            None => true,
+        })
+        .collect();
+    if matching.is_empty() {
+        return;
+    }
+
+    match webhook_circuit_state(store, &config.url) {
+        Ok(WebhookCircuitState::Closed) => {}
+        Ok(WebhookCircuitState::Open { retry_at }) => {
+            log::info!("Webhook circuit for node_id {} is open until {}; notification suppressed", node_id, retry_at.to_rfc3339());
+            return;
+        }
+        Ok(WebhookCircuitState::HalfOpen) => log::info!("Webhook circuit for node_id {} is half-open; trying a notification", node_id),
+        Err(e) => log::warn!("Failed to read webhook circuit for node_id {}: {}", node_id, e),
+    }
+
+    let payload = WebhookPayload {
+        node_id,
+        received_at: Utc::now().to_rfc3339(),
+        matched: matching.len(),
+        logs: matching
+            .into_iter()
+            .take(MAX_WEBHOOK_MESSAGES)
+            .map(|log| LogEntry {
+                timestamp: log.timestamp.clone(),
+                message: redact_message(redaction_patterns, &log.message).0.unwrap_or_else(|| log.message.clone()),
+                source: log.source.clone(),
+                metric: log.metric.clone(),
+                value: log.value,
+            })
+            .collect(),
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            log::warn!("Failed to serialize webhook payload for node_id {}: {}", node_id, e);
+            return;
+        }
+    };
+    let request = Request::builder()
+        .method(Method::Post)
+        .uri(config.url.as_str())
+        .header("content-type", "application/json")
+        .body(body)
+        .build();
+    let started = Instant::now();
+    let succeeded = match spin_sdk::http::run(spin_sdk::http::send::<_, Response>(request)) {
+        Ok(response) if (200..300).contains(response.status()) => {
+            log::debug!("Webhook for node_id {} accepted {} entries", node_id, payload.matched);
+            true
+        }
+        Ok(response) => {
+            log::warn!("Webhook for node_id {} returned status {}", node_id, response.status());
+            false
+        }
+        Err(e) => {
+            log::warn!("Webhook for node_id {} failed: {}", node_id, e);
+            false
+        }
+    };
+    let elapsed_ms = started.elapsed().as_millis();
+    let succeeded = if succeeded && elapsed_ms > WEBHOOK_SLOW_CALL_MS {
+        log::warn!("Webhook for node_id {} took {} ms; counted as a failure", node_id, elapsed_ms);
+        false
+    } else {
+        succeeded
+    };
+    if let Err(e) = record_webhook_outcome(store, &config.url, succeeded) {
+        log::warn!("Failed to record webhook outcome for node_id {}: {}", node_id, e);
+    }
+}
+
+fn save_command_template(store: &Store, name: &str, template: &Command) -> Result<()> {
+    let json = serde_json::to_string(template)?;
+    store.set(command_template_key(name), json.as_bytes())?;
+    Ok(())
+}
+
+fn load_command_template(store: &Store, name: &str) -> Result<Option<Command>> {
+    match store.get(command_template_key(name))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+fn delete_command_template(store: &Store, name: &str) -> Result<bool> {
+    let key = command_template_key(name);
+    if !store.exists(&key)? {
+        return Ok(false);
+    }
+    store.delete(&key)?;
+    Ok(true)
+}
+
+fn save_node_group(store: &Store, name: &str, group: &NodeGroup) -> Result<()> {
+    let json = serde_json::to_string(group)?;
+    store.set(node_group_key(name), json.as_bytes())?;
+    Ok(())
+}
+
+fn load_node_group(store: &Store, name: &str) -> Result<Option<NodeGroup>> {
+    match store.get(node_group_key(name))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+fn delete_node_group(store: &Store, name: &str) -> Result<bool> {
+    let key = node_group_key(name);
+    if !store.exists(&key)? {
+        return Ok(false);
+    }
+    store.delete(&key)?;
+    Ok(true)
+}
+
+/// Shallow-merges `overrides` into the template's parameters (override keys win).
+fn apply_template_overrides(template: Command, overrides: Option<serde_json::Value>) -> Result<Command, String> {
+    let parameters = match (template.parameters, overrides) {
+        (parameters, None) => parameters,
+        (None, Some(overrides)) => Some(overrides),
+        (Some(serde_json::Value::Object(mut base)), Some(serde_json::Value::Object(overrides))) => {
+            base.extend(overrides);
+            Some(serde_json::Value::Object(base))
+        }
+        _ => return Err("Template parameters and overrides must both be JSON objects".to_string()),
+    };
+    Ok(Command {
+        id: None,
+        command: template.command,
+        parameters,
+    })
+}
+
+fn save_update_interval_config(store: &Store, config: &UpdateIntervalConfig) -> Result<()> {
+    let json = serde_json::to_string(config)?;
+    store.set("update_interval_config", json.as_bytes())?;
+    Ok(())
+}
+
+fn get_update_interval_config(store: &Store) -> Option<UpdateIntervalConfig> {
+    store
+        .get("update_interval_config")
+        .ok()
+        .and_then(|opt| opt)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|s| serde_json::from_str(&s).ok())
+}
+
+/// `X-Retention` header value with the effective cleanup settings, only when the `debug`
+/// variable is on, so operators can confirm their variables took effect.
+fn retention_debug_header() -> Option<String> {
+    get_bool_variable("debug", false).then(|| {
+        format!(
+            "cleanup_interval_minutes={}, delete_timeout_minutes={}",
+            get_i64_variable("cleanup_interval_minutes", DEFAULT_CLEANUP_INTERVAL_MINUTES),
+            get_i64_variable("delete_timeout_minutes", DEFAULT_DELETE_TIMEOUT_MINUTES)
+        )
+    })
+}
+
+/// Runs the retention cleanup when the cleanup interval has elapsed and no maintenance holds the lock.
+/// Requests that lose the claim skip cleanup instead of waiting.
+fn run_scheduled_cleanup(conn: &Connection, store: &Store) -> Result<()> {
+    let cleanup_interval = get_i64_variable("cleanup_interval_minutes", DEFAULT_CLEANUP_INTERVAL_MINUTES);
+    let delete_timeout = get_i64_variable("delete_timeout_minutes", DEFAULT_DELETE_TIMEOUT_MINUTES);
+
+    if !should_cleanup(store, cleanup_interval)? {
+        return Ok(());
+    }
+    let Some(lock) = try_acquire_maintenance_lock(conn)? else {
+        // Not recorded as a run, so the next request past the interval retries
+        log::info!("Scheduled cleanup deferred: the maintenance lock is held by another run");
+        return Ok(());
+    };
+    // Check again under the lock: a run that finished between the first check and our claim
+    // has already cleaned up
+    if !should_cleanup(store, cleanup_interval)? {
+        log::debug!("Scheduled cleanup not needed: another run finished while the lock was being claimed");
+        return release_maintenance_lock(conn, &lock);
+    }
+
+    let retention = RetentionSettings::from_variables();
+    let mut cleanup_result = cleanup_old_data(conn, delete_timeout, &retention).and_then(|report| save_cleanup_report(store, &report));
+
+    // Optional size-based reclaim on top of the age-based cleanup
+    let reclaim_threshold = get_i64_variable("node_reclaim_threshold", 0);
+    if cleanup_result.is_ok() && reclaim_threshold > 0 {
+        cleanup_result = reclaim_largest_nodes(conn, reclaim_threshold, &retention).map(|_| ());
+    }
+    // KV eviction is best effort: a failure must not hold back the database cleanup schedule
+    if cleanup_result.is_ok() {
+        match sweep_transient_kv(store, Utc::now()) {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Evicted {} expired transient KV entries", removed),
+            Err(e) => log::warn!("Transient KV sweep failed: {}", e),
+        }
+    }
+    // Record the run before releasing, so the next claimant's re-check sees it
+    if cleanup_result.is_ok() {
+        update_last_cleanup_time(store)?;
+    }
+    release_maintenance_lock(conn, &lock)?;
+    cleanup_result
+}
+
+/// Current upload interval, falling back to `default_upload_interval` when the KV store is unavailable.
+fn current_update_interval(store: Option<&Store>) -> i64 {
+    let default_interval = get_i64_variable("default_upload_interval", DEFAULT_UPLOAD_INTERVAL_SECONDS);
+    match store {
+        Some(store) => get_current_update_interval(store, default_interval),
+        None => default_interval,
+    }
+}
+
+fn get_current_update_interval(store: &Store, default_interval: i64) -> i64 {
+    match get_update_interval_config(store) {
+        Some(config) => {
+            let now = Utc::now().timestamp() as u64;
+            if now >= config.start_time && now <= config.end_time {
+                log::debug!("In active period, using active_period: {}", config.active_period);
+                config.active_period
+            } else {
+                log::debug!("In inactive period, using inactive_period: {}", config.inactive_period);
+                config.inactive_period
+            }
+        }
+        None => {
+            log::debug!("No update interval config found, using default: {}", default_interval);
+            default_interval
+        }
+    }
+}
+
+// ============================================================================
+// HTTP Handlers
+// ============================================================================
+
+// Every response goes through one of these builders so it always carries a content-type.
+
+fn text_response(status: u16, body: impl Into<String>) -> Response {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(body.into())
+        .build()
+}
+
+fn json_response<T: Serialize>(status: u16, value: &T) -> Result<Response> {
+    let response_body = serde_json::to_string(value)?;
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(response_body)
+        .build())
+}
+
+/// Sends an error as plain text (its message), or with `structured_errors` as JSON with its code.
+fn error_reply(status: u16, error: ErrorResponse) -> Response {
+    if get_bool_variable("structured_errors", false) {
+        if let Ok(response) = json_response(status, &error) {
+            return response;
+        }
+    }
+    text_response(status, error.message)
+}
+
+/// Builds an error with a stable code naming the client's mistake (`{"error": "<code>"}` as JSON).
+fn error_response(status: u16, error: &str, message: impl Into<String>) -> Response {
+    let response = ErrorResponse {
+        error: error.to_string(),
+        message: message.into(),
+        index: None,
+    };
+    error_reply(status, response)
+}
+
+/// Builds an error that has no specific code; it carries the one of its status.
+fn status_error(status: u16, message: impl Into<String>) -> Response {
+    let error = match status {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        409 => "conflict",
+        413 => "payload_too_large",
+        415 => "unsupported_media_type",
+        429 => "too_many_requests",
+        503 => "service_unavailable",
+        _ => "internal_error",
+    };
+    error_response(status, error, message)
+}
+
+/// Returns a 415 response when the request declares a content type outside `accepted`.
+/// Requests without a `content-type` header are treated as JSON for older clients.
+fn unsupported_content_type(req: &Request, accepted: &[&str]) -> Option<Response> {
+    let content_type = req.header("content-type").and_then(|v| v.as_str())?;
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if accepted.contains(&media_type.as_str()) {
+        return None;
+    }
+    Some(status_error(
+        415,
+        format!("Unsupported Media Type '{}': expected one of {}", media_type, accepted.join(", ")),
+    ))
+}
+
+/// True when the SHA-256 of `body` matches `expected`, a hex digest (case-insensitive).
+fn verify_body_checksum(body: &[u8], expected: &str) -> bool {
+    let digest = Sha256::digest(body);
+    let actual: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    actual.eq_ignore_ascii_case(expected.trim())
+}
+
+/// Returns the request body with any `content-encoding: gzip` removed. Inflation stops at
+/// `MAX_DECOMPRESSED_BODY_BYTES`; an oversize, corrupt, or otherwise encoded body yields the error response.
+fn decoded_body(req: &Request) -> Result<Cow<'_, [u8]>, Response> {
+    decoded_body_up_to(req, MAX_DECOMPRESSED_BODY_BYTES)
+}
+
+/// `decoded_body` with an endpoint-specific inflation limit.
+fn decoded_body_up_to(req: &Request, max_bytes: u64) -> Result<Cow<'_, [u8]>, Response> {
+    let encoding = req.header("content-encoding").and_then(|v| v.as_str()).map(|v| v.trim().to_ascii_lowercase());
+    match encoding.as_deref() {
+        None | Some("") | Some("identity") => Ok(Cow::Borrowed(req.body())),
+        Some("gzip") => {
+            let mut inflated = Vec::new();
+            let mut decoder = GzDecoder::new(req.body()).take(max_bytes + 1);
+            if decoder.read_to_end(&mut inflated).is_err() {
+                return Err(status_error(400, "Invalid gzip body"));
+            }
+            if inflated.len() as u64 > max_bytes {
+                return Err(status_error(413, format!("Decompressed body exceeds the limit of {} bytes", max_bytes)));
+            }
+            Ok(Cow::Owned(inflated))
+        }
+        Some(other) => Err(status_error(415, format!("Unsupported Content-Encoding '{}': expected gzip", other))),
+    }
+}
+
+/// Decodes an `/update` body into its log entries: `content-encoding` is removed first (see
+/// `decoded_body`), then `content-type` picks the parser. A missing content type means JSON.
+/// New formats add a media type to `ACCEPTED_PROBE_UPLOAD_CONTENT_TYPES` and an arm here.
+fn decode_upload(req: &Request) -> Result<Vec<LogEntry>, Response> {
+    if let Some(response) = unsupported_content_type(req, ACCEPTED_PROBE_UPLOAD_CONTENT_TYPES) {
+        return Err(response);
+    }
+    let media_type = req
+        .header("content-type")
+        .and_then(|v| v.as_str())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .unwrap_or_else(|| "application/json".to_string());
+    let body = decoded_body(req)?;
+    match media_type.as_str() {
+        "application/x-ndjson" => {
+            let text = std::str::from_utf8(&body).map_err(|_| status_error(400, "Body is not valid UTF-8"))?;
+            let mut logs = Vec::new();
+            for (line_number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                match serde_json::from_str::<LogEntry>(line) {
+                    Ok(entry) => logs.push(entry),
+                    Err(e) => return Err(status_error(400, format!("Invalid log entry on line {}: {}", line_number + 1, e))),
+                }
+            }
+            Ok(logs)
+        }
+        _ => match serde_json::from_slice::<ProbeUploadRequest>(&body) {
+            Ok(upload) => Ok(upload.logs),
+            Err(e) => Err(status_error(400, format!("Invalid JSON body: {}", e))),
+        },
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() {
+            if let Ok(byte) = u8::from_str_radix(&value[i + 1..i + 3], 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Returns the percent-decoded value of a query string parameter, if present.
+fn get_query_param(uri: &str, name: &str) -> Option<String> {
+    get_query_params(uri, name).into_iter().next()
+}
+
+/// Every value of a repeated query parameter, in order.
+fn get_query_params(uri: &str, name: &str) -> Vec<String> {
+    let Some((_, query)) = uri.split_once('?') else {
+        return Vec::new();
+    };
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+        .collect()
+}
+
+/// Parses repeated and/or comma-separated `node_id` values, bounded by `MAX_NODE_ID_FILTER`.
+fn parse_node_id_params(values: &[String]) -> Result<Vec<i64>, String> {
+    let mut node_ids = Vec::new();
+    for item in values.iter().flat_map(|value| value.split(',')) {
+        match item.trim().parse::<u32>() {
+            Ok(node_id) => node_ids.push(node_id as i64),
+            Err(_) => return Err(format!("Invalid node_id '{}': expected a non-negative integer", item.trim())),
+        }
+    }
+    node_ids.sort_unstable();
+    node_ids.dedup();
+    if node_ids.len() > MAX_NODE_ID_FILTER {
+        return Err(format!("Too many node_id values: at most {} allowed", MAX_NODE_ID_FILTER));
+    }
+    Ok(node_ids)
+}
+
+/// Parses a comma-separated `fields=` list, rejecting names outside `DOWNLOAD_LOG_FIELDS`.
+fn parse_fields_param(value: &str) -> Result<Vec<String>, String> {
+    let fields: Vec<String> = value.split(',').map(|f| f.trim().to_string()).filter(|f| !f.is_empty()).collect();
+    if fields.is_empty() {
+        return Err("Invalid fields: at least one field is required".to_string());
+    }
+    match fields.iter().find(|f| !DOWNLOAD_LOG_FIELDS.contains(&f.as_str())) {
+        Some(unknown) => Err(format!("Unknown field '{}', allowed: {}", unknown, DOWNLOAD_LOG_FIELDS.join(","))),
+        None => Ok(fields),
+    }
+}
+
+fn project_log_entries(logs: &[DownloadLogEntry], fields: &[String]) -> Result<Vec<serde_json::Value>> {
+    let mut projected = Vec::with_capacity(logs.len());
+    for log in logs {
+        let mut entry = match serde_json::to_value(log)? {
+            serde_json::Value::Object(map) => map,
+            _ => return Err(anyhow!("Log entry did not serialize to an object")),
+        };
+        entry.retain(|key, _| fields.contains(key));
+        projected.push(serde_json::Value::Object(entry));
+    }
+    Ok(projected)
+}
+
+/// Parses an optional RFC 3339 query parameter into the canonical `...Z` form used for comparisons.
+fn parse_timestamp_param(uri: &str, name: &str) -> Result<Option<String>, String> {
+    match get_query_param(uri, name) {
+        Some(value) => DateTime::parse_from_rfc3339(&value)
+            .map(|t| Some(t.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true)))
+            .map_err(|_| format!("Invalid {}: expected ISO 8601 timestamp", name)),
+        None => Ok(None),
+    }
+}
+
+/// How requests authenticate, from the `auth_mode` variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AuthMode {
+    /// `X-Api-Key` compared with the per-role key variables (default)
+    ApiKey,
+    /// `Authorization: Bearer` HS256 token signed with `jwt_secret`, role taken from its `scope` claim
+    Jwt,
+}
+
+fn get_auth_mode() -> AuthMode {
+    match variables::get("auth_mode").unwrap_or_default().trim() {
+        "" | "api_key" => AuthMode::ApiKey,
+        "jwt" => AuthMode::Jwt,
+        other => {
+            log::warn!("Ignoring invalid auth_mode variable '{}': expected api_key or jwt", other);
+            AuthMode::ApiKey
+        }
+    }
+}
+
+/// JWT scope that stands in for each API key variable.
+fn auth_scope(api_key_variable: &str) -> &'static str {
+    match api_key_variable {
+        "probe_api_key" => "probe",
+        "log_collector_api_key" => "collector",
+        "cli_api_key" => "cli",
+        "admin_api_key" => "admin",
+        _ => "",
+    }
+}
+
+/// Claims checked on a bearer token; any others are ignored.
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    exp: i64,
+    nbf: Option<i64>,
+    /// Space-separated string (OAuth style) or array of scopes
+    #[serde(default)]
+    scope: serde_json::Value,
+}
+
+impl JwtClaims {
+    fn has_scope(&self, scope: &str) -> bool {
+        match &self.scope {
+            serde_json::Value::String(scopes) => scopes.split_whitespace().any(|s| s == scope),
+            serde_json::Value::Array(scopes) => scopes.iter().any(|s| s.as_str() == Some(scope)),
+            _ => false,
+        }
+    }
+}
+
+/// Verifies an HS256 token's signature and validity window at `now` (Unix seconds). Only HS256
+/// is accepted, so a token cannot pick a weaker algorithm (or `none`) for itself.
+fn verify_jwt(token: &str, secret: &[u8], now: i64) -> Result<JwtClaims, &'static str> {
+    let Some((signing_input, signature)) = token.rsplit_once('.') else {
+        return Err("malformed token");
+    };
+    let Some((header, payload)) = signing_input.split_once('.').filter(|(_, payload)| !payload.contains('.')) else {
+        return Err("malformed token");
+    };
+    let header: serde_json::Value = URL_SAFE_NO_PAD
+        .decode(header)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or("malformed header")?;
+    if header.get("alg").and_then(|alg| alg.as_str()) != Some("HS256") {
+        return Err("unsupported alg");
+    }
+
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed signature")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|_| "invalid secret")?;
+    mac.update(signing_input.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| "bad signature")?;
+
+    let claims: JwtClaims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or("malformed claims")?;
+    if claims.exp + JWT_CLOCK_SKEW_SECONDS <= now {
+        return Err("expired");
+    }
+    if claims.nbf.is_some_and(|nbf| nbf - JWT_CLOCK_SKEW_SECONDS > now) {
+        return Err("not yet valid");
+    }
+    Ok(claims)
+}
+
+/// Checks the `Authorization: Bearer` token against `jwt_secret` and requires `scope` in its claims.
+/// An empty configured secret never authorizes.
+fn is_jwt_authorized(req: &Request, scope: &str) -> Result<bool> {
+    let secret = variables::get("jwt_secret").unwrap_or_default();
+    let Some(token) = req.header("authorization").and_then(|v| v.as_str()).and_then(|v| v.trim().strip_prefix("Bearer ")) else {
+        log::info!("Rejected {} {}: missing bearer token", req.method(), req.path());
+        return Ok(false);
+    };
+    if secret.is_empty() {
+        log::warn!("Rejected {} {}: auth_mode is jwt but jwt_secret is not configured", req.method(), req.path());
+        return Ok(false);
+    }
+    match verify_jwt(token.trim(), secret.as_bytes(), Utc::now().timestamp()) {
+        Ok(claims) if claims.has_scope(scope) => Ok(true),
+        Ok(_) => {
+            log::info!("Rejected {} {}: token lacks scope {}", req.method(), req.path(), scope);
+            Ok(false)
+        }
+        Err(reason) => {
+            log::info!("Rejected {} {}: invalid bearer token ({})", req.method(), req.path(), reason);
+            Ok(false)
+        }
+    }
+}
+
+/// Checks the `X-Api-Key` header against the API key stored in the given variable, or with
+/// `auth_mode = jwt` the bearer token's scope for that role.
+/// An empty configured key never authorizes (used to disable the optional admin key).
+fn is_authorized(req: &Request, api_key_variable: &str) -> Result<bool> {
+    if get_auth_mode() == AuthMode::Jwt {
+        // An `admin` token alone must not enable the admin endpoints the operator left disabled
+        if api_key_variable == "admin_api_key" && variables::get("admin_api_key").unwrap_or_default().is_empty() {
+            log::info!("Rejected {} {}: admin endpoints are disabled while admin_api_key is empty", req.method(), req.path());
+            return Ok(false);
+        }
+        return is_jwt_authorized(req, auth_scope(api_key_variable));
+    }
+    let api_key = variables::get(api_key_variable)?;
+    let Some(api_key_header) = req.header("x-api-key").and_then(|v| v.as_str()) else {
+        log::info!("Rejected {} {}: missing X-Api-Key header", req.method(), req.path());
+        return Ok(false);
+    };
+
+    let authorized = !api_key.is_empty() && api_key_header == api_key;
+    if !authorized {
+        log::info!("Rejected {} {}: X-Api-Key does not match {}", req.method(), req.path(), api_key_variable);
+    }
+    Ok(authorized)
+}
+
+/// Registered nodes must present their secret in `X-Node-Secret`; unregistered ids only pass
+/// while registration is optional. With tenancy, a node owned by another tenant is refused.
+/// Every endpoint that acts as a node checks this, so the shared probe key alone cannot speak
+/// for another node. `Err` carries the response to send.
+fn verify_node_identity(req: &Request, conn: &Connection, node_id: u32, tenant: Option<&str>) -> Result<Result<(), Response>> {
+    if let Some(tenant) = tenant {
+        if get_node_tenant(conn, node_id as i64)?.is_some_and(|owner| owner != tenant) {
+            log::warn!("Rejecting {} for node_id {}: node belongs to another tenant than '{}'", req.path(), node_id, tenant);
+            return Ok(Err(error_response(403, "node_in_other_tenant", "Node belongs to another tenant")));
+        }
+    }
+    let node_secret = req.header("x-node-secret").and_then(|v| v.as_str());
+    match get_node_secret(conn, node_id as i64)? {
+        Some(secret) if !node_secret.is_some_and(|node_secret| node_secret_matches(&secret, node_secret)) => {
+            log::warn!("Rejecting {} for node_id {}: missing or wrong node secret", req.path(), node_id);
+            Ok(Err(error_response(403, "invalid_node_secret", "Missing or wrong X-Node-Secret")))
+        }
+        None if get_bool_variable("require_registration", false) => {
+            log::warn!("Rejecting {} for unregistered node_id {}", req.path(), node_id);
+            Ok(Err(error_response(403, "node_not_registered", "Node is not registered")))
+        }
+        _ => Ok(Ok(())),
+    }
+}
+
+/// Role keys per tenant from the `tenants` variable, e.g.
+/// `{"team-a": {"probe_api_key": "...", "log_collector_api_key": "..."}}`.
+fn tenant_api_key(tenant: &str, api_key_variable: &str) -> Option<String> {
+    let raw = variables::get("tenants").unwrap_or_default();
+    if raw.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str::<HashMap<String, HashMap<String, String>>>(&raw) {
+        Ok(mut tenants) => tenants.get_mut(tenant)?.remove(api_key_variable),
+        Err(e) => {
+            log::warn!("Ignoring invalid tenants variable: {}", e);
+            None
+        }
+    }
+}
+
+/// Authorizes a tenant-scoped endpoint. Without `enable_tenancy` this is the usual key check and
+/// yields no tenant. With it, `X-Tenant` must name a configured tenant and `X-Api-Key` must be
+/// that tenant's key for the role (in place of the global key); the tenant is returned for
+/// scoping the request. `Err` carries the response to send.
+fn authorize_tenant(req: &Request, api_key_variable: &str) -> Result<Result<Option<String>, Response>> {
+    if !get_bool_variable("enable_tenancy", false) {
+        if !is_authorized(req, api_key_variable)? {
+            return Ok(Err(status_error(401, "Unauthorized")));
+        }
+        return Ok(Ok(None));
+    }
+    let Some(tenant) = req.header("x-tenant").and_then(|v| v.as_str()).map(|v| v.trim()).filter(|v| !v.is_empty()) else {
+        return Ok(Err(error_response(400, "missing_tenant", "Missing X-Tenant header")));
+    };
+    let api_key_header = req.header("x-api-key").and_then(|v| v.as_str()).unwrap_or_default();
+    match tenant_api_key(tenant, api_key_variable) {
+        Some(api_key) if !api_key.is_empty() && api_key_header == api_key => Ok(Ok(Some(tenant.to_string()))),
+        _ => {
+            log::info!("Rejected {} {}: X-Api-Key is not the {} of tenant '{}'", req.method(), req.path(), api_key_variable, tenant);
+            Ok(Err(status_error(401, "Unauthorized")))
+        }
+    }
+}
+
+/// A checked upload: the probe's headers and its parsed body.
+struct ProbeUpload {
+    node_id: u32,
+    node_version: Option<String>,
+    node_labels: Option<HashMap<String, String>>,
+    /// The probe's command cursor in `cursor` mode; `None` consumes the queue
+    command_cursor: Option<i64>,
+    logs: Vec<LogEntry>,
+    body_len: usize,
+    /// The tenant whose key authorized the upload; `None` when tenancy is disabled
+    tenant: Option<String>,
+}
+
+/// Checks the probe headers and body of an upload, for `/update` and the gateway endpoints that
+/// carry the same headers. `Err` holds the response for a rejected request.
+fn parse_probe_upload(req: &Request, tenant: Option<String>) -> Result<Result<ProbeUpload, Response>> {
+    // Get node ID
+    let node_id_str = match req.header("x-node-id").and_then(|v| v.as_str()) {
+        Some(node_id_str) => node_id_str,
+        None => return Ok(Err(error_response(400, "missing_node_id", "Missing X-Node-ID header"))),
+    };
+    let node_id: u32 = match node_id_str.trim().parse() {
+        Ok(node_id) => node_id,
+        Err(_) => return Ok(Err(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer"))),
+    };
+    if node_id == SELFTEST_NODE_ID {
+        log::warn!("Rejecting upload from node_id {}: reserved for /selftest", node_id);
+        return Ok(Err(error_response(400, "reserved_node_id", "Node id is reserved for /selftest")));
+    }
+
+    // Optional allow-list: unexpected ids point to misconfiguration or intrusion
+    if let Some(allowed) = get_allowed_node_ids() {
+        if !allowed.iter().any(|range| range.contains(&node_id)) {
+            log::warn!("Rejecting upload from node_id {}: not in allowed_node_ids", node_id);
+            return Ok(Err(error_response(403, "node_not_allowed", "Node id is not in allowed_node_ids")));
+        }
+    }
+
+    // Optional firmware version and labels reported by the probe
+    let node_version = req.header("x-node-version").and_then(|v| v.as_str()).map(|v| v.trim()).filter(|v| !v.is_empty());
+    let node_labels = match req.header("x-node-labels").and_then(|v| v.as_str()) {
+        Some(value) => match parse_node_labels(value) {
+            Some(labels) => Some(labels),
+            None => return Ok(Err(error_response(400, "invalid_node_labels", "Invalid X-Node-Labels"))),
+        },
+        None => None,
+    };
+
+    // Optional non-destructive delivery: the probe keeps its own cursor over command ids
+    let command_cursor = match req.header("x-command-mode").and_then(|v| v.as_str()).map(|v| v.trim()) {
+        None | Some("consume") => None,
+        Some("cursor") => match req.header("x-last-command-id").and_then(|v| v.as_str()) {
+            None => Some(0),
+            Some(value) => match value.trim().parse::<i64>() {
+                Ok(after_id) if after_id >= 0 => Some(after_id),
+                _ => return Ok(Err(error_response(400, "invalid_last_command_id", "Invalid X-Last-Command-ID: must be a non-negative integer"))),
+            },
+        },
+        Some(_) => return Ok(Err(error_response(400, "invalid_command_mode", "Invalid X-Command-Mode: expected consume or cursor"))),
+    };
+
+    // Optional transport integrity check on the bytes as sent, before the body is trusted enough to parse
+    let body = req.body();
+    if let Some(expected) = req.header("x-content-sha256").and_then(|v| v.as_str()) {
+        if !verify_body_checksum(body, expected) {
+            log::warn!("Rejecting upload for node_id {}: body does not match X-Content-SHA256", node_id);
+            return Ok(Err(error_response(400, "checksum_mismatch", "Body does not match X-Content-SHA256")));
+        }
+    }
+    let logs = match decode_upload(req) {
+        Ok(logs) => logs,
+        Err(response) => return Ok(Err(response)),
+    };
+
+    // Optional firmware sanity check: a batch must be in non-decreasing timestamp order
+    if get_bool_variable("require_monotonic_uploads", false) {
+        if let Some(index) = first_out_of_order_index(&logs) {
+            log::warn!("Rejecting upload for node_id {}: entry {} is timestamped before an earlier entry", node_id, index);
+            let response = ErrorResponse {
+                error: "non_monotonic_timestamps".to_string(),
+                message: format!("Entry {} is timestamped before an earlier entry", index),
+                index: Some(index),
+            };
+            return Ok(Err(error_reply(400, response)));
+        }
+    }
+
+    if let Some(index) = first_incomplete_metric_index(&logs) {
+        log::warn!("Rejecting upload for node_id {}: entry {} has a metric without a value or a value without a metric", node_id, index);
+        let response = ErrorResponse {
+            error: "incomplete_metric".to_string(),
+            message: format!("Entry {} has a metric without a value or a value without a metric", index),
+            index: Some(index),
+        };
+        return Ok(Err(error_reply(400, response)));
+    }
+
+    Ok(Ok(ProbeUpload {
+        node_id,
+        node_version: node_version.map(|v| v.to_string()),
+        node_labels,
+        command_cursor,
+        logs,
+        body_len: body.len(),
+        tenant,
+    }))
+}
+
+/// Backpressure: when enabled, refuses uploads while the backlog is above the threshold.
+/// Otherwise returns the backlog (counted only with a threshold set) for the Retry-After check.
+fn check_backpressure(conn: &Connection, node_id: u32) -> Result<Result<Option<i64>, Response>> {
+    let backpressure_threshold = get_i64_variable("backpressure_threshold", 0);
+    let backlog = if backpressure_threshold > 0 { Some(count_log_messages(conn)?) } else { None };
+    if backlog.is_some_and(|count| count > backpressure_threshold) && get_bool_variable("backpressure_reject", false) {
+        let retry_after = current_update_interval(open_store().as_ref()) * 2;
+        log::warn!("Rejecting upload from node_id {}: log backlog above {}", node_id, backpressure_threshold);
+        let mut response = status_error(429, "Too Many Requests: log backlog above backpressure threshold");
+        response.set_header("retry-after", retry_after.to_string());
+        return Ok(Err(response));
+    }
+    Ok(Ok(backlog))
+}
+
+/// The database work of an upload on the caller's connection: node bookkeeping, the log insert
+/// and the command fetch. `/gateway/sync` runs it inside its transaction, so nothing here may
+/// open one of its own or reach outside the database.
+fn apply_probe_upload(conn: &Connection, store: Option<&Store>, upload: &ProbeUpload, ingest_settings: &IngestSettings) -> Result<(UpdateResponse, IngestReport)> {
+    let node_id = upload.node_id;
+    log::debug!("Received upload request. Node_id: {}, uploaded logline count: {}", node_id, upload.logs.len());
+
+    // Track the node's last-seen time and reported version and labels
+    upsert_node(conn, node_id as i64, upload.node_version.as_deref(), upload.node_labels.as_ref(), upload.tenant.as_deref())?;
+    let node_metadata = get_node_metadata(conn, node_id as i64)?;
+
+    // Insert log messages; with persistent ids the sequence is synced around the insert
+    let id_store = store.filter(|_| get_log_id_strategy() == LogIdStrategy::Sequence);
+    if let Some(id_store) = id_store {
+        sync_log_id_sequence(conn, id_store)?;
+    }
+    let ingest_report = insert_log_messages(conn, node_id, &upload.logs, ingest_settings, upload.tenant.as_deref())?;
+    if let Some(id_store) = id_store.filter(|_| ingest_report.inserted > 0) {
+        sync_log_id_sequence(conn, id_store)?;
+    }
+    // Empty polls are not uploads; counting them would hide the last real batch size
+    if !upload.logs.is_empty() {
+        record_upload_stats(conn, node_id as i64, upload.logs.len(), upload.body_len)?;
+    }
+    log::debug!(
+        "Inserted {} log messages for node_id {} ({} repeats coalesced, {} sampled out)",
+        ingest_report.inserted,
+        node_id,
+        ingest_report.coalesced,
+        ingest_report.sampled_out
+    );
+    if ingest_report.future_clamped > 0 || ingest_report.future_rejected > 0 {
+        log::warn!(
+            "Node_id {} uploaded future timestamps (more than {}s ahead): clamped={}, rejected={}",
+            node_id,
+            ingest_settings.max_future_skew_seconds,
+            ingest_report.future_clamped,
+            ingest_report.future_rejected
+        );
+    }
+    if ingest_report.too_old_rejected > 0 {
+        log::info!(
+            "Node_id {} uploaded {} entries older than {} minutes; rejected",
+            node_id,
+            ingest_report.too_old_rejected,
+            ingest_settings.max_upload_age_minutes
+        );
+    }
+
+    // Get commands for this node: consumed by default, or read past the probe's cursor
+    let (commands, last_command_id) = match upload.command_cursor {
+        Some(after_id) => {
+            let (commands, cursor) = get_commands_after(conn, node_id, &node_metadata, after_id)?;
+            (commands, Some(cursor))
+        }
+        None => (get_and_delete_commands(conn, node_id, &node_metadata)?, None),
+    };
+
+    // Get current update interval based on active/inactive period
+    let update_interval = current_update_interval(store);
+
+    let response = UpdateResponse {
+        commands,
+        update_interval,
+        sampled_out: (!ingest_settings.sample_rates.is_empty()).then_some(ingest_report.sampled_out),
+        too_old_rejected: (ingest_settings.max_upload_age_minutes > 0).then_some(ingest_report.too_old_rejected),
+        redacted: (!ingest_settings.redaction_patterns.is_empty()).then_some(ingest_report.redacted),
+        last_command_id,
+    };
+    Ok((response, ingest_report))
+}
+
+/// What follows a stored upload outside any transaction: watched nodes' webhooks, then the
+/// scheduled cleanup (both skipped while the KV store is unavailable).
+fn finish_probe_upload(req: &Request, conn: &Connection, store: Option<&Store>, upload: &ProbeUpload, ingest_report: &IngestReport, ingest_settings: &IngestSettings) -> Result<()> {
+    if let Some(store) = store {
+        if ingest_report.inserted > 0 {
+            notify_webhook(store, upload.node_id, &upload.logs, ingest_settings.default_log_level_for(upload.node_id), &ingest_settings.redaction_patterns);
+        }
+        if !skip_cleanup_requested(req) {
+            run_scheduled_cleanup(conn, store)?;
+        }
+    }
+    Ok(())
+}
+
+/// JSON response for a stored upload, with its row count, Retry-After while the backlog stays
+/// above the backpressure threshold, and the retention debug header.
+fn upload_response<T: Serialize>(body: &T, node_id: u32, ingest_report: &IngestReport, backlog: Option<i64>, update_interval: i64) -> Result<Response> {
+    let mut response = json_response(200, body)?;
+    response.set_header("x-rows-inserted", ingest_report.inserted.to_string());
+
+    // Ask the probe to slow down while cleanup can't keep up with the backlog (pre-insert count plus this batch)
+    let backpressure_threshold = get_i64_variable("backpressure_threshold", 0);
+    if backlog.is_some_and(|count| count + ingest_report.inserted as i64 > backpressure_threshold) {
+        log::info!("Log backlog above {}, signalling backpressure to node_id {}", backpressure_threshold, node_id);
+        response.set_header("retry-after", (update_interval * 2).to_string());
+    }
+    if let Some(retention) = retention_debug_header() {
+        response.set_header("x-retention", retention);
+    }
+    Ok(response)
+}
+
+fn handle_update(req: Request) -> Result<Response> {
+    // Validate probe API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "probe_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+    let upload = match parse_probe_upload(&req, tenant)? {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+
+    // Open database and initialize
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    if let Err(response) = verify_node_identity(&req, &conn, upload.node_id, upload.tenant.as_deref())? {
+        return Ok(response);
+    }
+    let backlog = match check_backpressure(&conn, upload.node_id)? {
+        Ok(backlog) => backlog,
+        Err(response) => return Ok(response),
+    };
+
+    let store = open_store();
+    let ingest_settings = IngestSettings::from_variables();
+    let (response, ingest_report) = apply_probe_upload(&conn, store.as_ref(), &upload, &ingest_settings)?;
+    finish_probe_upload(&req, &conn, store.as_ref(), &upload, &ingest_report, &ingest_settings)?;
+
+    // Return commands and update_interval as JSON
+    upload_response(&response, upload.node_id, &ingest_report, backlog, response.update_interval)
+}
+
+/// `X-Skip-Cleanup: true` lets latency-sensitive callers leave the opportunistic cleanup to a later request.
+fn skip_cleanup_requested(req: &Request) -> bool {
+    req.header("x-skip-cleanup").and_then(|v| v.as_str()).is_some_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Whether a comma-separated header such as `Accept` lists `token` (parameters ignored, except `q=0`).
+fn header_lists(req: &Request, header: &str, token: &str) -> bool {
+    req.header(header).and_then(|v| v.as_str()).is_some_and(|value| {
+        value.split(',').any(|item| {
+            let mut parts = item.split(';').map(|part| part.trim());
+            parts.next().is_some_and(|name| name.eq_ignore_ascii_case(token)) && !parts.any(|param| param.replace(' ', "") == "q=0")
+        })
+    })
+}
+
+/// CSV is selected by `format=csv` or an `Accept` header naming `text/csv`; JSON is the default.
+fn wants_csv(req: &Request, uri: &str) -> bool {
+    if let Some(format) = get_query_param(uri, "format") {
+        return format == "csv";
+    }
+    header_lists(req, "accept", "text/csv")
+}
+
+/// One JSON object per line, each line written straight into `out`.
+fn write_ndjson<W: Write>(out: &mut W, logs: &[DownloadLogEntry]) -> Result<()> {
+    for log in logs {
+        serde_json::to_writer(&mut *out, log)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// NDJSON body for a page of logs, gzip-compressed while it is serialized when `gzip` is set.
+fn ndjson_body(logs: &[DownloadLogEntry], gzip: bool) -> Result<Vec<u8>> {
+    if !gzip {
+        let mut body = Vec::new();
+        write_ndjson(&mut body, logs)?;
+        return Ok(body);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    write_ndjson(&mut encoder, logs)?;
+    // finish() flushes the last deflate block and writes the gzip trailer
+    Ok(encoder.finish()?)
+}
+
+/// Where a `/download` page starts: the requested id, else the collector's stored cursor.
+/// `Err` holds the 503 for a stored cursor that cannot be read without the KV store.
+fn resolve_download_start(store: Option<&Store>, tenant: Option<&str>, requested_last_id: Option<i64>, collector_id: Option<&str>) -> Result<Result<i64, Response>> {
+    match (requested_last_id, collector_id, store) {
+        (Some(last_id), _, _) => Ok(Ok(last_id)),
+        (None, Some(collector_id), Some(store)) => Ok(Ok(load_collector_cursor(store, tenant, collector_id)?.unwrap_or(0))),
+        (None, _, _) => Ok(Err(error_response(503, "kv_unavailable", "Key-value store unavailable"))),
+    }
+}
+
+/// Reads one `/download` page after `last_id`, using the current interval for filtering, and
+/// advances the collector's stored cursor to the highest id served (skipped rows included).
+fn read_download_page(conn: &Connection, store: Option<&Store>, tenant: Option<&str>, collector_id: Option<&str>, last_id: i64, current_upload_interval: i64, options: &DownloadOptions) -> Result<(LogQuery, LogPage)> {
+    let query = download_query(last_id, current_upload_interval, options, tenant);
+    let page = query_logs_page(conn, &query)?;
+    if let (Some(collector_id), Some(store)) = (collector_id, store) {
+        let cursor = page.max_id.unwrap_or(last_id).max(last_id);
+        save_collector_cursor(store, tenant, collector_id, cursor)?;
+    }
+    Ok((query, page))
+}
+
+fn handle_download(req: Request) -> Result<Response> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    // Optional server-side cursor, keyed by collector id
+    let collector_id = match req.header("x-collector-id").and_then(|v| v.as_str()).map(|v| v.trim()) {
+        Some(id) if is_valid_key_name(id) => Some(id.to_string()),
+        Some(_) => return Ok(error_response(400, "invalid_collector_id", "Invalid X-Collector-ID")),
+        None => None,
+    };
+
+    // Parse query parameter; collectors with an id may omit it to resume from their stored cursor
+    let uri = req.uri().to_string();
+    let requested_last_id = match get_query_param(&uri, "last_log_message_id") {
+        Some(value) => match value.parse::<i64>() {
+            Ok(last_id) => Some(last_id),
+            Err(_) => return Ok(status_error(400, "Invalid last_log_message_id")),
+        },
+        None if collector_id.is_some() => None,
+        None => return Ok(status_error(400, "Missing last_log_message_id parameter")),
+    };
+
+    if requested_last_id.is_some_and(|last_id| last_id < 0) {
+        return Ok(status_error(400, "Invalid last_log_message_id: must be non-negative"));
+    }
+
+    let mut options = match DownloadOptions::from_uri(&uri) {
+        Ok(options) => options,
+        Err(message) => return Ok(status_error(400, message)),
+    };
+
+    // The stored cursor is the highest item_id served, which is only gap-free when the page
+    // follows id order; under timestamp order it would skip rows stored earlier but sorted later
+    if collector_id.is_some() {
+        if options.order == LogOrder::TimestampNode {
+            return Ok(error_response(400, "cursor_requires_id_order", "tiebreak=node_id cannot be combined with X-Collector-ID"));
+        }
+        options.order = LogOrder::Id;
+    }
+
+    // Open database
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Get current upload interval based on active/inactive period (same logic as /update)
+    let store = open_store();
+    let current_upload_interval = current_update_interval(store.as_ref());
+
+    let last_id = match resolve_download_start(store.as_ref(), tenant.as_deref(), requested_last_id, collector_id.as_deref())? {
+        Ok(last_id) => last_id,
+        Err(response) => return Ok(response),
+    };
+    let (query, page) = read_download_page(&conn, store.as_ref(), tenant.as_deref(), collector_id.as_deref(), last_id, current_upload_interval, &options)?;
+
+    // Check if cleanup is needed (skipped while the KV store is unavailable or the caller opts out)
+    if let Some(store) = store.as_ref().filter(|_| !skip_cleanup_requested(&req)) {
+        run_scheduled_cleanup(&conn, store)?;
+    }
+
+    // Pollers that opt in get a bodyless 204 when there is nothing to read; rows that were
+    // skipped still move the cursor, so those pages keep their body
+    let empty_page = page.logs.is_empty() && page.skipped == 0 && !page.has_more;
+    let mut response = if empty_page && header_lists(&req, "prefer", "return-204-empty") {
+        Response::builder()
+            .status(204)
+            .header("preference-applied", "return-204-empty")
+            .header("x-update-interval", current_upload_interval.to_string())
+            .header("x-has-more", "false")
+            .body(Vec::new())
+            .build()
+    } else if wants_csv(&req, &uri) {
+        // CSV carries the rows only; the interval travels in a header
+        Response::builder()
+            .status(200)
+            .header("content-type", "text/csv; charset=utf-8")
+            .header("x-update-interval", current_upload_interval.to_string())
+            .header("x-has-more", page.has_more.to_string())
+            .header("x-skipped", page.skipped.to_string())
+            .body(to_csv(&page.logs))
+            .build()
+    } else {
+        download_json_response(&query, &options, page, current_upload_interval)?
+    };
+
+    // Rows at or after the cutoff are still settling, so the collector has seen everything before it
+    if let Some(cutoff) = &query.cutoff {
+        response.set_header("x-up-to", cutoff.as_str());
+    }
+    if let Some(retention) = retention_debug_header() {
+        response.set_header("x-retention", retention);
+    }
+    Ok(response)
+}
+
+/// The JSON body of `/download`: logs (flat or grouped, optionally projected) and update_interval.
+fn download_json_response(query: &LogQuery, options: &DownloadOptions, page: LogPage, current_upload_interval: i64) -> Result<Response> {
+    let applied_filters = options.debug.then(|| AppliedFilters {
+        last_id: query.after_id,
+        cutoff: query.cutoff.clone(),
+        from: query.from.clone(),
+        node_ids: query.node_ids.clone(),
+        order: query.order.as_str(),
+        limit: query.limit,
+        fields: options.fields.clone(),
+        prefix: query.prefix.clone(),
+        q: query.search.clone(),
+        source: query.source.clone(),
+    });
+    let node_ids: Vec<i64> = page.logs.iter().map(|log| log.node_id).collect();
+    let paging = PagingInfo {
+        has_more: page.has_more,
+        last_item_id: page.max_id,
+        skipped: page.skipped,
+    };
+    // Projection and highlighting both turn the entries into JSON objects
+    let highlight = options.search.as_deref().filter(|_| options.highlight);
+    if options.fields.is_none() && highlight.is_none() {
+        let entries = DownloadEntries::new(&node_ids, page.logs, options.group_by_node);
+        return versioned_download_response(options.api_version, entries, current_upload_interval, paging, applied_filters);
+    }
+    let mut items = match &options.fields {
+        Some(fields) => project_log_entries(&page.logs, fields)?,
+        None => page.logs.iter().map(serde_json::to_value).collect::<Result<Vec<_>, _>>()?,
+    };
+    if let Some(term) = highlight {
+        for (item, log) in items.iter_mut().zip(&page.logs) {
+            if let serde_json::Value::Object(entry) = item {
+                entry.insert("matches".to_string(), serde_json::to_value(match_offsets(&log.message, term))?);
+            }
+        }
+    }
+    let entries = DownloadEntries::new(&node_ids, items, options.group_by_node);
+    versioned_download_response(options.api_version, entries, current_upload_interval, paging, applied_filters)
+}
+
+/// `[start, end)` character (Unicode code point) ranges of the non-overlapping occurrences of
+/// `term` in `message`, compared ASCII case-insensitively like SQLite's `LIKE`.
+fn match_offsets(message: &str, term: &str) -> Vec<[usize; 2]> {
+    // ASCII lower-casing keeps byte lengths, so byte offsets carry over to the original message
+    let haystack = message.to_ascii_lowercase();
+    let needle = term.to_ascii_lowercase();
+    let term_chars = term.chars().count();
+
+    let mut offsets = Vec::new();
+    let mut chars_before = 0;
+    let mut scanned_bytes = 0;
+    for (byte, _) in haystack.match_indices(&needle) {
+        chars_before += haystack[scanned_bytes..byte].chars().count();
+        offsets.push([chars_before, chars_before + term_chars]);
+        chars_before += term_chars;
+        scanned_bytes = byte + needle.len();
+    }
+    offsets
+}
+
+fn versioned_download_response<T: Serialize>(api_version: ApiVersion, entries: DownloadEntries<T>, update_interval: i64, paging: PagingInfo, applied_filters: Option<AppliedFilters>) -> Result<Response> {
+    match api_version {
+        ApiVersion::V1 => json_response(
+            200,
+            &DownloadResponseV1 {
+                entries,
+                update_interval,
+                applied_filters,
+            },
+        ),
+        ApiVersion::V2 => json_response(
+            200,
+            &DownloadResponseV2 {
+                entries,
+                update_interval,
+                paging,
+                applied_filters,
+            },
+        ),
+    }
+}
+
+fn handle_cursor_reset(req: Request) -> Result<Response> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    let collector_id = match req.header("x-collector-id").and_then(|v| v.as_str()).map(|v| v.trim()) {
+        Some(id) if is_valid_key_name(id) => id.to_string(),
+        Some(_) => return Ok(error_response(400, "invalid_collector_id", "Invalid X-Collector-ID")),
+        None => return Ok(error_response(400, "missing_collector_id", "Missing X-Collector-ID header")),
+    };
+
+    // An empty body resets to the beginning
+    let body = req.body();
+    let reset_req: CursorResetRequest = if body.is_empty() {
+        CursorResetRequest { last_log_message_id: 0 }
+    } else {
+        serde_json::from_slice(body)?
+    };
+    if reset_req.last_log_message_id < 0 {
+        return Ok(status_error(400, "Invalid last_log_message_id: must be non-negative"));
+    }
+
+    let store = Store::open_default()?;
+    save_collector_cursor(&store, tenant.as_deref(), &collector_id, reset_req.last_log_message_id)?;
+    log::info!("Reset cursor of collector {} to {}", collector_id, reset_req.last_log_message_id);
+
+    let response = CursorResponse {
+        collector_id,
+        last_log_message_id: reset_req.last_log_message_id,
+    };
+    json_response(200, &response)
+}
+
+fn handle_collector_ack(req: Request) -> Result<Response> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    let collector_id = match req.header("x-collector-id").and_then(|v| v.as_str()).map(|v| v.trim()) {
+        Some(id) if is_valid_key_name(id) => id.to_string(),
+        Some(_) => return Ok(error_response(400, "invalid_collector_id", "Invalid X-Collector-ID")),
+        None => return Ok(error_response(400, "missing_collector_id", "Missing X-Collector-ID header")),
+    };
+    let last_id = match get_query_param(req.uri(), "last_id").map(|v| v.trim().parse::<i64>()) {
+        Some(Ok(last_id)) if last_id >= 0 => last_id,
+        Some(_) => return Ok(status_error(400, "Invalid last_id: must be non-negative")),
+        None => return Ok(status_error(400, "Missing last_id parameter")),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let acked_id = record_collector_ack(&conn, tenant.as_deref(), &collector_id, last_id)?;
+    log::debug!("Collector {} acknowledged logs up to id {} (effective {})", collector_id, last_id, acked_id);
+
+    let response = CollectorAckResponse { collector_id, acked_id };
+    json_response(200, &response)
+}
+
+/// Gateways act as probe and collector at once: the probe key goes in X-Api-Key and the
+/// collector key in X-Collector-Api-Key (with `auth_mode = jwt`, one token with both scopes).
+/// With tenancy both keys must belong to the tenant in X-Tenant, which then scopes both halves.
+/// `Err` carries the response to send.
+fn authorize_gateway(req: &Request) -> Result<Result<Option<String>, Response>> {
+    let tenant = match authorize_tenant(req, "probe_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(Err(response)),
+    };
+    if tenant.is_none() && get_auth_mode() == AuthMode::Jwt {
+        if !is_authorized(req, "log_collector_api_key")? {
+            return Ok(Err(status_error(401, "Unauthorized")));
+        }
+        return Ok(Ok(None));
+    }
+    let collector_key = match &tenant {
+        Some(tenant) => tenant_api_key(tenant, "log_collector_api_key").unwrap_or_default(),
+        None => variables::get("log_collector_api_key")?,
+    };
+    let collector_key_header = req.header("x-collector-api-key").and_then(|v| v.as_str()).unwrap_or_default();
+    if collector_key.is_empty() || collector_key_header != collector_key {
+        match &tenant {
+            Some(tenant) => log::info!("Rejected {} {}: X-Collector-Api-Key does not match tenant '{}'", req.method(), req.path(), tenant),
+            None => log::info!("Rejected {} {}: X-Collector-Api-Key does not match log_collector_api_key", req.method(), req.path()),
+        }
+        return Ok(Err(status_error(401, "Unauthorized")));
+    }
+    Ok(Ok(tenant))
+}
+
+/// Upload and download in one round trip for gateways, with the full semantics of `/update`
+/// followed by a `/download` page (v2 defaults, so `has_more` is included).
+fn handle_sync(req: Request) -> Result<Response> {
+    // Both roles must authorize: probe key in X-Api-Key, collector key in X-Collector-Api-Key
+    let tenant = match authorize_gateway(&req)? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_UPLOAD_CONTENT_TYPES) {
+        return Ok(response);
+    }
+    let body = match decoded_body(&req) {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let sync_req: SyncRequest = match serde_json::from_slice(&body) {
+        Ok(sync_req) => sync_req,
+        Err(e) => return Ok(status_error(400, format!("Invalid JSON body: {}", e))),
+    };
+
+    // Check the download parameters before the upload runs: a download rejected after a completed
+    // upload would drop the commands it delivered and make the gateway's retry insert the logs twice
+    let collector_id = req.header("x-collector-id").and_then(|v| v.as_str()).map(|v| v.trim());
+    if collector_id.is_some_and(|id| !is_valid_key_name(id)) {
+        return Ok(error_response(400, "invalid_collector_id", "Invalid X-Collector-ID"));
+    }
+    match sync_req.last_log_message_id {
+        Some(last_id) if last_id < 0 => return Ok(status_error(400, "Invalid last_log_message_id: must be non-negative")),
+        None if collector_id.is_none() => return Ok(status_error(400, "Missing last_log_message_id")),
+        _ => {}
+    }
+    let upload = match parse_probe_upload(&req, tenant)? {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    // The download half takes the `/download` defaults; a collector's stored cursor needs id order
+    let mut options = DownloadOptions::from_uri("/download").map_err(|message| anyhow!(message))?;
+    if collector_id.is_some() {
+        options.order = LogOrder::Id;
+    }
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+    if let Err(response) = verify_node_identity(&req, &conn, upload.node_id, upload.tenant.as_deref())? {
+        return Ok(response);
+    }
+    let backlog = match check_backpressure(&conn, upload.node_id)? {
+        Ok(backlog) => backlog,
+        Err(response) => return Ok(response),
+    };
+    let store = open_store();
+    let last_id = match resolve_download_start(store.as_ref(), upload.tenant.as_deref(), sync_req.last_log_message_id, collector_id)? {
+        Ok(last_id) => last_id,
+        Err(response) => return Ok(response),
+    };
+
+    // Upload half, then the download page
+    let ingest_settings = IngestSettings::from_variables();
+    let (update, ingest_report) = apply_probe_upload(&conn, store.as_ref(), &upload, &ingest_settings)?;
+    let (_, page) = read_download_page(&conn, store.as_ref(), upload.tenant.as_deref(), collector_id, last_id, update.update_interval, &options)?;
+    finish_probe_upload(&req, &conn, store.as_ref(), &upload, &ingest_report, &ingest_settings)?;
+
+    let update_interval = update.update_interval;
+    let response = SyncResponse {
+        update,
+        logs: page.logs,
+        has_more: page.has_more,
+    };
+    upload_response(&response, upload.node_id, &ingest_report, backlog, update_interval)
+}
+
+/// The collector ack, log insert and command fetch of a `/gateway/sync` call, on a connection
+/// that is inside the caller's transaction. Returns the effective ack with the upload result.
+fn apply_gateway_sync(conn: &Connection, store: Option<&Store>, upload: &ProbeUpload, ack: Option<(&str, i64)>, ingest_settings: &IngestSettings) -> Result<(UpdateResponse, IngestReport, Option<i64>)> {
+    let acked_id = match ack {
+        Some((collector_id, last_id)) => {
+            let acked_id = record_collector_ack(conn, upload.tenant.as_deref(), collector_id, last_id)?;
+            log::debug!("Gateway collector {} acknowledged logs up to id {} (effective {})", collector_id, last_id, acked_id);
+            Some(acked_id)
+        }
+        None => None,
+    };
+    let (update, ingest_report) = apply_probe_upload(conn, store, upload, ingest_settings)?;
+    Ok((update, ingest_report, acked_id))
+}
+
+/// Upload, command fetch and download acknowledgement in one gateway call, committed as one
+/// transaction: a failed call leaves no logs inserted, no commands consumed and the ack where it
+/// was, so the gateway can simply retry it.
+fn handle_gateway_sync(req: Request) -> Result<Response> {
+    // Both roles must authorize: probe key in X-Api-Key, collector key in X-Collector-Api-Key
+    let tenant = match authorize_gateway(&req)? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_UPLOAD_CONTENT_TYPES) {
+        return Ok(response);
+    }
+    let body = match decoded_body(&req) {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let sync_req: GatewaySyncRequest = match serde_json::from_slice(&body) {
+        Ok(sync_req) => sync_req,
+        Err(e) => return Ok(status_error(400, format!("Invalid body: {}", e))),
+    };
+    let ack = match sync_req.ack_last_id {
+        Some(last_id) if last_id < 0 => return Ok(status_error(400, "Invalid ack_last_id: must be non-negative")),
+        Some(last_id) => match req.header("x-collector-id").and_then(|v| v.as_str()).map(|v| v.trim()) {
+            Some(id) if is_valid_key_name(id) => Some((id, last_id)),
+            Some(_) => return Ok(error_response(400, "invalid_collector_id", "Invalid X-Collector-ID")),
+            None => return Ok(error_response(400, "missing_collector_id", "Missing X-Collector-ID header")),
+        },
+        None => None,
+    };
+    let upload = match parse_probe_upload(&req, tenant)? {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+    if let Err(response) = verify_node_identity(&req, &conn, upload.node_id, upload.tenant.as_deref())? {
+        return Ok(response);
+    }
+    let backlog = match check_backpressure(&conn, upload.node_id)? {
+        Ok(backlog) => backlog,
+        Err(response) => return Ok(response),
+    };
+
+    let store = open_store();
+    let ingest_settings = IngestSettings::from_variables();
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+    let (update, ingest_report, acked_id) = match apply_gateway_sync(&conn, store.as_ref(), &upload, ack, &ingest_settings) {
+        Ok(applied) => {
+            conn.execute("COMMIT", &[])?;
+            applied
+        }
+        Err(e) => {
+            _ = conn.execute("ROLLBACK", &[]);
+            return Err(e);
+        }
+    };
+    finish_probe_upload(&req, &conn, store.as_ref(), &upload, &ingest_report, &ingest_settings)?;
+
+    let update_interval = update.update_interval;
+    let response = GatewaySyncResponse { update, acked_id };
+    upload_response(&response, upload.node_id, &ingest_report, backlog, update_interval)
+}
+
+fn handle_download_item(req: Request, item_id: i64) -> Result<Response> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Single row lookup: no settlement cutoff, the caller already knows the id
+    match get_log_by_id(&conn, item_id, tenant.as_deref())? {
+        Some(entry) => json_response(200, &entry),
+        None => Ok(error_response(404, "log_not_found", "Log entry not found")),
+    }
+}
+
+fn handle_replay(req: Request) -> Result<Response> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    // Replay ignores the incremental cursor and the settlement cutoff: only the time window applies
+    let uri = req.uri().to_string();
+    let (from, to) = match (parse_timestamp_param(&uri, "from"), parse_timestamp_param(&uri, "to")) {
+        (Ok(Some(from)), Ok(Some(to))) => (from, to),
+        (Err(message), _) | (_, Err(message)) => return Ok(status_error(400, message)),
+        _ => return Ok(status_error(400, "Missing from or to parameter")),
+    };
+    let after_id = match get_query_param(&uri, "after_id").map(|v| v.parse::<i64>()) {
+        Some(Ok(after_id)) if after_id >= 0 => after_id,
+        None => 0,
+        _ => return Ok(status_error(400, "Invalid after_id: must be non-negative")),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Id order keeps after_id paging exact
+    let query = LogQuery {
+        after_id,
+        node_ids: Vec::new(),
+        cutoff: None,
+        from: Some(from),
+        to: Some(to),
+        prefix: None,
+        search: None,
+        source: None,
+        tenant,
+        order: LogOrder::Id,
+        limit: MAX_LOG_ITEMS_PER_DOWNLOAD,
+        // A smaller max_response_bytes applies here too
+        max_bytes: Some(
+            usize::try_from(get_i64_variable("max_response_bytes", 0))
+                .ok()
+                .filter(|&max_bytes| max_bytes > 0)
+                .map_or(MAX_REPLAY_PAGE_BYTES, |max_bytes| max_bytes.min(MAX_REPLAY_PAGE_BYTES)),
+        ),
+    };
+    let LogPage { logs, has_more, .. } = query_logs_page(&conn, &query)?;
+
+    // NDJSON (optionally gzip) keeps large disaster-recovery pulls small on the wire
+    if header_lists(&req, "accept", "application/x-ndjson") {
+        let gzip = header_lists(&req, "accept-encoding", "gzip");
+        let mut builder = Response::builder();
+        builder
+            .status(200)
+            .header("content-type", "application/x-ndjson")
+            .header("x-has-more", has_more.to_string())
+            .header("vary", "accept, accept-encoding");
+        if gzip {
+            builder.header("content-encoding", "gzip");
+        }
+        return Ok(builder.body(ndjson_body(&logs, gzip)?).build());
+    }
+
+    let response = ReplayResponse { logs, has_more };
+    json_response(200, &response)
+}
+
+fn handle_metrics_query(req: Request) -> Result<Response> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    let uri = req.uri().to_string();
+    let Some(metric) = get_query_param(&uri, "metric").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()) else {
+        return Ok(status_error(400, "Missing metric parameter"));
+    };
+    let node_id: Option<u32> = match get_query_param(&uri, "node_id").map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => Some(node_id),
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => None,
+    };
+    let (from, to) = match (parse_timestamp_param(&uri, "from"), parse_timestamp_param(&uri, "to")) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(message), _) | (_, Err(message)) => return Ok(status_error(400, message)),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let (points, has_more) = query_metric_points(&conn, &metric, node_id, from.as_deref(), to.as_deref(), tenant.as_deref())?;
+    let response = MetricQueryResponse { metric, points, has_more };
+    json_response(200, &response)
+}
+
+/// Server-Sent Events for a batch: one `id:`/`data:` event per item.
+fn sse_events<T: Serialize>(items: &[T], event_id: fn(&T) -> i64) -> Result<String> {
+    let mut events = String::new();
+    for item in items {
+        events.push_str(&format!("id: {}\ndata: {}\n\n", event_id(item), serde_json::to_string(item)?));
+    }
+    Ok(events)
+}
+
+/// A validated `/download/stream` request: the connection to poll and the query whose `after_id`
+/// advances as batches are sent.
+struct DownloadTail {
+    conn: Connection,
+    query: LogQuery,
+}
+
+/// Checks a `/download/stream` request before the response starts, so a bad request still gets
+/// its error status instead of an event stream.
+fn prepare_download_stream(req: &Request) -> Result<Result<DownloadTail, Response>> {
+    // Validate log collector API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(req, "log_collector_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(Err(response)),
+    };
+
+    // EventSource resends the last event id on reconnect; an explicit parameter wins
+    let uri = req.uri().to_string();
+    let requested_last_id = get_query_param(&uri, "last_log_message_id").or_else(|| req.header("last-event-id").and_then(|v| v.as_str()).map(|v| v.to_string()));
+    let requested_last_id = match requested_last_id.map(|v| v.trim().parse::<i64>()) {
+        Some(Ok(last_id)) if last_id >= 0 => Some(last_id),
+        Some(_) => return Ok(Err(status_error(400, "Invalid last_log_message_id: must be non-negative"))),
+        None => None,
+    };
+    let node_ids = match parse_node_id_params(&get_query_params(&uri, "node_id")) {
+        Ok(node_ids) => node_ids,
+        Err(message) => return Ok(Err(status_error(400, message))),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // A fresh tail starts at the newest row. Id order without the settlement cutoff serves every
+    // row exactly once as soon as it is stored, so the cursor can never pass an unsettled row.
+    let last_id = match requested_last_id {
+        Some(last_id) => last_id,
+        None => get_max_log_id(&conn)?,
+    };
+    let query = LogQuery {
+        after_id: last_id,
+        node_ids,
+        cutoff: None,
+        from: None,
+        to: None,
+        prefix: None,
+        search: None,
+        source: None,
+        tenant,
+        order: LogOrder::Id,
+        limit: MAX_STREAM_EVENTS_PER_POLL,
+        max_bytes: None,
+    };
+    Ok(Ok(DownloadTail { conn, query }))
+}
+
+/// Server-Sent Events over one open response. `poll` returns the next batch after a cursor and
+/// whether more is already waiting; each batch is written as soon as it is found, and an idle
+/// stream gets a comment line every `STREAM_KEEPALIVE_SECONDS`. The response ends when the
+/// client goes away or after `stream_max_duration_seconds`; EventSource then reconnects with
+/// `Last-Event-ID`. Returns the number of events sent.
+async fn stream_events<T: Serialize>(response_out: ResponseOutparam, mut last_id: i64, event_id: fn(&T) -> i64, mut poll: impl FnMut(i64) -> Result<(Vec<T>, bool)>) -> Result<usize> {
+    let headers = [("content-type".to_string(), b"text/event-stream".to_vec()), ("cache-control".to_string(), b"no-cache".to_vec())];
+    let outgoing = OutgoingResponse::new(Fields::from_list(&headers).map_err(|e| anyhow!("Invalid stream headers: {:?}", e))?);
+    let mut body = outgoing.take_body();
+    response_out.set(outgoing);
+
+    // The opening `id:` carries the cursor, so a fresh stream keeps its starting point across reconnects
+    let opening = format!("retry: {}\nid: {}\n\n", STREAM_POLL_INTERVAL_MS, last_id);
+    if body.send(opening.into_bytes()).await.is_err() {
+        return Ok(0);
+    }
+
+    let max_duration = std::time::Duration::from_secs(get_i64_variable("stream_max_duration_seconds", DEFAULT_STREAM_MAX_DURATION_SECONDS).max(0) as u64);
+    let keepalive = std::time::Duration::from_secs(STREAM_KEEPALIVE_SECONDS);
+    let started = Instant::now();
+    let mut last_write = Instant::now();
+    let mut sent = 0;
+    loop {
+        let (batch, has_more) = poll(last_id)?;
+        let chunk = match batch.last() {
+            Some(last) => {
+                last_id = event_id(last);
+                sse_events(&batch, event_id)?
+            }
+            // Keeps proxies from closing an idle stream, and a failed write shows the client has gone
+            None if last_write.elapsed() >= keepalive => ":\n\n".to_string(),
+            None => String::new(),
+        };
+        if !chunk.is_empty() {
+            if body.send(chunk.into_bytes()).await.is_err() {
+                break;
+            }
+            sent += batch.len();
+            last_write = Instant::now();
+        }
+        if started.elapsed() >= max_duration {
+            break;
+        }
+        // A full batch means more rows are waiting; otherwise wait for new ones. Each component
+        // instance serves a single request, so the blocking sleep holds up nothing else.
+        if !has_more {
+            std::thread::sleep(std::time::Duration::from_millis(STREAM_POLL_INTERVAL_MS).min(max_duration.saturating_sub(started.elapsed())));
+        }
+    }
+    Ok(sent)
+}
+
+/// Live tail of `/download/stream`: new rows in id order as they are stored.
+async fn stream_download_tail(response_out: ResponseOutparam, mut tail: DownloadTail) -> Result<usize> {
+    let last_id = tail.query.after_id;
+    stream_events(response_out, last_id, |log: &DownloadLogEntry| log.item_id, |after_id| {
+        tail.query.after_id = after_id;
+        let page = query_logs_page(&tail.conn, &tail.query)?;
+        Ok((page.logs, page.has_more))
+    })
+    .await
+}
+
+/// Logs the end of a streamed response; the timing covers the whole time the stream was open.
+fn log_stream_end(method: &Method, path: &str, started: Instant, streamed: Result<usize>) {
+    match streamed {
+        Ok(sent) => log::info!("handled {} {} in {}ms, status 200, {} events streamed", method, path, started.elapsed().as_millis(), sent),
+        Err(e) => log::error!("{} {} failed while streaming: {}", method, path, e),
+    }
+}
+
+/// Writes a whole `Response` to the outparam; every endpoint except the live streams answers this way.
+async fn send_response(response_out: ResponseOutparam, response: Response) {
+    let headers: Vec<(String, Vec<u8>)> = response.headers().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect();
+    let fields = Fields::from_list(&headers).unwrap_or_else(|e| {
+        log::error!("Dropping invalid response headers: {:?}", e);
+        Fields::new()
+    });
+    let outgoing = OutgoingResponse::new(fields);
+    if outgoing.set_status_code(*response.status()).is_err() {
+        log::error!("Invalid response status {}", response.status());
+    }
+    let mut body = outgoing.take_body();
+    response_out.set(outgoing);
+    if let Err(e) = body.send(response.into_body()).await {
+        log::warn!("Failed to write response body: {:?}", e);
+    }
+}
+
+fn handle_command(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    // Parse request body
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_COMMAND_CONTENT_TYPES) {
+        return Ok(response);
+    }
+    // Batch commands and parameter blobs may arrive gzipped over slow links
+    let body = match decoded_body(&req) {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let cmd_req: CommandRequest = match serde_json::from_slice(&body) {
+        Ok(cmd_req) => cmd_req,
+        Err(e) => return Ok(status_error(400, format!("Invalid command: {}", e))),
+    };
+
+    // A retry carrying the same Idempotency-Key gets the first response instead of queueing again
+    let Some(idempotency_key) = req.header("idempotency-key").and_then(|v| v.as_str()).map(|v| v.trim()) else {
+        return queue_commands(cmd_req);
+    };
+    if !is_valid_key_name(idempotency_key) {
+        return Ok(error_response(400, "invalid_idempotency_key", "Invalid Idempotency-Key"));
+    }
+    let store = Store::open_default()?;
+    let now = Utc::now();
+    let name = format!("command:{}", idempotency_key);
+    if let Some(previous) = get_transient_kv(&store, "idempotency:", &name, now)? {
+        log::info!("Replaying response for Idempotency-Key '{}'", idempotency_key);
+        let mut response = json_response(200, &previous)?;
+        response.set_header("idempotent-replayed", "true");
+        return Ok(response);
+    }
+
+    let response = queue_commands(cmd_req)?;
+    // Only a success is remembered, so a rejected request can be corrected and resent with the key
+    if *response.status() == 200 {
+        let body: serde_json::Value = serde_json::from_slice(response.body())?;
+        put_transient_kv(&store, "idempotency:", &name, now + chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS), body)?;
+    }
+    Ok(response)
+}
+
+/// Queues a parsed `POST /command` request: templates, `set_update_interval`, and single-node
+/// or broadcast commands.
+fn queue_commands(mut cmd_req: CommandRequest) -> Result<Response> {
+    // Expand a stored template into the command and parameters before normal processing
+    if let Some(template_name) = cmd_req.template.take() {
+        let store = Store::open_default()?;
+        let template = match load_command_template(&store, &template_name)? {
+            Some(template) => template,
+            None => return Ok(status_error(404, format!("Unknown command template '{}'", template_name))),
+        };
+        let command = match apply_template_overrides(template, cmd_req.overrides.take()) {
+            Ok(command) => command,
+            Err(message) => return Ok(status_error(400, message)),
+        };
+        cmd_req.command = command.command;
+        cmd_req.parameters = command.parameters;
+    }
+
+    if cmd_req.command.is_empty() {
+        return Ok(status_error(400, "Missing command or template"));
+    }
+
+    // A group expands to its stored members, fanned out like a broadcast
+    let group_node_ids = match cmd_req.group.take() {
+        Some(group_name) => {
+            let store = Store::open_default()?;
+            match load_node_group(&store, &group_name)? {
+                Some(group) => Some(group.node_ids.into_iter().map(i64::from).collect::<Vec<_>>()),
+                None => return Ok(status_error(404, format!("Unknown node group '{}'", group_name))),
+            }
+        }
+        None => None,
+    };
+
+    // Reject unknown condition keys or operators up front rather than holding the command forever
+    let condition = match cmd_req.condition.take() {
+        Some(value) => match serde_json::from_value::<CommandCondition>(value) {
+            Ok(condition) => Some(condition),
+            Err(e) => return Ok(status_error(400, format!("Invalid condition: {}", e))),
+        },
+        None => None,
+    };
+    let not_before = match cmd_req.not_before.take() {
+        Some(value) => match DateTime::parse_from_rfc3339(&value) {
+            Ok(not_before) => Some(not_before.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true)),
+            Err(_) => return Ok(status_error(400, "Invalid not_before: expected ISO 8601 timestamp")),
+        },
+        None => None,
+    };
+
+    // Handle set_update_interval specially - store in KV, don't forward to nodes
+    if cmd_req.command == "set_update_interval" {
+        if let Some(params) = &cmd_req.parameters {
+            // Parse start_time and end_time as ISO 8601 strings and convert to Unix timestamps
+            let Some(start_time_str) = params.get("start_time").and_then(|v| v.as_str()) else {
+                return Ok(status_error(400, "Missing start_time parameter"));
+            };
+            let Some(end_time_str) = params.get("end_time").and_then(|v| v.as_str()) else {
+                return Ok(status_error(400, "Missing end_time parameter"));
+            };
+            let Some(active_period) = params.get("active_period").and_then(|v| v.as_i64()) else {
+                return Ok(status_error(400, "Missing active_period parameter"));
+            };
+            let Some(inactive_period) = params.get("inactive_period").and_then(|v| v.as_i64()) else {
+                return Ok(status_error(400, "Missing inactive_period parameter"));
+            };
+
+            // Parse ISO 8601 timestamps to Unix timestamps
+            let Ok(start_time) = start_time_str.parse::<DateTime<Utc>>() else {
+                return Ok(status_error(400, "Invalid start_time format, expected ISO 8601"));
+            };
+            let Ok(end_time) = end_time_str.parse::<DateTime<Utc>>() else {
+                return Ok(status_error(400, "Invalid end_time format, expected ISO 8601"));
+            };
+
+            let config = UpdateIntervalConfig {
+                start_time: start_time.timestamp() as u64,
+                end_time: end_time.timestamp() as u64,
+                active_period,
+                inactive_period,
+            };
+
+            log::info!(
+                "Storing update interval config: start={}, end={}, active={}, inactive={}",
+                config.start_time,
+                config.end_time,
+                config.active_period,
+                config.inactive_period
+            );
+
+            let store = Store::open_default()?;
+            save_update_interval_config(&store, &config)?;
+
+            return command_created_response(0, None);
+        } else {
+            return Ok(status_error(400, "Missing parameters for set_update_interval"));
+        }
+    }
+
+    // Open database for other commands
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Check if node_id is specified in parameters; a quoted id is forwarded as a number
+    let node_id_opt = match extract_node_id(cmd_req.parameters.as_ref()) {
+        Ok(node_id) => node_id,
+        Err(message) => return Ok(status_error(400, message)),
+    };
+    if let (Some(node_id), Some(serde_json::Value::Object(params))) = (node_id_opt, cmd_req.parameters.as_mut()) {
+        let key = if params.contains_key("node_id") { "node_id" } else { "node id" };
+        if let Some(value) = params.get_mut(key) {
+            *value = serde_json::Value::from(node_id);
+        }
+    }
+
+    // Prepare command JSON
+    let command = Command {
+        id: None,
+        command: cmd_req.command.clone(),
+        parameters: cmd_req.parameters.clone(),
+    };
+    let command_json = serde_json::to_string(&command)?;
+
+    // Reject oversize commands before they get copied to every target node
+    let max_command_bytes = get_i64_variable("max_command_bytes", DEFAULT_MAX_COMMAND_BYTES);
+    if command_json.len() as i64 > max_command_bytes {
+        return Ok(status_error(
+            413,
+            format!("Command too large: {} bytes exceeds the limit of {} bytes", command_json.len(), max_command_bytes),
+        ));
+    }
+
+    if node_id_opt.is_some() && group_node_ids.is_some() {
+        return Ok(status_error(400, "Specify either parameters.node_id or group, not both"));
+    }
+
+    let mut options = CommandOptions {
+        min_node_version: cmd_req.min_node_version.clone(),
+        condition,
+        broadcast_id: None,
+        not_before,
+    };
+    let issued_at = Utc::now().to_rfc3339();
+
+    let mut commands_created = 0;
+    if let Some(node_id) = node_id_opt {
+        // A command for a node that never connected would sit until cleanup; pre-provisioning
+        // needs it queued, so refusing it is opt-in
+        if get_bool_variable("reject_unknown_node_target", false) && !node_is_known(&conn, node_id)? {
+            return Ok(status_error(404, format!("Unknown node_id {}", node_id)));
+        }
+        // Insert command for specific node (held at delivery until its version qualifies)
+        if !insert_command(&conn, node_id, &command_json, &issued_at, &options)? {
+            return Ok(pending_limit_response(node_id));
+        }
+        commands_created += 1;
+    } else {
+        // Insert command for all nodes (or the group's members), expanding {node_id} placeholders per target
+        options.broadcast_id = Some(random_hex(8)?);
+        let templated = command_json.contains(NODE_ID_PLACEHOLDER);
+        let node_ids = match group_node_ids {
+            Some(node_ids) => node_ids,
+            None => get_broadcast_node_ids(&conn)?,
+        };
+        let mut queue_full = None;
+        for node_id in node_ids {
+            // Skip nodes known to run firmware below the required version
+            if let Some(min_version) = &options.min_node_version {
+                if let Some(version) = get_node_version(&conn, node_id)? {
+                    if !version_at_least(&version, min_version) {
+                        log::debug!("Skipping node_id {} (version {} < {})", node_id, version, min_version);
+                        continue;
+                    }
+                }
+            }
+            let queued = if templated {
+                let node_command = Command {
+                    id: None,
+                    command: cmd_req.command.clone(),
+                    parameters: cmd_req.parameters.as_ref().map(|p| substitute_node_id(p, node_id)),
+                };
+                insert_command(&conn, node_id, &serde_json::to_string(&node_command)?, &issued_at, &options)?
+            } else {
+                insert_command(&conn, node_id, &command_json, &issued_at, &options)?
+            };
+            // A flooded node is skipped; the broadcast still reaches the others
+            if queued {
+                commands_created += 1;
+            } else {
+                queue_full = Some(node_id);
+            }
+        }
+        if let (0, Some(node_id)) = (commands_created, queue_full) {
+            return Ok(pending_limit_response(node_id));
+        }
+    }
+
+    command_created_response(commands_created, options.broadcast_id)
+}
+
+/// Target node from command parameters (`node_id`, or the legacy `node id`). Accepts a JSON
+/// integer or a string holding one, as shell tooling often quotes it; `Ok(None)` means broadcast.
+/// Any other value is an error rather than a silent broadcast.
+fn extract_node_id(parameters: Option<&serde_json::Value>) -> Result<Option<i64>, String> {
+    let Some(value) = parameters.and_then(|p| p.get("node_id").or_else(|| p.get("node id"))) else {
+        return Ok(None);
+    };
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Number(number) => number.as_i64().map(Some).ok_or_else(|| format!("Invalid node_id {}: expected an integer", number)),
+        serde_json::Value::String(text) => text.trim().parse::<i64>().map(Some).map_err(|_| format!("Invalid node_id '{}': expected an integer", text)),
+        other => Err(format!("Invalid node_id {}: expected an integer", other)),
+    }
+}
+
+fn pending_limit_response(node_id: i64) -> Response {
+    let max_pending = get_i64_variable("max_pending_commands", DEFAULT_MAX_PENDING_COMMANDS);
+    status_error(
+        429,
+        format!("Too Many Requests: node_id {} already has {} pending commands (max_pending_commands); wait for delivery or clear its queue", node_id, max_pending),
+    )
+}
+
+fn command_created_response(commands_created: usize, broadcast_id: Option<String>) -> Result<Response> {
+    let response = CommandResponse {
+        status: "ok".to_string(),
+        commands_created,
+        broadcast_id,
+    };
+    json_response(200, &response)
+}
+
+/// Names embedded in KV keys: command templates, node groups and collector ids.
+fn is_valid_key_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn handle_command_template(req: Request, name: &str) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    if !is_valid_key_name(name) {
+        return Ok(status_error(400, "Invalid template name: use letters, digits, '_' or '-'"));
+    }
+
+    let store = Store::open_default()?;
+    let status = match req.method() {
+        spin_sdk::http::Method::Put => {
+            let template: Command = match serde_json::from_slice(req.body()) {
+                Ok(template) => template,
+                Err(e) => return Ok(status_error(400, format!("Invalid template: {}", e))),
+            };
+            if template.command.is_empty() || template.command == "set_update_interval" {
+                return Ok(status_error(400, "Templates must name a node command"));
+            }
+            save_command_template(&store, name, &template)?;
+            log::info!("Stored command template '{}' for command {}", name, template.command);
+            "stored"
+        }
+        _ => {
+            if !delete_command_template(&store, name)? {
+                return Ok(status_error(404, format!("Unknown command template '{}'", name)));
+            }
+            log::info!("Deleted command template '{}'", name);
+            "deleted"
+        }
+    };
+
+    let response = TemplateResponse {
+        status: status.to_string(),
+        template: name.to_string(),
+    };
+    json_response(200, &response)
+}
+
+fn handle_node_group(req: Request, name: &str) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    if !is_valid_key_name(name) {
+        return Ok(status_error(400, "Invalid group name: use letters, digits, '_' or '-'"));
+    }
+
+    let store = Store::open_default()?;
+    let (status, node_ids) = match req.method() {
+        spin_sdk::http::Method::Put => {
+            let mut group: NodeGroup = match serde_json::from_slice(req.body()) {
+                Ok(group) => group,
+                Err(e) => return Ok(status_error(400, format!("Invalid node group: {}", e))),
+            };
+            group.node_ids.sort_unstable();
+            group.node_ids.dedup();
+            if group.node_ids.is_empty() {
+                return Ok(status_error(400, "A node group needs at least one node_id"));
+            }
+            save_node_group(&store, name, &group)?;
+            log::info!("Stored node group '{}' with {} nodes", name, group.node_ids.len());
+            ("stored", group.node_ids)
+        }
+        spin_sdk::http::Method::Delete => {
+            if !delete_node_group(&store, name)? {
+                return Ok(status_error(404, format!("Unknown node group '{}'", name)));
+            }
+            log::info!("Deleted node group '{}'", name);
+            ("deleted", Vec::new())
+        }
+        _ => match load_node_group(&store, name)? {
+            Some(group) => ("ok", group.node_ids),
+            None => return Ok(status_error(404, format!("Unknown node group '{}'", name))),
+        },
+    };
+
+    let response = NodeGroupResponse {
+        status: status.to_string(),
+        group: name.to_string(),
+        node_ids,
+    };
+    json_response(200, &response)
+}
+
+fn handle_webhook(req: Request, node_id: &str) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    let node_id: u32 = match node_id.parse() {
+        Ok(node_id) => node_id,
+        Err(_) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+    };
+
+    let store = Store::open_default()?;
+    let status = match req.method() {
+        spin_sdk::http::Method::Put => {
+            let config: WebhookConfig = match serde_json::from_slice(req.body()) {
+                Ok(config) => config,
+                Err(e) => return Ok(status_error(400, format!("Invalid webhook: {}", e))),
+            };
+            if !(config.url.starts_with("https://") || config.url.starts_with("http://")) {
+                return Ok(status_error(400, "Webhook url must be an http or https URL"));
+            }
+            if config.min_level.as_deref().is_some_and(|level| log_level_rank(level).is_none()) {
+                return Ok(status_error(400, "Invalid min_level: expected trace, debug, info, warn or error"));
+            }
+            save_webhook(&store, node_id, &config)?;
+            // Re-registering is how an operator says the endpoint is fixed: close its circuit
+            store.delete(webhook_circuit_key(&config.url))?;
+            log::info!("Stored webhook for node_id {}", node_id);
+            "stored"
+        }
+        _ => {
+            if !delete_webhook(&store, node_id)? {
+                return Ok(status_error(404, format!("No webhook for node {}", node_id)));
+            }
+            log::info!("Deleted webhook for node_id {}", node_id);
+            "deleted"
+        }
+    };
+
+    let response = WebhookResponse {
+        status: status.to_string(),
+        node_id,
+    };
+    json_response(200, &response)
+}
+
+fn handle_command_ack(req: Request) -> Result<Response> {
+    // Validate probe API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "probe_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    let node_id: u32 = match req.header("x-node-id").and_then(|v| v.as_str()).map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => node_id,
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => return Ok(error_response(400, "missing_node_id", "Missing X-Node-ID header")),
+    };
+    if node_id == SELFTEST_NODE_ID {
+        return Ok(error_response(400, "reserved_node_id", "Node id is reserved for /selftest"));
+    }
+
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_UPLOAD_CONTENT_TYPES) {
+        return Ok(response);
+    }
+    let ack_req: CommandAckRequest = match serde_json::from_slice(req.body()) {
+        Ok(ack_req) => ack_req,
+        Err(e) => return Ok(status_error(400, format!("Invalid acknowledgement: {}", e))),
+    };
+    let mut command_ids = ack_req.command_ids;
+    command_ids.extend(ack_req.command_id);
+    command_ids.sort_unstable();
+    command_ids.dedup();
+    if command_ids.is_empty() {
+        return Ok(error_response(400, "missing_command_ids", "Missing command_ids"));
+    }
+    if command_ids.len() > MAX_ACK_IDS_PER_REQUEST {
+        return Ok(error_response(400, "too_many_command_ids", format!("Too many command_ids: at most {} per request", MAX_ACK_IDS_PER_REQUEST)));
+    }
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Only the node itself may acknowledge (and so delete) its commands
+    if let Err(response) = verify_node_identity(&req, &conn, node_id, tenant.as_deref())? {
+        return Ok(response);
+    }
+
+    let (acknowledged, not_found) = acknowledge_commands(&conn, node_id, &command_ids)?;
+    if !not_found.is_empty() {
+        log::debug!("Node_id {} acknowledged {} commands no longer queued: {:?}", node_id, not_found.len(), not_found);
+    }
+    json_response(200, &CommandAckResponse { acknowledged, not_found })
+}
+
+fn handle_command_result(req: Request) -> Result<Response> {
+    // Validate probe API key (the tenant's own key when tenancy is enabled)
+    let tenant = match authorize_tenant(&req, "probe_api_key")? {
+        Ok(tenant) => tenant,
+        Err(response) => return Ok(response),
+    };
+
+    let node_id: u32 = match req.header("x-node-id").and_then(|v| v.as_str()).map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => node_id,
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => return Ok(error_response(400, "missing_node_id", "Missing X-Node-ID header")),
+    };
+    if node_id == SELFTEST_NODE_ID {
+        return Ok(error_response(400, "reserved_node_id", "Node id is reserved for /selftest"));
+    }
+
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_UPLOAD_CONTENT_TYPES) {
+        return Ok(response);
+    }
+    let result_req: CommandResultRequest = match serde_json::from_slice(req.body()) {
+        Ok(result_req) => result_req,
+        Err(e) => return Ok(status_error(400, format!("Invalid result report: {}", e))),
+    };
+    if result_req.command.is_empty() {
+        return Ok(status_error(400, "Missing command"));
+    }
+
+    // Known commands must match their schema; anything else is kept as reported
+    let (result, structured) = match parse_command_result(&result_req.command, result_req.result.clone()) {
+        Ok(Some(parsed)) => (parsed, true),
+        Ok(None) => (result_req.result, false),
+        Err(message) => return Ok(status_error(400, format!("Invalid result for {}: {}", result_req.command, message))),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Results feed /command/results and rollout streams, so only the node itself may report them
+    if let Err(response) = verify_node_identity(&req, &conn, node_id, tenant.as_deref())? {
+        return Ok(response);
+    }
+
+    let id = insert_command_result(&conn, node_id, result_req.command_id, &result_req.command, &result, structured)?;
+    log::debug!("Stored {} result {} from node_id {}", result_req.command, id, node_id);
+    json_response(200, &CommandResultResponse { id, structured })
+}
+
+fn handle_command_results(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    let uri = req.uri().to_string();
+    let node_id: Option<u32> = match get_query_param(&uri, "node_id").map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => Some(node_id),
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => None,
+    };
+    let command = get_query_param(&uri, "command").filter(|command| !command.is_empty());
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let response = CommandResultsResponse {
+        results: get_command_results(&conn, command.as_deref(), node_id)?,
+    };
+    json_response(200, &response)
+}
+
+/// A validated `/command/results/stream` request: the broadcast to follow and the cursor to start after.
+struct ResultTail {
+    conn: Connection,
+    broadcast_id: String,
+    after_id: i64,
+}
+
+/// Checks a `/command/results/stream` request before the response starts, so a bad request still
+/// gets its error status instead of an event stream.
+fn prepare_command_results_stream(req: &Request) -> Result<Result<ResultTail, Response>> {
+    // Validate CLI API key
+    if !is_authorized(req, "cli_api_key")? {
+        return Ok(Err(status_error(401, "Unauthorized")));
+    }
+
+    let uri = req.uri().to_string();
+    let Some(broadcast_id) = get_query_param(&uri, "command_group").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()) else {
+        return Ok(Err(status_error(400, "Missing command_group parameter")));
+    };
+    // EventSource resends the last event id on reconnect; an explicit parameter wins
+    let after_id = get_query_param(&uri, "after_id").or_else(|| req.header("last-event-id").and_then(|v| v.as_str()).map(|v| v.to_string()));
+    let after_id = match after_id.map(|v| v.trim().parse::<i64>()) {
+        Some(Ok(after_id)) if after_id >= 0 => after_id,
+        Some(_) => return Ok(Err(status_error(400, "Invalid after_id: must be non-negative"))),
+        None => 0,
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+    Ok(Ok(ResultTail { conn, broadcast_id, after_id }))
+}
+
+/// Live view of a broadcast rollout: the group's results as nodes report them. Without a cursor
+/// it starts from the group's first result, so the client sees every node that answered. Each
+/// result is one autocommitted insert, so ids become visible in order and the cursor cannot pass
+/// a result that is still being written.
+async fn stream_command_results(response_out: ResponseOutparam, tail: ResultTail) -> Result<usize> {
+    stream_events(response_out, tail.after_id, |result: &StoredCommandResult| result.id, |after_id| {
+        let results = get_broadcast_results_after(&tail.conn, &tail.broadcast_id, after_id)?;
+        let has_more = results.len() as i64 >= MAX_STREAM_EVENTS_PER_POLL;
+        Ok((results, has_more))
+    })
+    .await
+}
+
+fn handle_command_peek(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    let uri = req.uri().to_string();
+    let node_id: u32 = match get_query_param(&uri, "node_id").map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => node_id,
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => return Ok(error_response(400, "missing_node_id", "Missing X-Node-ID header")),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Read-only: unlike the probe's poll, nothing is deleted or recorded as delivered
+    let response = PeekResponse {
+        node_id,
+        commands: peek_commands(&conn, node_id)?,
+    };
+    json_response(200, &response)
+}
+
+fn handle_command_unacked(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    let older_than_minutes = match get_query_param(req.uri(), "older_than_minutes").map(|v| v.trim().parse::<i64>()) {
+        Some(Ok(minutes)) if minutes >= 0 => minutes,
+        None => 0,
+        _ => return Ok(status_error(400, "Invalid older_than_minutes: must be non-negative")),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let older_than = Utc::now() - chrono::Duration::minutes(older_than_minutes);
+    let response = UnackedResponse {
+        commands: get_unacked_commands(&conn, older_than)?,
+    };
+    json_response(200, &response)
+}
+
+fn handle_command_queue_clear(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    let uri = req.uri().to_string();
+    let node_id: u32 = match get_query_param(&uri, "node_id").map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => node_id,
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => return Ok(error_response(400, "missing_node_id", "Missing X-Node-ID header")),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let removed = clear_commands(&conn, node_id)?;
+    log::info!("Cleared {} queued commands for node_id {}", removed, node_id);
+
+    json_response(200, &ClearQueueResponse { node_id, removed })
+}
+
+fn handle_register_node(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_COMMAND_CONTENT_TYPES) {
+        return Ok(response);
+    }
+    let register_req: RegisterNodeRequest = match serde_json::from_slice(req.body()) {
+        Ok(register_req) => register_req,
+        Err(e) => return Ok(status_error(400, format!("Invalid registration: {}", e))),
+    };
+    if register_req.node_id == SELFTEST_NODE_ID {
+        return Ok(error_response(400, "reserved_node_id", "Node id is reserved for /selftest"));
+    }
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Re-registering rotates the secret; the old one stops working immediately
+    let secret = register_node(&conn, register_req.node_id as i64)?;
+    log::info!("Registered node_id {}", register_req.node_id);
+
+    let response = RegisterNodeResponse {
+        node_id: register_req.node_id,
+        secret,
+    };
+    json_response(200, &response)
+}
+
+fn handle_nodes(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Health is judged against the interval probes are currently told to upload at
+    let update_interval = current_update_interval(open_store().as_ref());
+    let response = NodesResponse {
+        nodes: get_nodes(&conn, update_interval, &HealthThresholds::from_variables())?,
+        update_interval,
+    };
+    json_response(200, &response)
+}
 
-    let mut logs = Vec::new();
-    for row in result.rows() {
-        let id = row.get::<i64>("id");
-        let timestamp = row.get::<&str>("timestamp");
-        let node_id = row.get::<i64>("node_id");
-        let message = row.get::<&str>("message");
-
-        if let (Some(id), Some(timestamp), Some(node_id), Some(message)) = (id, timestamp, node_id, message) {
-            logs.push(DownloadLogEntry {
-                item_id: id,
-                timestamp: timestamp.to_string(),
-                node_id,
-                message: message.to_string(),
-            });
-        }
+fn handle_stats(req: Request) -> Result<Response> {
+    // Validate CLI API key
+    if !is_authorized(&req, "cli_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    Ok(logs)
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let response = StatsResponse {
+        command_latency: get_command_latency_stats(&conn)?,
+    };
+    json_response(200, &response)
 }
 
-fn insert_command(conn: &Connection, node_id: i64, command_json: &str) -> Result<()> {
-    let timestamp = Utc::now().to_rfc3339();
-    conn.execute(
-        "INSERT INTO commands (timestamp, node_id, command) VALUES (?, ?, ?)",
-        &[Value::Text(timestamp), Value::Integer(node_id), Value::Text(command_json.to_string())],
-    )?;
-    Ok(())
+fn handle_version() -> Result<Response> {
+    // No authentication: only identifies the running code and schema
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    let response = VersionResponse {
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version: get_schema_version(&conn)?,
+    };
+    json_response(200, &response)
 }
 
-fn get_all_node_ids(conn: &Connection) -> Result<Vec<i64>> {
-    let result = conn.execute("SELECT DISTINCT node_id FROM log_messages ORDER BY node_id", &[])?;
+fn handle_time() -> Result<Response> {
+    // No authentication and no storage access: probes call this to calibrate their clocks
+    let now = Utc::now();
+    let response = TimeResponse {
+        server_time: now.to_rfc3339_opts(SecondsFormat::Millis, true),
+        epoch_ms: now.timestamp_millis(),
+    };
+    let mut response = json_response(200, &response)?;
+    response.set_header("cache-control", "no-store");
+    Ok(response)
+}
 
-    let mut node_ids = Vec::new();
-    for row in result.rows() {
-        if let Some(node_id) = row.get::<i64>("node_id") {
-            node_ids.push(node_id);
-        }
+fn handle_admin_kv(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    Ok(node_ids)
+    let store = Store::open_default()?;
+    json_response(200, &dump_kv_state(&store)?)
 }
 
-// ============================================================================
-// Key-Value Store Operations
-// ============================================================================
+fn handle_import(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+    if let Some(response) = unsupported_content_type(&req, ACCEPTED_IMPORT_CONTENT_TYPES) {
+        return Ok(response);
+    }
 
-fn should_cleanup(store: &Store, cleanup_interval_minutes: i64) -> Result<bool> {
-    match store.get("last_cleanup_time") {
-        Ok(Some(bytes)) => {
-            let last_cleanup_str = String::from_utf8(bytes)?;
-            let last_cleanup: DateTime<Utc> = last_cleanup_str.parse()?;
-            let now = Utc::now();
-            Ok((now - last_cleanup).num_minutes() >= cleanup_interval_minutes)
-        }
-        _ => Ok(true),
+    // Snapshot pages post back as downloaded, with Content-Encoding: gzip
+    let body = match decoded_body_up_to(&req, MAX_DECOMPRESSED_IMPORT_BYTES) {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+    let ImportBatch { entries, commands, rejected } = match parse_import_body(&body) {
+        Ok(parsed) => parsed,
+        Err(message) => return Ok(status_error(400, message)),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+    let id_store = match get_log_id_strategy() {
+        LogIdStrategy::Sequence => open_store(),
+        LogIdStrategy::Rowid => None,
+    };
+    if let Some(id_store) = &id_store {
+        sync_log_id_sequence(&conn, id_store)?;
+    }
+    let accepted = import_log_messages(&conn, &entries)?;
+    if let Some(id_store) = &id_store {
+        sync_log_id_sequence(&conn, id_store)?;
     }
-}
 
-fn update_last_cleanup_time(store: &Store) -> Result<()> {
-    let now = Utc::now().to_rfc3339();
-    store.set("last_cleanup_time", now.as_bytes())?;
-    Ok(())
-}
+    let restored = restore_commands(&conn, &commands)?;
+    let duplicates = (entries.len() - accepted) + (commands.len() - restored);
 
-fn save_update_interval_config(store: &Store, config: &UpdateIntervalConfig) -> Result<()> {
-    let json = serde_json::to_string(config)?;
-    store.set("update_interval_config", json.as_bytes())?;
-    Ok(())
-}
+    log::info!(
+        "Imported {} historical log messages and {} queued commands ({} rejected, {} duplicates skipped)",
+        accepted,
+        restored,
+        rejected,
+        duplicates
+    );
 
-fn get_update_interval_config(store: &Store) -> Option<UpdateIntervalConfig> {
-    store
-        .get("update_interval_config")
-        .ok()
-        .and_then(|opt| opt)
-        .and_then(|bytes| String::from_utf8(bytes).ok())
-        .and_then(|s| serde_json::from_str(&s).ok())
+    let response = ImportResponse {
+        accepted,
+        rejected,
+        commands: restored,
+        duplicates,
+    };
+    let mut response = json_response(200, &response)?;
+    response.set_header("x-rows-inserted", accepted.to_string());
+    Ok(response)
 }
 
-fn get_current_update_interval(store: &Store, default_interval: i64) -> i64 {
-    match get_update_interval_config(store) {
-        Some(config) => {
-            let now = Utc::now().timestamp() as u64;
-            if now >= config.start_time && now <= config.end_time {
-                log::debug!("In active period, using active_period: {}", config.active_period);
-                config.active_period
-            } else {
-                log::debug!("In inactive period, using inactive_period: {}", config.inactive_period);
-                config.inactive_period
-            }
-        }
-        None => {
-            log::debug!("No update interval config found, using default: {}", default_interval);
-            default_interval
-        }
+fn handle_export_snapshot(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
-}
 
-// ============================================================================
-// HTTP Handlers
-// ============================================================================
+    // An interrupted export resumes from the X-Next-Cursor of the last page it received
+    let cursor = match get_query_param(req.uri(), "cursor") {
+        Some(value) => match SnapshotCursor::parse(&value) {
+            Some(cursor) => cursor,
+            None => return Ok(status_error(400, "Invalid cursor: expected logs:{id} or commands:{id}")),
+        },
+        None => SnapshotCursor::Logs(0),
+    };
 
-fn handle_update(req: Request) -> Result<Response> {
-    // Validate probe API key
-    let probe_api_key = variables::get("probe_api_key")?;
-    let api_key_header = req
-        .header("x-api-key")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("Missing X-Api-Key header"))?;
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
 
-    if api_key_header != probe_api_key {
-        return Ok(Response::builder().status(401).body("Unauthorized").build());
-    }
+    // Rows are compressed as they are written, so only the gzip output is held in memory
+    let exported_at = Utc::now();
+    let header = SnapshotRecord::Snapshot(SnapshotHeader {
+        schema_version: get_schema_version(&conn)?,
+        exported_at: exported_at.to_rfc3339(),
+        cursor: cursor.to_string(),
+    });
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, &header)?;
+    encoder.write_all(b"\n")?;
+    let next_cursor = write_snapshot_page(&conn, &mut encoder, cursor)?;
+    let body = encoder.finish()?;
 
-    // Get node ID
-    let node_id_str = req
-        .header("x-node-id")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("Missing X-Node-ID header"))?;
-    let node_id: u32 = node_id_str.parse().map_err(|_| anyhow!("Invalid node ID"))?;
+    log::info!("Exported snapshot page from {} ({} bytes), next cursor {:?}", cursor, body.len(), next_cursor.map(|c| c.to_string()));
 
-    // Parse request body
-    let body = req.body();
-    let upload_req: ProbeUploadRequest = serde_json::from_slice(body)?;
+    let mut builder = Response::builder();
+    builder
+        .status(200)
+        .header("content-type", "application/gzip")
+        .header(
+            "content-disposition",
+            format!("attachment; filename=\"snapshot-{}.ndjson.gz\"", exported_at.format("%Y%m%dT%H%M%SZ")),
+        )
+        .header("x-has-more", next_cursor.is_some().to_string());
+    if let Some(next_cursor) = next_cursor {
+        builder.header("x-next-cursor", next_cursor.to_string());
+    }
+    Ok(builder.body(body).build())
+}
 
-    log::debug!(
-        "Received upload request. Node_id: {}, uploaded logline count: {}",
-        node_id,
-        upload_req.logs.len()
-    );
+fn handle_renormalize(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
+    }
+
+    // Large databases take several calls; each resumes after the previous `last_id`
+    let after_id = match get_query_param(req.uri(), "after_id") {
+        Some(value) => match value.parse::<i64>() {
+            Ok(id) if id >= 0 => id,
+            _ => return Ok(status_error(400, "Invalid after_id parameter")),
+        },
+        None => 0,
+    };
 
-    // Open database and initialize
     let conn = Connection::open_default()?;
     init_database(&conn)?;
 
-    // Insert log messages
-    insert_log_messages(&conn, node_id, &upload_req.logs)?;
+    let Some(lock) = try_acquire_maintenance_lock(&conn)? else {
+        return Ok(status_error(409, "Maintenance already in progress"));
+    };
+    let result = renormalize_timestamps(&conn, after_id);
+    release_maintenance_lock(&conn, &lock)?;
+    let report = result?;
 
-    // Check if cleanup is needed
-    let store = Store::open_default()?;
-    let cleanup_interval = variables::get("cleanup_interval_minutes")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DEFAULT_CLEANUP_INTERVAL_MINUTES);
-    let delete_timeout = variables::get("delete_timeout_minutes")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DEFAULT_DELETE_TIMEOUT_MINUTES);
+    log::info!(
+        "Timestamp renormalization after id {}: {} scanned, {} fixed, {} unparseable",
+        after_id,
+        report.scanned,
+        report.fixed,
+        report.unparseable
+    );
+
+    json_response(200, &report)
+}
 
-    if should_cleanup(&store, cleanup_interval)? {
-        cleanup_old_data(&conn, delete_timeout)?;
-        update_last_cleanup_time(&store)?;
+fn handle_purge_logs(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    // Get and delete commands for this node
-    let commands = get_and_delete_commands(&conn, node_id)?;
+    // Both bounds are required so a bare DELETE /logs can never wipe the table
+    let uri = req.uri().to_string();
+    let (from, to) = match (parse_timestamp_param(&uri, "from"), parse_timestamp_param(&uri, "to")) {
+        (Ok(Some(from)), Ok(Some(to))) => (from, to),
+        (Err(message), _) | (_, Err(message)) => return Ok(status_error(400, message)),
+        _ => return Ok(status_error(400, "Missing from or to parameter")),
+    };
+    if from >= to {
+        return Ok(status_error(400, "Invalid range: from must be before to"));
+    }
+    let node_id: Option<u32> = match get_query_param(&uri, "node_id").map(|v| v.trim().parse()) {
+        Some(Ok(node_id)) => Some(node_id),
+        Some(Err(_)) => return Ok(error_response(400, "invalid_node_id", "Invalid node_id: expected an unsigned integer")),
+        None => None,
+    };
 
-    // Get current update interval based on active/inactive period
-    let default_interval = variables::get("default_upload_interval")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DEFAULT_UPLOAD_INTERVAL_SECONDS);
-    let update_interval = get_current_update_interval(&store, default_interval);
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
 
-    // Return commands and update_interval as JSON
-    let response = UpdateResponse { commands, update_interval };
-    let response_body = serde_json::to_string(&response)?;
-    Ok(Response::builder()
-        .status(200)
-        .header("content-type", "application/json")
-        .body(response_body)
-        .build())
-}
+    let Some(lock) = try_acquire_maintenance_lock(&conn)? else {
+        return Ok(status_error(409, "Maintenance already in progress"));
+    };
+    let result = purge_logs(&conn, &from, &to, node_id, &RetentionSettings::from_variables());
+    release_maintenance_lock(&conn, &lock)?;
+    let (deleted, complete) = result?;
 
-fn handle_download(req: Request) -> Result<Response> {
-    // Validate log collector API key
-    let log_collector_api_key = variables::get("log_collector_api_key")?;
-    let api_key_header = req
-        .header("x-api-key")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("Missing X-Api-Key header"))?;
+    match node_id {
+        Some(node_id) => log::info!("Purged {} log messages of node_id {} between {} and {}", deleted, node_id, from, to),
+        None => log::info!("Purged {} log messages between {} and {}", deleted, from, to),
+    }
+
+    json_response(200, &PurgeResponse { deleted, complete })
+}
 
-    if api_key_header != log_collector_api_key {
-        return Ok(Response::builder().status(401).body("Unauthorized").build());
+fn handle_undelete(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    // Parse query parameter
     let uri = req.uri().to_string();
-    let last_id = uri
-        .split("last_log_message_id=")
-        .nth(1)
-        .and_then(|s| s.split('&').next())
-        .ok_or_else(|| anyhow!("Missing last_log_message_id parameter"))?
-        .parse::<i64>()
-        .map_err(|_| anyhow!("Invalid last_log_message_id"))?;
+    let (from, to) = match (parse_timestamp_param(&uri, "from"), parse_timestamp_param(&uri, "to")) {
+        (Ok(Some(from)), Ok(Some(to))) => (from, to),
+        (Err(message), _) | (_, Err(message)) => return Ok(status_error(400, message)),
+        _ => return Ok(status_error(400, "Missing from or to parameter")),
+    };
+
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // Never restore while a cleanup is moving rows out
+    let Some(lock) = try_acquire_maintenance_lock(&conn)? else {
+        return Ok(status_error(409, "Maintenance already in progress"));
+    };
+    let result = undelete_log_messages(&conn, &from, &to);
+    release_maintenance_lock(&conn, &lock)?;
+    let restored = result?;
+
+    log::info!("Restored {} soft-deleted log messages between {} and {}", restored, from, to);
 
-    if last_id < 0 {
-        return Ok(Response::builder()
-            .status(400)
-            .body("Invalid last_log_message_id: must be non-negative")
-            .build());
+    json_response(200, &UndeleteResponse { restored })
+}
+
+fn handle_vacuum(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    // Open database
     let conn = Connection::open_default()?;
     init_database(&conn)?;
 
-    // Get current upload interval based on active/inactive period (same logic as /update)
-    let store = Store::open_default()?;
-    let default_interval = variables::get("default_upload_interval")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DEFAULT_UPLOAD_INTERVAL_SECONDS);
-    let current_upload_interval = get_current_update_interval(&store, default_interval);
+    let size_before = get_database_size(&conn)?;
 
-    // Get logs using the current interval for filtering
-    let logs = get_logs_for_download(&conn, last_id, current_upload_interval)?;
+    // Never vacuum while a cleanup (or another maintenance run) is deleting rows
+    let Some(lock) = try_acquire_maintenance_lock(&conn)? else {
+        return Ok(status_error(409, "Maintenance already in progress"));
+    };
 
-    // Check if cleanup is needed
-    let store = Store::open_default()?;
-    let cleanup_interval = variables::get("cleanup_interval_minutes")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DEFAULT_CLEANUP_INTERVAL_MINUTES);
-    let delete_timeout = variables::get("delete_timeout_minutes")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DEFAULT_DELETE_TIMEOUT_MINUTES);
+    let vacuum_result = vacuum_database(&conn);
+    release_maintenance_lock(&conn, &lock)?;
+    let mode = vacuum_result?;
+    let size_after = get_database_size(&conn)?;
 
-    if should_cleanup(&store, cleanup_interval)? {
-        cleanup_old_data(&conn, delete_timeout)?;
-        update_last_cleanup_time(&store)?;
-    }
+    log::info!("Database {} finished: {} bytes -> {} bytes", mode, size_before, size_after);
 
-    // Return logs and update_interval as JSON
-    let response = DownloadResponse {
-        logs,
-        update_interval: current_upload_interval,
+    let response = VacuumResponse {
+        mode: mode.to_string(),
+        size_before,
+        size_after,
     };
-    let response_body = serde_json::to_string(&response)?;
-    Ok(Response::builder()
-        .status(200)
-        .header("content-type", "application/json")
-        .body(response_body)
-        .build())
+    json_response(200, &response)
 }
 
-fn handle_command(req: Request) -> Result<Response> {
-    // Validate CLI API key
-    let cli_api_key = variables::get("cli_api_key")?;
-    let api_key_header = req
-        .header("x-api-key")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("Missing X-Api-Key header"))?;
-
-    if api_key_header != cli_api_key {
-        return Ok(Response::builder().status(401).body("Unauthorized").build());
+fn handle_reindex(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    // Parse request body
-    let body = req.body();
-    let cmd_req: CommandRequest = serde_json::from_slice(body)?;
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
 
-    // Handle set_update_interval specially - store in KV, don't forward to nodes
-    if cmd_req.command == "set_update_interval" {
-        if let Some(params) = &cmd_req.parameters {
-            // Parse start_time and end_time as ISO 8601 strings and convert to Unix timestamps
-            let start_time_str = params
-                .get("start_time")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| anyhow!("Missing start_time parameter"))?;
-            let end_time_str = params
-                .get("end_time")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| anyhow!("Missing end_time parameter"))?;
-            let active_period = params
-                .get("active_period")
-                .and_then(|v| v.as_i64())
-                .ok_or_else(|| anyhow!("Missing active_period parameter"))?;
-            let inactive_period = params
-                .get("inactive_period")
-                .and_then(|v| v.as_i64())
-                .ok_or_else(|| anyhow!("Missing inactive_period parameter"))?;
+    // Rebuilding while cleanup deletes rows would only redo the work afterwards
+    let Some(lock) = try_acquire_maintenance_lock(&conn)? else {
+        return Ok(status_error(409, "Maintenance already in progress"));
+    };
 
-            // Parse ISO 8601 timestamps to Unix timestamps
-            let start_time: DateTime<Utc> = start_time_str.parse().map_err(|_| anyhow!("Invalid start_time format, expected ISO 8601"))?;
-            let end_time: DateTime<Utc> = end_time_str.parse().map_err(|_| anyhow!("Invalid end_time format, expected ISO 8601"))?;
+    let started = Instant::now();
+    let reindex_result = reindex_tables(&conn);
+    release_maintenance_lock(&conn, &lock)?;
+    let tables = reindex_result?;
+    let duration_ms = started.elapsed().as_millis();
 
-            let config = UpdateIntervalConfig {
-                start_time: start_time.timestamp() as u64,
-                end_time: end_time.timestamp() as u64,
-                active_period,
-                inactive_period,
-            };
+    log::info!("Reindexed {} in {}ms", REINDEX_TABLES.join(", "), duration_ms);
 
-            log::info!(
-                "Storing update interval config: start={}, end={}, active={}, inactive={}",
-                config.start_time,
-                config.end_time,
-                config.active_period,
-                config.inactive_period
-            );
+    let response = ReindexResponse { tables, duration_ms };
+    json_response(200, &response)
+}
 
-            let store = Store::open_default()?;
-            save_update_interval_config(&store, &config)?;
+// ============================================================================
+// Self-test
+// ============================================================================
 
-            return Ok(Response::builder().status(200).body("OK").build());
-        } else {
-            return Ok(Response::builder().status(400).body("Missing parameters for set_update_interval").build());
-        }
+fn selftest_step(name: &'static str, result: Result<()>) -> SelfTestStep {
+    if let Err(e) = &result {
+        log::warn!("Self-test step {} failed: {}", name, e);
+    }
+    SelfTestStep {
+        name,
+        passed: result.is_ok(),
+        error: result.err().map(|e| e.to_string()),
     }
+}
 
-    // Open database for other commands
-    let conn = Connection::open_default()?;
-    init_database(&conn)?;
+/// Backdated past the retention window, so the cleanup step's real retention run removes it.
+fn selftest_insert_log(conn: &Connection, marker: &str) -> Result<()> {
+    let now = Utc::now();
+    let delete_timeout = get_i64_variable("delete_timeout_minutes", DEFAULT_DELETE_TIMEOUT_MINUTES);
+    let timestamp = now - chrono::Duration::minutes(delete_timeout + 1);
+    let row = LogRow::new(timestamp.to_rfc3339_opts(SecondsFormat::Secs, true), Cow::Borrowed(marker), None, None, None);
+    insert_log_row(conn, SELFTEST_NODE_ID, Some(&now.to_rfc3339()), row).map(|_| ())
+}
 
-    // Prepare command JSON
-    let command = Command {
-        command: cmd_req.command.clone(),
-        parameters: cmd_req.parameters.clone(),
+/// Reads the synthetic row back through the same query builder `/download` uses.
+fn selftest_read_log(conn: &Connection, marker: &str) -> Result<()> {
+    let query = LogQuery {
+        after_id: 0,
+        node_ids: vec![SELFTEST_NODE_ID as i64],
+        cutoff: None,
+        from: None,
+        to: None,
+        prefix: None,
+        search: Some(marker.to_string()),
+        source: None,
+        tenant: None,
+        order: LogOrder::Id,
+        limit: 10,
+        max_bytes: None,
     };
-    let command_json = serde_json::to_string(&command)?;
+    if query_logs_page(conn, &query)?.logs.iter().any(|log| log.message == marker) {
+        Ok(())
+    } else {
+        Err(anyhow!("Synthetic log message was not returned by the download query"))
+    }
+}
 
-    // Check if node_id is specified in parameters
-    let node_id_opt = cmd_req
-        .parameters
-        .as_ref()
-        .and_then(|p| p.get("node_id").or_else(|| p.get("node id")))
-        .and_then(|v| v.as_i64());
+fn selftest_command_queue(conn: &Connection, marker: &str) -> Result<()> {
+    let command = Command {
+        id: None,
+        command: "run_command".to_string(),
+        parameters: Some(serde_json::json!({ "value": marker })),
+    };
+    let issued_at = Utc::now().to_rfc3339();
+    if !insert_command(conn, SELFTEST_NODE_ID as i64, &serde_json::to_string(&command)?, &issued_at, &CommandOptions::default())? {
+        return Err(anyhow!("Synthetic command was refused: max_pending_commands reached"));
+    }
+    let queued = peek_commands(conn, SELFTEST_NODE_ID)?;
+    if queued.iter().any(|pending| pending.command.as_ref().and_then(|c| c.parameters.as_ref()) == command.parameters.as_ref()) {
+        Ok(())
+    } else {
+        Err(anyhow!("Synthetic command was not returned from the queue"))
+    }
+}
 
-    if let Some(node_id) = node_id_opt {
-        // Insert command for specific node
-        insert_command(&conn, node_id, &command_json)?;
+fn selftest_kv_store(marker: &str) -> Result<()> {
+    let store = Store::open_default()?;
+    let key = format!("selftest:{}", marker.trim_start_matches("selftest "));
+    store.set(&key, marker.as_bytes())?;
+    let read_back = store.get(&key)?;
+    store.delete(&key)?;
+    if read_back.as_deref() == Some(marker.as_bytes()) {
+        Ok(())
     } else {
-        // Insert command for all nodes
-        let node_ids = get_all_node_ids(&conn)?;
-        for node_id in node_ids {
-            insert_command(&conn, node_id, &command_json)?;
+        Err(anyhow!("Key-value store returned a different value than was written"))
+    }
+}
+
+/// Runs the real retention cleanup (`cleanup_old_data` under the maintenance lock, with the
+/// configured settings), which removes the backdated synthetic row like any expired log. What it
+/// legitimately keeps (rows collectors have not acknowledged, soft-deleted copies) and the
+/// synthetic command are then removed directly, so the self-test leaves nothing behind.
+fn selftest_cleanup(conn: &Connection) -> Result<()> {
+    let cleanup_result = match try_acquire_maintenance_lock(conn)? {
+        Some(lock) => {
+            let delete_timeout = get_i64_variable("delete_timeout_minutes", DEFAULT_DELETE_TIMEOUT_MINUTES);
+            let result = cleanup_old_data(conn, delete_timeout, &RetentionSettings::from_variables());
+            release_maintenance_lock(conn, &lock)?;
+            result
         }
+        None => Err(anyhow!("Maintenance lock is held by another run; retention cleanup was not exercised")),
+    };
+
+    let retention = RetentionSettings {
+        soft_delete: false,
+        undelete_window_minutes: 0,
+        collector_ack_window_minutes: 0,
+    };
+    let node_param = [Value::Integer(SELFTEST_NODE_ID as i64)];
+    remove_log_rows(conn, "SELECT id FROM log_messages WHERE node_id = ? LIMIT 100", &node_param, &retention)?;
+    conn.execute("DELETE FROM deleted_log_messages WHERE node_id = ?", &node_param)?;
+    clear_commands(conn, SELFTEST_NODE_ID)?;
+    cleanup_result?;
+
+    let remaining = conn.execute("SELECT COUNT(*) AS count FROM log_messages WHERE node_id = ?", &node_param)?;
+    let count = remaining.rows().next().and_then(|row| row.get::<i64>("count"));
+    match count {
+        Some(0) => Ok(()),
+        Some(count) => Err(anyhow!("{} synthetic log messages left after cleanup", count)),
+        None => Err(anyhow!("Could not count remaining synthetic log messages")),
+    }
+}
+
+fn handle_selftest(req: Request) -> Result<Response> {
+    // Validate admin API key
+    if !is_authorized(&req, "admin_api_key")? {
+        return Ok(status_error(401, "Unauthorized"));
     }
 
-    Ok(Response::builder().status(200).body("OK").build())
+    let conn = Connection::open_default()?;
+    init_database(&conn)?;
+
+    // A fresh marker per run, so leftovers of an interrupted run cannot make a step pass
+    let marker = format!("selftest {}", random_hex(8)?);
+    let steps = vec![
+        selftest_step("insert_log", selftest_insert_log(&conn, &marker)),
+        selftest_step("read_log", selftest_read_log(&conn, &marker)),
+        selftest_step("command_queue", selftest_command_queue(&conn, &marker)),
+        selftest_step("kv_store", selftest_kv_store(&marker)),
+        // Always last: it also removes the synthetic rows of earlier steps
+        selftest_step("cleanup", selftest_cleanup(&conn)),
+    ];
+    let passed = steps.iter().all(|step| step.passed);
+    log::info!("Self-test {}", if passed { "passed" } else { "failed" });
+
+    json_response(if passed { 200 } else { 500 }, &SelfTestResponse { passed, steps })
 }
 
 // ============================================================================
 // Main HTTP Component
 // ============================================================================
 
+fn parse_level_filter(value: &str) -> Option<log::LevelFilter> {
+    match value.trim().to_lowercase().as_str() {
+        "off" => Some(log::LevelFilter::Off),
+        "trace" => Some(log::LevelFilter::Trace),
+        "debug" => Some(log::LevelFilter::Debug),
+        "info" => Some(log::LevelFilter::Info),
+        "warn" => Some(log::LevelFilter::Warn),
+        "error" => Some(log::LevelFilter::Error),
+        _ => None,
+    }
+}
+
+/// The `enable_*` variables that must all be true for a path to be served, so one binary can be
+/// deployed as e.g. an ingest-only or read-only hub.
+fn route_enable_flags(method: &Method, path: &str) -> &'static [&'static str] {
+    match (method, path) {
+        (_, "/update") => &["enable_update"],
+        (_, "/sync") => &["enable_update", "enable_download"],
+        (_, "/gateway/sync") => &["enable_update", "enable_download"],
+        (_, path) if path.starts_with("/download") => &["enable_download"],
+        (&Method::Post, "/command") => &["enable_command"],
+        (_, path) if path.starts_with("/command/template/") => &["enable_command"],
+        _ => &[],
+    }
+}
+
+/// Parses the `log_targets` variable, e.g. `moonblokz_telemetry_hub=debug,spin_sdk=warn`.
+/// Each module path prefix gets its own level; entries that do not parse are returned separately.
+fn parse_log_targets(raw: &str) -> (Vec<(String, log::LevelFilter)>, Vec<String>) {
+    let mut targets = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in raw.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        match entry.split_once('=').and_then(|(target, level)| Some((target.trim(), parse_level_filter(level)?))) {
+            Some((target, level)) if !target.is_empty() => targets.push((target.to_string(), level)),
+            _ => invalid.push(entry.to_string()),
+        }
+    }
+    (targets, invalid)
+}
+
 #[http_component]
-fn handle_request(req: Request) -> Result<impl IntoResponse> {
-    // Get log level from configuration and initialize logger
+async fn handle_request(req: Request, response_out: ResponseOutparam) {
+    // Get log level and per-target overrides from configuration and initialize logger
     let loglevel = variables::get("loglevel").unwrap_or_else(|_| "info".to_string());
-    let log_level = match loglevel.to_lowercase().as_str() {
-        "trace" => log::LevelFilter::Trace,
-        "debug" => log::LevelFilter::Debug,
-        "info" => log::LevelFilter::Info,
-        "warn" => log::LevelFilter::Warn,
-        "error" => log::LevelFilter::Error,
-        _ => log::LevelFilter::Info,
-    };
-    let _ = SimpleLogger::new().with_level(log_level).init();
+    let log_level = parse_level_filter(&loglevel).unwrap_or(log::LevelFilter::Info);
+    let (log_targets, invalid_targets) = parse_log_targets(&variables::get("log_targets").unwrap_or_default());
+    let logger = log_targets
+        .iter()
+        .fold(SimpleLogger::new().with_level(log_level), |logger, (target, level)| logger.with_module_level(target, *level));
+    if logger.init().is_ok() && !invalid_targets.is_empty() {
+        log::warn!("Ignoring invalid log_targets entries: {}", invalid_targets.join(", "));
+    }
+    let started = Instant::now();
 
     // Parse request URI and method
     let uri = req.uri();
@@ -563,14 +6437,100 @@ fn handle_request(req: Request) -> Result<impl IntoResponse> {
     // Extract path: remove domain/scheme if present, then remove query string
     let path = uri.split("://").last().unwrap_or(uri).split('/').skip(1).collect::<Vec<_>>().join("/");
     let path = format!("/{}", path.split('?').next().unwrap_or(&path));
-    let method = req.method();
+    let method = req.method().clone();
 
     log::debug!("Received request: method={}, path={}", method, path);
 
-    match (method, path.as_str()) {
+    // Disabled routes look like they do not exist
+    let disabled_by = route_enable_flags(&method, &path).iter().find(|flag| !get_bool_variable(flag, true));
+    // Tenant endpoints only accept tenant API keys, so with both set bearer tokens would be ignored there
+    let jwt_with_tenancy = get_auth_mode() == AuthMode::Jwt && get_bool_variable("enable_tenancy", false);
+    let result = match (&method, path.as_str()) {
+        _ if disabled_by.is_some() => {
+            log::debug!("Rejecting {} {}: {} is false", method, path, disabled_by.unwrap_or(&""));
+            Ok(status_error(404, "Not Found"))
+        }
+        _ if jwt_with_tenancy => {
+            log::error!("Refusing {} {}: auth_mode = jwt cannot be combined with enable_tenancy", method, path);
+            Ok(error_response(500, "jwt_with_tenancy", "auth_mode = jwt cannot be combined with enable_tenancy"))
+        }
         (&spin_sdk::http::Method::Post, "/update") => handle_update(req),
+        (&spin_sdk::http::Method::Post, "/sync") => handle_sync(req),
+        (&spin_sdk::http::Method::Post, "/gateway/sync") => handle_gateway_sync(req),
+        (&spin_sdk::http::Method::Get, "/download/replay") => handle_replay(req),
+        (&spin_sdk::http::Method::Get, "/download/stream") => match prepare_download_stream(&req) {
+            Ok(Ok(tail)) => {
+                log_stream_end(&method, &path, started, stream_download_tail(response_out, tail).await);
+                return;
+            }
+            Ok(Err(response)) => Ok(response),
+            Err(e) => Err(e),
+        },
+        (&spin_sdk::http::Method::Post, "/download/cursor/reset") => handle_cursor_reset(req),
+        (&spin_sdk::http::Method::Post, "/download/ack") => handle_collector_ack(req),
+        (&spin_sdk::http::Method::Get, path) if path.strip_prefix("/download/").is_some_and(|id| !id.is_empty()) => {
+            match path.trim_start_matches("/download/").parse::<i64>() {
+                Ok(item_id) => handle_download_item(req, item_id),
+                Err(_) => Ok(status_error(404, "Not Found")),
+            }
+        }
         (&spin_sdk::http::Method::Get, path) if path.starts_with("/download") => handle_download(req),
         (&spin_sdk::http::Method::Post, "/command") => handle_command(req),
-        _ => Ok(Response::builder().status(404).body("Not Found").build()),
-    }
+        (&spin_sdk::http::Method::Get, "/command/peek") => handle_command_peek(req),
+        (&spin_sdk::http::Method::Get, "/command/unacked") => handle_command_unacked(req),
+        (&spin_sdk::http::Method::Post, "/command/ack") => handle_command_ack(req),
+        (&spin_sdk::http::Method::Post, "/command/result") => handle_command_result(req),
+        (&spin_sdk::http::Method::Get, "/command/results") => handle_command_results(req),
+        (&spin_sdk::http::Method::Get, "/command/results/stream") => match prepare_command_results_stream(&req) {
+            Ok(Ok(tail)) => {
+                log_stream_end(&method, &path, started, stream_command_results(response_out, tail).await);
+                return;
+            }
+            Ok(Err(response)) => Ok(response),
+            Err(e) => Err(e),
+        },
+        (&spin_sdk::http::Method::Delete, "/command/queue") => handle_command_queue_clear(req),
+        (&spin_sdk::http::Method::Put | &spin_sdk::http::Method::Delete, path) if path.starts_with("/command/template/") => {
+            let name = path.trim_start_matches("/command/template/").to_string();
+            handle_command_template(req, &name)
+        }
+        (&spin_sdk::http::Method::Put | &spin_sdk::http::Method::Delete, path) if path.starts_with("/webhook/") => {
+            let node_id = path.trim_start_matches("/webhook/").to_string();
+            handle_webhook(req, &node_id)
+        }
+        (&spin_sdk::http::Method::Get, "/nodes") => handle_nodes(req),
+        (&spin_sdk::http::Method::Post, "/nodes/register") => handle_register_node(req),
+        (&spin_sdk::http::Method::Get | &spin_sdk::http::Method::Put | &spin_sdk::http::Method::Delete, path) if path.starts_with("/nodes/group/") => {
+            let name = path.trim_start_matches("/nodes/group/").to_string();
+            handle_node_group(req, &name)
+        }
+        (&spin_sdk::http::Method::Get, "/stats") => handle_stats(req),
+        (&spin_sdk::http::Method::Get, "/metrics/query") => handle_metrics_query(req),
+        (&spin_sdk::http::Method::Get, "/version") => handle_version(),
+        (&spin_sdk::http::Method::Get, "/time") => handle_time(),
+        (&spin_sdk::http::Method::Post, "/import") => handle_import(req),
+        (&spin_sdk::http::Method::Get, "/export/snapshot") => handle_export_snapshot(req),
+        (&spin_sdk::http::Method::Post, "/maintenance/vacuum") => handle_vacuum(req),
+        (&spin_sdk::http::Method::Post, "/maintenance/reindex") => handle_reindex(req),
+        (&spin_sdk::http::Method::Post, "/maintenance/renormalize") => handle_renormalize(req),
+        (&spin_sdk::http::Method::Post, "/maintenance/undelete") => handle_undelete(req),
+        (&spin_sdk::http::Method::Delete, "/logs") => handle_purge_logs(req),
+        (&spin_sdk::http::Method::Post, "/selftest") => handle_selftest(req),
+        (&spin_sdk::http::Method::Get, "/admin/kv") => handle_admin_kv(req),
+        _ => Ok(status_error(404, "Not Found")),
+    };
+
+    // Per-request timing, to see which endpoints (and whether cleanup or inserts) dominate latency
+    // Handler errors are logged here and answered with a generic 500, so database or internal
+    // details never reach the client
+    let mut response = result.unwrap_or_else(|e| {
+        log::error!("{} {} failed: {}", method, path, e);
+        status_error(500, "Internal Server Error")
+    });
+    let elapsed_ms = started.elapsed().as_millis();
+    // Ingest endpoints report their row count, so a slow request can be told apart from a large batch
+    let rows = response.header("x-rows-inserted").and_then(|v| v.as_str()).map(|rows| format!(", {} rows inserted", rows)).unwrap_or_default();
+    log::info!("handled {} {} in {}ms, status {}{}", method, path, elapsed_ms, response.status(), rows);
+    response.set_header("x-response-time-ms", elapsed_ms.to_string());
+    send_response(response_out, response).await;
 }
@@ -9,6 +9,31 @@ BASE_URL="${BASE_URL:-http://127.0.0.1:3000}"
 PROBE_KEY="${PROBE_KEY:-probe-secret-key-123456789012}"
 COLLECTOR_KEY="${COLLECTOR_KEY:-collector-secret-key-123456789}"
 CLI_KEY="${CLI_KEY:-cli-secret-key-12345678901234}"
+ADMIN_KEY="${ADMIN_KEY:-admin-secret-key-1234567890123}"
+# Must match the hub's download_order_by variable (timestamp, id or received_at)
+DOWNLOAD_ORDER_BY="${DOWNLOAD_ORDER_BY:-timestamp}"
+# Must match the hub's node_reclaim_threshold variable; the reclaim test needs 1-5000 (and cleanup interval 0)
+NODE_RECLAIM_THRESHOLD="${NODE_RECLAIM_THRESHOLD:-0}"
+# Must match the hub's structured_errors variable; errors are plain text unless it is true
+STRUCTURED_ERRORS="${STRUCTURED_ERRORS:-false}"
+# Local SQLite file of the hub (spin up default); tests that plant rows directly skip without it
+HUB_DB_PATH="${HUB_DB_PATH:-.spin/sqlite_db.db}"
+# Must match the hub's reject_unknown_node_target variable
+REJECT_UNKNOWN_NODE_TARGET="${REJECT_UNKNOWN_NODE_TARGET:-false}"
+# Must match the hub's require_monotonic_uploads variable
+REQUIRE_MONOTONIC_UPLOADS="${REQUIRE_MONOTONIC_UPLOADS:-false}"
+# Must match the hub's max_pending_commands variable
+MAX_PENDING_COMMANDS="${MAX_PENDING_COMMANDS:-100}"
+# Must match the hub's webhook_failure_threshold variable
+WEBHOOK_FAILURE_THRESHOLD="${WEBHOOK_FAILURE_THRESHOLD:-5}"
+# Set to the hub's jwt_secret to run the JWT test (only that test; the others use API keys)
+JWT_SECRET="${JWT_SECRET:-}"
+# Set to a tenant from the hub's tenants variable (and its keys) to run the tenancy test
+TENANT="${TENANT:-}"
+TENANT_PROBE_KEY="${TENANT_PROBE_KEY:-}"
+TENANT_COLLECTOR_KEY="${TENANT_COLLECTOR_KEY:-}"
+# Must match the hub's cleanup_interval_minutes variable; the cleanup backlog test needs 0
+CLEANUP_INTERVAL_MINUTES="${CLEANUP_INTERVAL_MINUTES:-5}"
 NODE_ID=21
 
 echo "=== Testing Moonblokz Telemetry Hub ==="
@@ -37,6 +62,77 @@ UPLOAD_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
 echo "Response: $UPLOAD_RESPONSE"
 echo ""
 
+# Test 1d: Stored row count reported with the response
+echo "Test 1d: Upload reports its row count in X-Rows-Inserted"
+ROWS_HEADER=$(curl -s -D - -o /dev/null -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  -d '{"logs": []}' | tr -d '\r' | grep -i '^x-rows-inserted:')
+echo "$ROWS_HEADER" | grep -qi '^x-rows-inserted: 0$' && echo "PASS: row count header present" || echo "FAIL: unexpected header: $ROWS_HEADER"
+echo ""
+
+# Test 1a: Upload integrity check via X-Content-SHA256
+echo "Test 1a: Upload with a body checksum"
+CHECKSUM_BODY='{"logs": [{"timestamp": "2025-10-24T12:00:10Z", "message": "[INFO] Checksummed message"}]}'
+CHECKSUM=$(printf '%s' "$CHECKSUM_BODY" | sha256sum | cut -d' ' -f1)
+CHECKSUM_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -H "X-Content-SHA256: $CHECKSUM" \
+  --data-binary "$CHECKSUM_BODY")
+[ "$CHECKSUM_STATUS" = "200" ] && echo "PASS: matching checksum accepted" || echo "FAIL: expected 200, got $CHECKSUM_STATUS"
+MISMATCH_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -H "X-Content-SHA256: $CHECKSUM" \
+  --data-binary '{"logs": []}')
+[ "$STRUCTURED_ERRORS" = "true" ] && EXPECTED_ERROR='"error":"checksum_mismatch"' || EXPECTED_ERROR='Body does not match X-Content-SHA256'
+echo "$MISMATCH_RESPONSE" | grep -q "$EXPECTED_ERROR" && echo "PASS: corrupted body rejected" || echo "FAIL: checksum mismatch not detected"
+echo ""
+
+# Test 1b: Timestamp order within a batch
+echo "Test 1b: Upload an out-of-order batch (require_monotonic_uploads=$REQUIRE_MONOTONIC_UPLOADS)"
+ORDER_RESPONSE=$(curl -s -w "\n%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  -d '{"logs": [{"timestamp": "2025-10-24T12:00:20Z", "message": "[INFO] Order check 1"}, {"timestamp": "2025-10-24T12:00:30Z", "message": "[INFO] Order check 2"}, {"timestamp": "2025-10-24T12:00:25Z", "message": "[INFO] Order check 3"}]}')
+if [ "$REQUIRE_MONOTONIC_UPLOADS" = "true" ]; then
+  [ "$STRUCTURED_ERRORS" = "true" ] && EXPECTED_ERROR='"error":"non_monotonic_timestamps",.*"index":2' || EXPECTED_ERROR='Entry 2 is timestamped before an earlier entry'
+  echo "$ORDER_RESPONSE" | grep -q "$EXPECTED_ERROR" && echo "PASS: out-of-order entry identified" || echo "FAIL: unexpected response: $ORDER_RESPONSE"
+else
+  [ "$(echo "$ORDER_RESPONSE" | tail -n 1)" = "200" ] && echo "PASS: out-of-order batch accepted" || echo "FAIL: unexpected response: $ORDER_RESPONSE"
+fi
+echo ""
+
+# Test 1c: Body formats and encodings
+echo "Test 1c: Upload gzip-compressed NDJSON"
+NDJSON_STATUS=$(printf '%s\n%s\n' '{"timestamp": "2025-10-24T12:01:00Z", "message": "[INFO] NDJSON check 1"}' '{"timestamp": "2025-10-24T12:01:05Z", "message": "[INFO] NDJSON check 2"}' | gzip -c | \
+  curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/x-ndjson" \
+  -H "Content-Encoding: gzip" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  --data-binary @-)
+[ "$NDJSON_STATUS" = "200" ] && echo "PASS: gzip NDJSON upload accepted" || echo "FAIL: expected 200, got $NDJSON_STATUS"
+BAD_LINE_RESPONSE=$(printf '%s\n%s\n' '{"timestamp": "2025-10-24T12:01:10Z", "message": "[INFO] NDJSON check 3"}' 'not json' | \
+  curl -s -w "\n%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/x-ndjson" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  --data-binary @-)
+[ "$(echo "$BAD_LINE_RESPONSE" | tail -n 1)" = "400" ] && echo "$BAD_LINE_RESPONSE" | grep -q "line 2" && echo "PASS: malformed line reported" || echo "FAIL: unexpected response: $BAD_LINE_RESPONSE"
+CSV_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: text/csv" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  -d 'timestamp,message')
+[ "$CSV_STATUS" = "415" ] && echo "PASS: unsupported content type rejected" || echo "FAIL: expected 415, got $CSV_STATUS"
+echo ""
+
 # Test 2: Submit a command via CLI
 echo "Test 2: Submit command via /command endpoint"
 COMMAND_RESPONSE=$(curl -s -X POST "$BASE_URL/command" \
@@ -51,6 +147,7 @@ COMMAND_RESPONSE=$(curl -s -X POST "$BASE_URL/command" \
   }')
 
 echo "Response: $COMMAND_RESPONSE"
+echo "$COMMAND_RESPONSE" | grep -q '"status":"ok","commands_created":1' && echo "PASS: JSON command response" || echo "FAIL: unexpected command response"
 echo ""
 
 # Test 3: Upload again to retrieve the command
@@ -64,6 +161,378 @@ UPLOAD_RESPONSE2=$(curl -s -X POST "$BASE_URL/update" \
   }')
 
 echo "Response: $UPLOAD_RESPONSE2"
+echo "$UPLOAD_RESPONSE2" | grep -q '"id":[0-9]*,"command":"set_log_level"' && echo "PASS: delivered command carries its id" || echo "FAIL: delivered command has no id"
+echo ""
+
+# Test 3a0: A corrupt queued command does not take the others down with it
+echo "Test 3a0: Delivery with a corrupt command row"
+if command -v sqlite3 > /dev/null && [ -f "$HUB_DB_PATH" ]; then
+  sqlite3 "$HUB_DB_PATH" "INSERT INTO commands (timestamp, node_id, command) VALUES ('$(date -u +%Y-%m-%dT%H:%M:%S+00:00)', $NODE_ID, '{not json')"
+  curl -s -X POST "$BASE_URL/command" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $CLI_KEY" \
+    -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $NODE_ID, \"value\": \"corrupt-check\"}}" > /dev/null
+  CORRUPT_UPLOAD_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $PROBE_KEY" \
+    -H "X-Node-ID: $NODE_ID" \
+    -d '{"logs": []}')
+  echo "$CORRUPT_UPLOAD_RESPONSE" | grep -q "corrupt-check" && echo "PASS: valid command delivered" || echo "FAIL: valid command not delivered: $CORRUPT_UPLOAD_RESPONSE"
+  curl -s -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" | grep -q '"command":null' && echo "PASS: corrupt command left queued" || echo "FAIL: corrupt command was dropped"
+  curl -s -X DELETE "$BASE_URL/command/queue?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" > /dev/null
+else
+  echo "SKIP: needs sqlite3 and the hub database at $HUB_DB_PATH"
+fi
+echo ""
+
+# Test 3a: Store a command template and invoke it with overrides
+echo "Test 3a: Store and invoke a command template"
+TEMPLATE_RESPONSE=$(curl -s -X PUT "$BASE_URL/command/template/debug_logging" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_log_level", "parameters": {"log_level": "DEBUG"}}')
+
+echo "Response: $TEMPLATE_RESPONSE"
+TEMPLATE_COMMAND_RESPONSE=$(curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"template": "debug_logging", "overrides": {"node_id": '$NODE_ID'}}')
+
+echo "Response: $TEMPLATE_COMMAND_RESPONSE"
+echo ""
+
+# Test 3a1: Target a node group
+echo "Test 3a1: Store a node group and send it a command"
+GROUP_RESPONSE=$(curl -s -X PUT "$BASE_URL/nodes/group/test-group" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"node_ids": ['$NODE_ID']}')
+echo "Response: $GROUP_RESPONSE"
+GROUP_COMMAND_RESPONSE=$(curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_filter", "group": "test-group", "parameters": {"value": "group-check"}}')
+echo "Response: $GROUP_COMMAND_RESPONSE"
+echo "$GROUP_COMMAND_RESPONSE" | grep -q '"commands_created":1' && echo "PASS: group command fanned out to its member" || echo "FAIL: unexpected group command response"
+curl -s -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" | grep -q "group-check" && echo "PASS: group command queued for member" || echo "FAIL: group command not queued"
+UNKNOWN_GROUP_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_filter", "group": "no-such-group"}')
+[ "$UNKNOWN_GROUP_STATUS" = "404" ] && echo "PASS: unknown group rejected" || echo "FAIL: expected 404, got $UNKNOWN_GROUP_STATUS"
+curl -s -X DELETE "$BASE_URL/nodes/group/test-group" -H "X-Api-Key: $CLI_KEY" > /dev/null
+echo ""
+
+# Test 3b: Broadcast a templated command and retrieve the per-node copy
+echo "Test 3b: Broadcast a command with a {node_id} placeholder"
+BROADCAST_RESPONSE=$(curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{
+    "command": "run_command",
+    "parameters": {
+      "value": "/REPORT node-{node_id}"
+    }
+  }')
+
+echo "Response: $BROADCAST_RESPONSE"
+TEMPLATED_COMMANDS=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"logs": []}')
+
+echo "Response: $TEMPLATED_COMMANDS"
+echo "$TEMPLATED_COMMANDS" | grep -q "/REPORT node-$NODE_ID" && echo "PASS: placeholder expanded for node $NODE_ID" || echo "FAIL: placeholder not expanded"
+echo ""
+
+# Test 3b2: Peek at the queue without consuming it
+echo "Test 3b2: Peek at queued commands via /command/peek endpoint"
+curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $NODE_ID, \"value\": \"peek-check\"}}" > /dev/null
+curl -s -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" > /dev/null
+PEEK_RESPONSE=$(curl -s -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" \
+  -H "X-Api-Key: $CLI_KEY")
+
+echo "Response: $PEEK_RESPONSE"
+echo "$PEEK_RESPONSE" | grep -q "peek-check" && echo "PASS: command still queued after peeking" || echo "FAIL: peek consumed the command"
+PEEKED_COMMANDS=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"logs": []}')
+echo "$PEEKED_COMMANDS" | grep -q "peek-check" && echo "PASS: peeked command delivered to probe" || echo "FAIL: peeked command not delivered"
+echo ""
+
+# Test 3b3: Cursor-mode delivery leaves commands queued
+echo "Test 3b3: Fetch commands in cursor mode via /update endpoint"
+CURSOR_NODE_ID=23
+curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $CURSOR_NODE_ID, \"value\": \"cursor-check\"}}" > /dev/null
+CURSOR_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -H "X-Command-Mode: cursor" \
+  -d '{"logs": []}')
+
+echo "Response: $CURSOR_RESPONSE"
+LAST_COMMAND_ID=$(echo "$CURSOR_RESPONSE" | sed -n 's/.*"last_command_id":\([0-9]*\).*/\1/p')
+CURSOR_RESPONSE2=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -H "X-Command-Mode: cursor" \
+  -H "X-Last-Command-ID: $LAST_COMMAND_ID" \
+  -d '{"logs": []}')
+echo "$CURSOR_RESPONSE2" | grep -q "cursor-check" && echo "FAIL: command repeated after cursor advanced" || echo "PASS: cursor skips delivered command"
+# Resending the old cursor redelivers the command, but only its first delivery counts in /stats
+STATS_BEFORE=$(curl -s -X GET "$BASE_URL/stats" -H "X-Api-Key: $CLI_KEY" | grep -o "\"node_id\":$CURSOR_NODE_ID,\"deliveries\":[0-9]*")
+curl -s -o /dev/null -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -H "X-Command-Mode: cursor" \
+  -d '{"logs": []}'
+STATS_AFTER=$(curl -s -X GET "$BASE_URL/stats" -H "X-Api-Key: $CLI_KEY" | grep -o "\"node_id\":$CURSOR_NODE_ID,\"deliveries\":[0-9]*")
+[ -n "$STATS_BEFORE" ] && [ "$STATS_BEFORE" = "$STATS_AFTER" ] && echo "PASS: redelivery not counted as a delivery" || echo "FAIL: deliveries went from '$STATS_BEFORE' to '$STATS_AFTER'"
+CONSUMED_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -d '{"logs": []}')
+echo "$CONSUMED_RESPONSE" | grep -q "cursor-check" && echo "PASS: command still queued after cursor fetch" || echo "FAIL: cursor fetch deleted the command"
+echo ""
+
+# Test 3b4: Batched acknowledgement of cursor-mode commands
+echo "Test 3b4: Acknowledge commands via /command/ack endpoint"
+for VALUE in ack-one ack-two; do
+  curl -s -X POST "$BASE_URL/command" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $CLI_KEY" \
+    -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $CURSOR_NODE_ID, \"value\": \"$VALUE\"}}" > /dev/null
+done
+ACK_CURSOR_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -H "X-Command-Mode: cursor" \
+  -d '{"logs": []}')
+ACK_IDS=$(echo "$ACK_CURSOR_RESPONSE" | grep -o '"id":[0-9]*' | cut -d: -f2 | paste -sd, -)
+ACK_RESPONSE=$(curl -s -X POST "$BASE_URL/command/ack" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -d "{\"command_ids\": [$ACK_IDS, 999999999]}")
+
+echo "Response: $ACK_RESPONSE"
+echo "$ACK_RESPONSE" | grep -q '"acknowledged":\[[0-9]*,[0-9]*\],"not_found":\[999999999\]' && echo "PASS: batch acknowledged" || echo "FAIL: unexpected ack response"
+BAD_ACK_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command/ack" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CURSOR_NODE_ID" \
+  -d '{"command_ids": "seven"}')
+[ "$BAD_ACK_STATUS" = "400" ] && echo "PASS: malformed ack rejected" || echo "FAIL: expected 400, got $BAD_ACK_STATUS"
+echo ""
+
+# Test 3b4a: Delivered but unacknowledged commands
+echo "Test 3b4a: List unacknowledged commands via /command/unacked endpoint"
+UNACKED_RESPONSE=$(curl -s -X GET "$BASE_URL/command/unacked" \
+  -H "X-Api-Key: $CLI_KEY")
+
+echo "Response: $UNACKED_RESPONSE"
+echo "$UNACKED_RESPONSE" | grep -q '"commands":' && ! echo "$UNACKED_RESPONSE" | grep -q 'ack-one' && echo "PASS: acknowledged commands not listed" || echo "FAIL: unexpected unacked response"
+UNACKED_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/command/unacked?older_than_minutes=-5" \
+  -H "X-Api-Key: $CLI_KEY")
+[ "$UNACKED_STATUS" = "400" ] && echo "PASS: negative age rejected" || echo "FAIL: expected 400, got $UNACKED_STATUS"
+echo ""
+
+# Test 3b4b: Structured command results
+echo "Test 3b4b: Report and list command results"
+RESULT_RESPONSE=$(curl -s -X POST "$BASE_URL/command/result" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"command": "run_command", "result": {"output": "disk: 42% used", "exit_code": 0}}')
+echo "Response: $RESULT_RESPONSE"
+echo "$RESULT_RESPONSE" | grep -q '"structured":true' && echo "PASS: known result parsed" || echo "FAIL: result not structured"
+BAD_RESULT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command/result" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"command": "set_log_level", "result": {"log_level": "LOUD"}}')
+[ "$BAD_RESULT_STATUS" = "400" ] && echo "PASS: malformed result rejected" || echo "FAIL: expected 400, got $BAD_RESULT_STATUS"
+curl -s -X GET "$BASE_URL/command/results?command=run_command&node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" | grep -q "disk: 42% used" && echo "PASS: result listed" || echo "FAIL: result missing from /command/results"
+echo ""
+
+# Test 3b4c: Stream the results of a broadcast
+echo "Test 3b4c: Stream broadcast results via /command/results/stream"
+curl -s -X DELETE "$BASE_URL/command/queue?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" > /dev/null
+curl -s -o /dev/null -X PUT "$BASE_URL/nodes/group/rollout-group" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"node_ids": ['$NODE_ID']}'
+ROLLOUT_BROADCAST_ID=$(curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "reboot_probe", "group": "rollout-group"}' | sed -n 's/.*"broadcast_id":"\([0-9a-f]*\)".*/\1/p')
+ROLLOUT_COMMAND_ID=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"logs": []}' | grep -o '"id":[0-9]*' | head -n 1 | cut -d: -f2)
+curl -s -o /dev/null -X POST "$BASE_URL/command/result" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"command": "reboot_probe", "command_id": '"${ROLLOUT_COMMAND_ID:-0}"', "result": {"success": true}}'
+ROLLOUT_STREAM=$(curl -s -N --max-time 3 -X GET "$BASE_URL/command/results/stream?command_group=$ROLLOUT_BROADCAST_ID" -H "X-Api-Key: $CLI_KEY")
+echo "Response: $ROLLOUT_STREAM"
+[ -n "$ROLLOUT_BROADCAST_ID" ] && echo "$ROLLOUT_STREAM" | grep -q "\"broadcast_id\":\"$ROLLOUT_BROADCAST_ID\"" && echo "PASS: broadcast result streamed" || echo "FAIL: broadcast result not streamed"
+curl -s -X DELETE "$BASE_URL/nodes/group/rollout-group" -H "X-Api-Key: $CLI_KEY" > /dev/null
+echo ""
+
+# Test 3b5: Node list with health status
+echo "Test 3b5: List nodes via /nodes endpoint"
+NODES_RESPONSE=$(curl -s -X GET "$BASE_URL/nodes" \
+  -H "X-Api-Key: $CLI_KEY")
+
+echo "Response: $NODES_RESPONSE"
+echo "$NODES_RESPONSE" | grep -q "\"node_id\":$NODE_ID,[^}]*\"status\":\"healthy\"" && echo "PASS: node $NODE_ID is healthy" || echo "FAIL: node $NODE_ID not reported healthy"
+echo "$NODES_RESPONSE" | grep -q "\"node_id\":$NODE_ID,[^}]*\"logs_received\":[1-9]" && echo "PASS: node $NODE_ID upload counters recorded" || echo "FAIL: node $NODE_ID has no upload counters"
+echo ""
+
+# Test 3b6: Conditional delivery on node labels
+echo "Test 3b6: Hold a conditional command until the node labels match"
+CONDITION_NODE_ID=24
+curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $CONDITION_NODE_ID, \"value\": \"condition-check\"}, \"condition\": {\"labels\": {\"site\": \"lab\"}}}" > /dev/null
+HELD_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CONDITION_NODE_ID" \
+  -H "X-Node-Labels: site=field" \
+  -d '{"logs": []}')
+echo "$HELD_RESPONSE" | grep -q "condition-check" && echo "FAIL: command delivered to non-matching node" || echo "PASS: command held for non-matching labels"
+CONDITION_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $CONDITION_NODE_ID" \
+  -H "X-Node-Labels: site=lab" \
+  -d '{"logs": []}')
+
+echo "Response: $CONDITION_RESPONSE"
+echo "$CONDITION_RESPONSE" | grep -q "condition-check" && echo "PASS: command delivered once labels match" || echo "FAIL: matching command not delivered"
+echo ""
+
+# Test 3b7: Flush a node's command queue
+echo "Test 3b7: Clear queued commands via /command/queue endpoint"
+curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $NODE_ID, \"value\": \"flush-check\"}}" > /dev/null
+CLEAR_RESPONSE=$(curl -s -X DELETE "$BASE_URL/command/queue?node_id=$NODE_ID" \
+  -H "X-Api-Key: $CLI_KEY")
+
+echo "Response: $CLEAR_RESPONSE"
+curl -s -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" | grep -q "flush-check" && echo "FAIL: command still queued after flush" || echo "PASS: queue flushed"
+echo ""
+
+# Test 3b7a: A retried command with the same Idempotency-Key is queued once
+echo "Test 3b7a: Retry a command with an Idempotency-Key"
+curl -s -o /dev/null -X POST "$BASE_URL/nodes/register" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"node_id": 987671}'
+IDEMPOTENCY_KEY="test-$(date +%s)-$$"
+for attempt in 1 2; do
+  curl -s -D - -o /dev/null -X POST "$BASE_URL/command" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $CLI_KEY" \
+    -H "Idempotency-Key: $IDEMPOTENCY_KEY" \
+    -d '{"command": "set_filter", "parameters": {"node_id": 987671, "value": "idempotency-check"}}' | tr -d '\r' > /tmp/idempotency_$attempt
+done
+grep -qi '^idempotent-replayed: true' /tmp/idempotency_2 && ! grep -qi '^idempotent-replayed' /tmp/idempotency_1 && echo "PASS: retry replayed the first response" || echo "FAIL: retry not marked as replayed"
+rm -f /tmp/idempotency_1 /tmp/idempotency_2
+IDEMPOTENT_COPIES=$(curl -s -X GET "$BASE_URL/command/peek?node_id=987671" -H "X-Api-Key: $CLI_KEY" | grep -o "idempotency-check" | wc -l)
+[ "$IDEMPOTENT_COPIES" -eq 1 ] && echo "PASS: command queued once" || echo "FAIL: command queued $IDEMPOTENT_COPIES times"
+curl -s -o /dev/null -X DELETE "$BASE_URL/command/queue?node_id=987671" -H "X-Api-Key: $CLI_KEY"
+echo ""
+
+# Test 3b8: Commands for a node that has never connected
+echo "Test 3b8: Command targeting an unknown node (reject_unknown_node_target=$REJECT_UNKNOWN_NODE_TARGET)"
+UNKNOWN_NODE_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_filter", "parameters": {"node_id": 987654, "value": "unknown-check"}}')
+if [ "$REJECT_UNKNOWN_NODE_TARGET" = "true" ]; then
+  [ "$UNKNOWN_NODE_STATUS" = "404" ] && echo "PASS: unknown node rejected" || echo "FAIL: expected 404, got $UNKNOWN_NODE_STATUS"
+else
+  [ "$UNKNOWN_NODE_STATUS" = "200" ] && echo "PASS: command pre-provisioned for unknown node" || echo "FAIL: expected 200, got $UNKNOWN_NODE_STATUS"
+  curl -s -X DELETE "$BASE_URL/command/queue?node_id=987654" -H "X-Api-Key: $CLI_KEY" > /dev/null
+fi
+echo ""
+
+# Test 3b8a: node_id sent as a JSON string
+echo "Test 3b8a: Target a node with a quoted node_id"
+QUOTED_NODE_RESPONSE=$(curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_filter", "parameters": {"node_id": "'$NODE_ID'", "value": "quoted-check"}}')
+echo "$QUOTED_NODE_RESPONSE" | grep -q '"commands_created":1' && ! echo "$QUOTED_NODE_RESPONSE" | grep -q broadcast_id && echo "PASS: quoted node_id targets one node" || echo "FAIL: unexpected response: $QUOTED_NODE_RESPONSE"
+BAD_NODE_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_filter", "parameters": {"node_id": "abc"}}')
+[ "$BAD_NODE_STATUS" = "400" ] && echo "PASS: non-numeric node_id rejected" || echo "FAIL: expected 400, got $BAD_NODE_STATUS"
+curl -s -X DELETE "$BASE_URL/command/queue?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" > /dev/null
+echo ""
+
+# Test 3b9: Gzip-compressed command body
+echo "Test 3b9: Submit a gzip-compressed command"
+GZIP_COMMAND_RESPONSE=$(printf '%s' "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": $NODE_ID, \"value\": \"gzip-check\"}}" | gzip -c | \
+  curl -s -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "Content-Encoding: gzip" \
+  -H "X-Api-Key: $CLI_KEY" \
+  --data-binary @-)
+echo "Response: $GZIP_COMMAND_RESPONSE"
+curl -s -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" | grep -q "gzip-check" && echo "PASS: gzip command queued" || echo "FAIL: gzip command not queued"
+curl -s -X DELETE "$BASE_URL/command/queue?node_id=$NODE_ID" -H "X-Api-Key: $CLI_KEY" > /dev/null
+echo ""
+
+# Test 3b9a: Per-node pending command limit
+echo "Test 3b9a: Commands beyond max_pending_commands ($MAX_PENDING_COMMANDS) are refused"
+if [ "$MAX_PENDING_COMMANDS" -gt 0 ]; then
+  for i in $(seq 1 "$MAX_PENDING_COMMANDS"); do
+    curl -s -o /dev/null -X POST "$BASE_URL/command" \
+      -H "Content-Type: application/json" \
+      -H "X-Api-Key: $CLI_KEY" \
+      -d "{\"command\": \"set_filter\", \"parameters\": {\"node_id\": 987655, \"value\": \"flood-$i\"}}"
+  done
+  FLOOD_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $CLI_KEY" \
+    -d '{"command": "set_filter", "parameters": {"node_id": 987655, "value": "flood-over"}}')
+  [ "$FLOOD_STATUS" = "429" ] && echo "PASS: command over the limit rejected" || echo "FAIL: expected 429, got $FLOOD_STATUS"
+  curl -s -X DELETE "$BASE_URL/command/queue?node_id=987655" -H "X-Api-Key: $CLI_KEY" > /dev/null
+else
+  echo "SKIP: max_pending_commands is disabled"
+fi
+echo ""
+
+# Test 3c: Command delivery latency statistics
+echo "Test 3c: Query command delivery latency via /stats endpoint"
+STATS_RESPONSE=$(curl -s -X GET "$BASE_URL/stats" \
+  -H "X-Api-Key: $CLI_KEY")
+
+echo "Response: $STATS_RESPONSE"
 echo ""
 
 # Test 4: Download logs
@@ -75,6 +544,254 @@ DOWNLOAD_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0" \
 echo "Response: $DOWNLOAD_RESPONSE"
 echo ""
 
+# Test 4a: Download logs as CSV
+echo "Test 4a: Download logs as CSV via /download endpoint"
+CSV_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&format=csv" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+
+echo "Response: $CSV_RESPONSE"
+echo "$CSV_RESPONSE" | head -n 1 | grep -q "^item_id,timestamp,node_id,message,repeat_count" && echo "PASS: CSV header row" || echo "FAIL: missing CSV header row"
+echo ""
+
+# Test 4a1: Page size validation
+echo "Test 4a1: Validate the limit parameter of /download endpoint"
+for LIMIT in 0 -5 999999999999999999999; do
+  LIMIT_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&limit=$LIMIT&debug=true" \
+    -H "X-Api-Key: $COLLECTOR_KEY")
+  case "$LIMIT" in
+    999999999999999999999) EXPECTED='"limit":10000' ;;
+    *) EXPECTED='"limit":1' ;;
+  esac
+  echo "$LIMIT_RESPONSE" | grep -q "$EXPECTED" && echo "PASS: limit=$LIMIT clamped" || echo "FAIL: limit=$LIMIT not clamped: $LIMIT_RESPONSE"
+done
+LIMIT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=0&limit=abc" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+[ "$LIMIT_STATUS" = "400" ] && echo "PASS: limit=abc rejected" || echo "FAIL: limit=abc returned $LIMIT_STATUS"
+echo ""
+
+# Test 4a2: Versioned download response
+echo "Test 4a2: Select the download response shape with api_version"
+V1_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+! echo "$V1_RESPONSE" | grep -q '"has_more"' && echo "PASS: v1 is the default shape" || echo "FAIL: default response carries v2 fields"
+V2_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&api_version=v2" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "$V2_RESPONSE" | grep -q '"has_more"' && echo "$V2_RESPONSE" | grep -q '"last_item_id"' && echo "PASS: v2 adds paging metadata" || echo "FAIL: v2 response missing metadata"
+VERSION_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=0&api_version=v9" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+[ "$VERSION_STATUS" = "400" ] && echo "PASS: unknown api_version rejected" || echo "FAIL: expected 400, got $VERSION_STATUS"
+echo ""
+
+# Test 4a3: Relative lower bound
+echo "Test 4a3: Restrict /download to a recent window with since"
+SINCE_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&since=15m&debug=true" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "$SINCE_RESPONSE" | grep -q '"from":"20' && echo "PASS: since resolved to a lower bound" || echo "FAIL: since not applied: $SINCE_RESPONSE"
+for SINCE in 15d abc -5m; do
+  SINCE_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=0&since=$SINCE" \
+    -H "X-Api-Key: $COLLECTOR_KEY")
+  [ "$SINCE_STATUS" = "400" ] && echo "PASS: since=$SINCE rejected" || echo "FAIL: since=$SINCE returned $SINCE_STATUS"
+done
+echo ""
+
+# Test 4a4: Configured download ordering
+echo "Test 4a4: Download ordering follows download_order_by ($DOWNLOAD_ORDER_BY)"
+case "$DOWNLOAD_ORDER_BY" in
+  id) EXPECTED_ORDER='"order":"id"' ;;
+  received_at) EXPECTED_ORDER='"order":"received_at,id"' ;;
+  *) EXPECTED_ORDER='"order":"timestamp,node_id,id"' ;;
+esac
+ORDER_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&tiebreak=node_id&debug=true&api_version=v2" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "$ORDER_RESPONSE" | grep -q "$EXPECTED_ORDER" && echo "PASS: $DOWNLOAD_ORDER_BY ordering applied" || echo "FAIL: expected $EXPECTED_ORDER: $ORDER_RESPONSE"
+ORDER_IDS=$(echo "$ORDER_RESPONSE" | grep -o '"item_id":[0-9]*' | cut -d: -f2)
+if [ "$DOWNLOAD_ORDER_BY" != "timestamp" ]; then
+  [ "$ORDER_IDS" = "$(echo "$ORDER_IDS" | sort -n)" ] && echo "PASS: entries in insertion order" || echo "FAIL: entries out of insertion order"
+fi
+echo ""
+
+# Test 4a5: Highlight search matches
+echo "Test 4a5: Match offsets for q= with highlight=true"
+HIGHLIGHT_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&q=LOG%20message&highlight=true" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "$HIGHLIGHT_RESPONSE" | grep -q '"matches":\[\[12,23\]\]' && echo "PASS: match offsets returned" || echo "FAIL: unexpected highlight response: $HIGHLIGHT_RESPONSE"
+HIGHLIGHT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=0&highlight=true" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+[ "$HIGHLIGHT_STATUS" = "400" ] && echo "PASS: highlight without q rejected" || echo "FAIL: expected 400, got $HIGHLIGHT_STATUS"
+echo ""
+
+# Test 4a5a: 204 for an empty page on request
+echo "Test 4a5a: Empty download with Prefer: return-204-empty"
+EMPTY_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=1000000000" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "Prefer: return-204-empty")
+[ "$EMPTY_STATUS" = "204" ] && echo "PASS: empty page returned 204" || echo "FAIL: expected 204, got $EMPTY_STATUS"
+EMPTY_DEFAULT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=1000000000" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+[ "$EMPTY_DEFAULT_STATUS" = "200" ] && echo "PASS: empty page without preference returned 200" || echo "FAIL: expected 200, got $EMPTY_DEFAULT_STATUS"
+echo ""
+
+# Test 4a6: Filter by message source
+echo "Test 4a6: Upload a log with a source and filter on it"
+curl -s -o /dev/null -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  -d '{"logs": [{"timestamp": "'"$(date -u +%Y-%m-%dT%H:%M:%SZ)"'", "message": "[INFO] Source check", "source": "radio"}]}'
+SOURCE_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&source=radio" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "$SOURCE_RESPONSE" | grep -q '"message":"\[INFO\] Source check","repeat_count":1,"source":"radio"' && echo "PASS: source stored and filtered" || echo "FAIL: unexpected source response: $SOURCE_RESPONSE"
+curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true&source=sensor" \
+  -H "X-Api-Key: $COLLECTOR_KEY" | grep -q "Source check" && echo "FAIL: source filter returned another source" || echo "PASS: other sources excluded"
+echo ""
+
+# Test 4a7: Metric values and the time-series query
+echo "Test 4a7: Upload measurements and query them via /metrics/query"
+curl -s -o /dev/null -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  -d '{"logs": [{"timestamp": "2025-10-24T12:02:00Z", "message": "[INFO] temperature=21.5", "metric": "temperature", "value": 21.5}, {"timestamp": "2025-10-24T12:07:00Z", "message": "[INFO] temperature=21.75", "metric": "temperature", "value": 21.75}]}'
+METRIC_RESPONSE=$(curl -s -X GET "$BASE_URL/metrics/query?metric=temperature&node_id=4343&from=2025-10-24T12:00:00Z&to=2025-10-24T13:00:00Z" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "Response: $METRIC_RESPONSE"
+echo "$METRIC_RESPONSE" | grep -q '"timestamp":"2025-10-24T12:02:00Z","value":21.5},{"node_id":4343,"timestamp":"2025-10-24T12:07:00Z","value":21.75}' && echo "PASS: metric points returned in order" || echo "FAIL: unexpected metric response"
+curl -s -X GET "$BASE_URL/metrics/query?metric=temperature&node_id=987656" -H "X-Api-Key: $COLLECTOR_KEY" | grep -q '"points":\[\]' && echo "PASS: node filter applied" || echo "FAIL: node filter returned other nodes' points"
+NO_METRIC_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/metrics/query" -H "X-Api-Key: $COLLECTOR_KEY")
+[ "$NO_METRIC_STATUS" = "400" ] && echo "PASS: missing metric rejected" || echo "FAIL: expected 400, got $NO_METRIC_STATUS"
+HALF_METRIC_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4343" \
+  -d '{"logs": [{"timestamp": "2025-10-24T12:08:00Z", "message": "[INFO] ok"}, {"timestamp": "2025-10-24T12:09:00Z", "message": "[INFO] humidity", "metric": "humidity"}]}')
+[ "$STRUCTURED_ERRORS" = "true" ] && EXPECTED_ERROR='"error":"incomplete_metric",.*"index":1' || EXPECTED_ERROR='Entry 1 has a metric without a value or a value without a metric'
+echo "$HALF_METRIC_RESPONSE" | grep -q "$EXPECTED_ERROR" && echo "PASS: metric without value rejected" || echo "FAIL: unexpected response: $HALF_METRIC_RESPONSE"
+echo ""
+
+# Test 4b: Replay a time window regardless of the settlement cutoff
+echo "Test 4b: Replay logs via /download/replay endpoint"
+REPLAY_RESPONSE=$(curl -s -X GET "$BASE_URL/download/replay?from=2025-10-24T00:00:00Z&to=2025-10-25T00:00:00Z" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+
+echo "Response: $REPLAY_RESPONSE"
+echo ""
+
+# Test 4b1: Replay as gzip-compressed NDJSON
+echo "Test 4b1: Replay logs as gzip NDJSON via /download/replay endpoint"
+REPLAY_HEADERS=$(curl -s -D - -o /dev/null -X GET "$BASE_URL/download/replay?from=2025-10-24T00:00:00Z&to=2025-10-25T00:00:00Z" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "Accept: application/x-ndjson" \
+  -H "Accept-Encoding: gzip")
+
+echo "$REPLAY_HEADERS" | grep -qi "content-encoding: gzip" && echo "PASS: replay gzip-compressed" || echo "FAIL: replay not gzip-compressed"
+echo ""
+
+# Test 4b2: Live tail as Server-Sent Events
+echo "Test 4b2: Tail logs via /download/stream endpoint"
+STREAM_RESPONSE=$(curl -s -N --max-time 3 -X GET "$BASE_URL/download/stream?last_log_message_id=0" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+echo "$STREAM_RESPONSE" | grep -q '^data: {"item_id"' && echo "PASS: logs streamed as SSE events" || echo "FAIL: unexpected stream response: $STREAM_RESPONSE"
+echo ""
+
+# Test 4c: Fetch a single entry by id
+echo "Test 4c: Fetch one log entry via /download/{item_id} endpoint"
+ITEM_ID=$(echo "$CSV_RESPONSE" | sed -n '2s/^\([0-9]*\),.*/\1/p')
+ITEM_RESPONSE=$(curl -s -X GET "$BASE_URL/download/${ITEM_ID:-1}" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+
+echo "Response: $ITEM_RESPONSE"
+echo "$ITEM_RESPONSE" | grep -q "\"item_id\":${ITEM_ID:-1}," && echo "PASS: single entry returned" || echo "FAIL: single entry not returned"
+echo ""
+
+# Test 4d: Server-side collector cursor
+echo "Test 4d: Reset and use a server-side collector cursor"
+CURSOR_RESET_RESPONSE=$(curl -s -X POST "$BASE_URL/download/cursor/reset" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Collector-ID: test-collector" \
+  -d '{"last_log_message_id": 0}')
+
+echo "Response: $CURSOR_RESET_RESPONSE"
+STORED_CURSOR_RESPONSE=$(curl -s -X GET "$BASE_URL/download?include_unsettled=true" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Collector-ID: test-collector")
+echo "$STORED_CURSOR_RESPONSE" | grep -q '"item_id"' && echo "PASS: download resumed from stored cursor" || echo "FAIL: no logs from stored cursor"
+GROUPED_CURSOR_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?tiebreak=node_id" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Collector-ID: test-collector")
+[ "$GROUPED_CURSOR_STATUS" = "400" ] && echo "PASS: node_id tiebreak rejected for a stored cursor" || echo "FAIL: expected 400, got $GROUPED_CURSOR_STATUS"
+echo ""
+
+# Test 4d1: Collector acknowledgement of stored logs
+echo "Test 4d1: Acknowledge stored logs via /download/ack endpoint"
+ACK_HIGH=$(curl -s -X POST "$BASE_URL/download/ack?last_id=1000000000" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Collector-ID: test-collector")
+echo "Response: $ACK_HIGH"
+ACK_LOW=$(curl -s -X POST "$BASE_URL/download/ack?last_id=1" \
+  -H "X-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Collector-ID: test-collector")
+echo "$ACK_LOW" | grep -q '"acked_id":1000000000' && echo "PASS: ack does not move backwards" || echo "FAIL: unexpected ack response: $ACK_LOW"
+echo ""
+
+# Test 4e: Combined upload and download
+echo "Test 4e: Upload and download in one request via /sync endpoint"
+SYNC_RESPONSE=$(curl -s -X POST "$BASE_URL/sync" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Collector-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"logs": [], "last_log_message_id": 0}')
+
+echo "Response: $SYNC_RESPONSE"
+echo "$SYNC_RESPONSE" | grep -q '"update_interval"' && echo "$SYNC_RESPONSE" | grep -q '"has_more"' && echo "PASS: sync response combines update and download" || echo "FAIL: incomplete sync response"
+SYNC_NO_CURSOR_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/sync" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Collector-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"logs": [{"timestamp": "2025-10-24T12:00:00Z", "message": "[INFO] sync without cursor"}]}')
+[ "$SYNC_NO_CURSOR_STATUS" = "400" ] && echo "PASS: sync without a cursor rejected before upload" || echo "FAIL: expected 400, got $SYNC_NO_CURSOR_STATUS"
+GZIP_SYNC_RESPONSE=$(printf '%s' '{"logs": [{"timestamp": "2025-10-24T12:02:00Z", "message": "[INFO] gzip sync"}], "last_log_message_id": 0}' | gzip -c | \
+  curl -s -X POST "$BASE_URL/sync" \
+  -H "Content-Type: application/json" \
+  -H "Content-Encoding: gzip" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Collector-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  --data-binary @-)
+echo "$GZIP_SYNC_RESPONSE" | grep -q '"has_more"' && echo "PASS: gzip sync accepted" || echo "FAIL: unexpected gzip sync response: $GZIP_SYNC_RESPONSE"
+echo ""
+
+# Test 4e1: Gateway upload, command fetch and download ack in one call
+echo "Test 4e1: Upload and acknowledge via /gateway/sync endpoint"
+GATEWAY_SYNC_RESPONSE=$(curl -s -X POST "$BASE_URL/gateway/sync" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Collector-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -H "X-Collector-ID: test-gateway" \
+  -d '{"logs": [], "ack_last_id": 1000000000}')
+echo "Response: $GATEWAY_SYNC_RESPONSE"
+echo "$GATEWAY_SYNC_RESPONSE" | grep -q '"commands"' && echo "$GATEWAY_SYNC_RESPONSE" | grep -q '"acked_id":1000000000' && echo "PASS: gateway sync returns commands and ack" || echo "FAIL: incomplete gateway sync response"
+GATEWAY_NO_ID_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/gateway/sync" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Collector-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  -d '{"logs": [], "ack_last_id": 5}')
+[ "$GATEWAY_NO_ID_STATUS" = "400" ] && echo "PASS: ack without collector id rejected" || echo "FAIL: expected 400, got $GATEWAY_NO_ID_STATUS"
+GZIP_GATEWAY_STATUS=$(printf '%s' '{"logs": [{"timestamp": "2025-10-24T12:02:05Z", "message": "[INFO] gzip gateway sync"}]}' | gzip -c | \
+  curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/gateway/sync" \
+  -H "Content-Type: application/json" \
+  -H "Content-Encoding: gzip" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Collector-Api-Key: $COLLECTOR_KEY" \
+  -H "X-Node-ID: $NODE_ID" \
+  --data-binary @-)
+[ "$GZIP_GATEWAY_STATUS" = "200" ] && echo "PASS: gzip gateway sync accepted" || echo "FAIL: expected 200, got $GZIP_GATEWAY_STATUS"
+echo ""
+
 # Test 5: Test authentication failure
 echo "Test 5: Test authentication failure (should return 401)"
 AUTH_FAILURE=$(curl -s -w "\nHTTP Status: %{http_code}" -X POST "$BASE_URL/update" \
@@ -88,4 +805,417 @@ AUTH_FAILURE=$(curl -s -w "\nHTTP Status: %{http_code}" -X POST "$BASE_URL/updat
 echo "$AUTH_FAILURE"
 echo ""
 
+# Test 5a: Client mistakes are 4xx errors with an explicit content-type
+echo "Test 5a: Client errors return 400/401 with a content-type"
+[ "$STRUCTURED_ERRORS" = "true" ] && ERROR_CONTENT_TYPE="application/json" || ERROR_CONTENT_TYPE="text/plain"
+NO_KEY_HEADERS=$(curl -s -D - -o /dev/null -X GET "$BASE_URL/download?last_log_message_id=0" | tr -d '\r')
+echo "$NO_KEY_HEADERS" | grep -q '^HTTP/[0-9.]* 401' && echo "$NO_KEY_HEADERS" | grep -qi "^content-type: $ERROR_CONTENT_TYPE" && echo "PASS: missing X-Api-Key is a $ERROR_CONTENT_TYPE 401" || echo "FAIL: missing X-Api-Key not a $ERROR_CONTENT_TYPE 401"
+NO_CURSOR_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download" \
+  -H "X-Api-Key: $COLLECTOR_KEY")
+[ "$NO_CURSOR_STATUS" = "400" ] && echo "PASS: missing last_log_message_id rejected" || echo "FAIL: expected 400, got $NO_CURSOR_STATUS"
+MALFORMED_COMMAND_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": ')
+[ "$MALFORMED_COMMAND_STATUS" = "400" ] && echo "PASS: malformed command body rejected" || echo "FAIL: expected 400, got $MALFORMED_COMMAND_STATUS"
+BAD_INTERVAL_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"command": "set_update_interval", "parameters": {"start_time": "soon"}}')
+[ "$BAD_INTERVAL_STATUS" = "400" ] && echo "PASS: incomplete set_update_interval rejected" || echo "FAIL: expected 400, got $BAD_INTERVAL_STATUS"
+echo ""
+
+# Test 5b: Registered nodes must present their secret
+echo "Test 5b: Register a node and upload with and without its secret"
+REGISTERED_NODE_ID=99
+REGISTER_RESPONSE=$(curl -s -X POST "$BASE_URL/nodes/register" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d "{\"node_id\": $REGISTERED_NODE_ID}")
+
+echo "Response: $REGISTER_RESPONSE"
+NODE_SECRET=$(echo "$REGISTER_RESPONSE" | sed -n 's/.*"secret":"\([0-9a-f]*\)".*/\1/p')
+NO_SECRET_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $REGISTERED_NODE_ID" \
+  -d '{"logs": []}')
+[ "$NO_SECRET_STATUS" = "403" ] && echo "PASS: upload without secret rejected" || echo "FAIL: expected 403, got $NO_SECRET_STATUS"
+SECRET_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $REGISTERED_NODE_ID" \
+  -H "X-Node-Secret: $NODE_SECRET" \
+  -d '{"logs": []}')
+[ "$SECRET_STATUS" = "200" ] && echo "PASS: upload with secret accepted" || echo "FAIL: expected 200, got $SECRET_STATUS"
+ACK_NO_SECRET_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command/ack" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $REGISTERED_NODE_ID" \
+  -d '{"command_ids": [1]}')
+[ "$ACK_NO_SECRET_STATUS" = "403" ] && echo "PASS: ack without secret rejected" || echo "FAIL: expected 403, got $ACK_NO_SECRET_STATUS"
+RESULT_NO_SECRET_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command/result" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: $REGISTERED_NODE_ID" \
+  -d '{"command": "run_command", "result": {"output": "forged", "exit_code": 0}}')
+[ "$RESULT_NO_SECRET_STATUS" = "403" ] && echo "PASS: result without secret rejected" || echo "FAIL: expected 403, got $RESULT_NO_SECRET_STATUS"
+# Registering alone is not a sign of life
+curl -s -o /dev/null -X POST "$BASE_URL/nodes/register" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $CLI_KEY" \
+  -d '{"node_id": 987670}'
+curl -s -X GET "$BASE_URL/nodes" -H "X-Api-Key: $CLI_KEY" | grep -q '"node_id":987670,"last_seen":null' && echo "PASS: registered node has no last_seen" || echo "FAIL: registered node looks seen"
+echo ""
+
+# Test 5c: Bearer tokens with auth_mode = jwt
+echo "Test 5c: JWT authentication and scope checks"
+if [ -n "$JWT_SECRET" ]; then
+  b64url() { openssl base64 -A | tr '+/' '-_' | tr -d '='; }
+  jwt_for_scope() {
+    local header payload signature
+    header=$(printf '{"alg":"HS256","typ":"JWT"}' | b64url)
+    payload=$(printf '{"exp":%d,"scope":"%s"}' "$(( $(date +%s) + 300 ))" "$1" | b64url)
+    signature=$(printf '%s' "$header.$payload" | openssl dgst -sha256 -hmac "$JWT_SECRET" -binary | b64url)
+    echo "$header.$payload.$signature"
+  }
+  CLI_JWT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" \
+    -H "Authorization: Bearer $(jwt_for_scope cli)")
+  [ "$CLI_JWT_STATUS" = "200" ] && echo "PASS: cli-scoped token accepted" || echo "FAIL: expected 200, got $CLI_JWT_STATUS"
+  PROBE_JWT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/command/peek?node_id=$NODE_ID" \
+    -H "Authorization: Bearer $(jwt_for_scope probe)")
+  [ "$PROBE_JWT_STATUS" = "401" ] && echo "PASS: token without cli scope rejected" || echo "FAIL: expected 401, got $PROBE_JWT_STATUS"
+  # Admin tokens only work while the hub's admin_api_key is set (ADMIN_KEY empty: expect 401)
+  ADMIN_JWT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/admin/kv" \
+    -H "Authorization: Bearer $(jwt_for_scope admin)")
+  EXPECTED_ADMIN_JWT_STATUS=$([ -n "$ADMIN_KEY" ] && echo 200 || echo 401)
+  [ "$ADMIN_JWT_STATUS" = "$EXPECTED_ADMIN_JWT_STATUS" ] && echo "PASS: admin token follows admin_api_key" || echo "FAIL: expected $EXPECTED_ADMIN_JWT_STATUS, got $ADMIN_JWT_STATUS"
+else
+  echo "SKIP: run the hub with auth_mode = jwt and set JWT_SECRET to run"
+fi
+echo ""
+
+# Test 5d: Tenant-scoped upload and download with enable_tenancy
+echo "Test 5d: Tenancy isolation"
+if [ -n "$TENANT" ]; then
+  curl -s -o /dev/null -X POST "$BASE_URL/update" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $TENANT_PROBE_KEY" \
+    -H "X-Tenant: $TENANT" \
+    -H "X-Node-ID: $NODE_ID" \
+    -d '{"logs": [{"timestamp": "'"$(date -u +%Y-%m-%dT%H:%M:%SZ)"'", "message": "[INFO] Tenant check"}]}'
+  curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true" \
+    -H "X-Api-Key: $TENANT_COLLECTOR_KEY" \
+    -H "X-Tenant: $TENANT" | grep -q "Tenant check" && echo "PASS: tenant sees its own logs" || echo "FAIL: tenant log missing from its download"
+  NO_TENANT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=0" \
+    -H "X-Api-Key: $TENANT_COLLECTOR_KEY")
+  [ "$NO_TENANT_STATUS" = "400" ] && echo "PASS: download without X-Tenant rejected" || echo "FAIL: expected 400, got $NO_TENANT_STATUS"
+  OTHER_TENANT_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X GET "$BASE_URL/download?last_log_message_id=0" \
+    -H "X-Api-Key: $TENANT_COLLECTOR_KEY" \
+    -H "X-Tenant: $TENANT-other")
+  [ "$OTHER_TENANT_STATUS" = "401" ] && echo "PASS: key rejected for another tenant" || echo "FAIL: expected 401, got $OTHER_TENANT_STATUS"
+  TENANT_ACK_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/command/ack" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $TENANT_PROBE_KEY" \
+    -H "X-Tenant: $TENANT" \
+    -H "X-Node-ID: $NODE_ID" \
+    -d '{"command_ids": [999999999]}')
+  [ "$TENANT_ACK_STATUS" = "200" ] && echo "PASS: tenant probe acks for its own node" || echo "FAIL: expected 200, got $TENANT_ACK_STATUS"
+  TENANT_RESET_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/download/cursor/reset" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $TENANT_COLLECTOR_KEY" \
+    -H "X-Tenant: $TENANT" \
+    -H "X-Collector-ID: tenant-collector" \
+    -d '{"last_log_message_id": 0}')
+  [ "$TENANT_RESET_STATUS" = "200" ] && echo "PASS: tenant collector resets its cursor" || echo "FAIL: expected 200, got $TENANT_RESET_STATUS"
+  curl -s -X GET "$BASE_URL/admin/kv" -H "X-Api-Key: $ADMIN_KEY" | grep -q "\"collector_cursor:$TENANT:tenant-collector\"" && echo "PASS: cursor stored under the tenant" || echo "FAIL: tenant cursor key missing"
+else
+  echo "SKIP: run the hub with enable_tenancy = true and set TENANT, TENANT_PROBE_KEY and TENANT_COLLECTOR_KEY to run"
+fi
+echo ""
+
+# Test 6: Version information (no authentication)
+echo "Test 6: Query deployed version via /version endpoint"
+VERSION_RESPONSE=$(curl -s -X GET "$BASE_URL/version")
+
+echo "Response: $VERSION_RESPONSE"
+echo ""
+
+# Test 6a: Server time (no authentication)
+echo "Test 6a: Query server time via /time endpoint"
+TIME_RESPONSE=$(curl -s -X GET "$BASE_URL/time")
+echo "Response: $TIME_RESPONSE"
+echo "$TIME_RESPONSE" | grep -q '"epoch_ms":[0-9]\{13\}' && echo "PASS: server time returned" || echo "FAIL: unexpected time response"
+echo ""
+
+# Test 7: Vacuum the database
+echo "Test 7: Reclaim disk space via /maintenance/vacuum endpoint"
+VACUUM_RESPONSE=$(curl -s -w "\nHTTP Status: %{http_code}" -X POST "$BASE_URL/maintenance/vacuum" \
+  -H "X-Api-Key: $ADMIN_KEY")
+
+echo "$VACUUM_RESPONSE"
+echo ""
+
+# Test 7a: Rebuild indexes
+echo "Test 7a: Rebuild indexes via /maintenance/reindex endpoint"
+REINDEX_RESPONSE=$(curl -s -X POST "$BASE_URL/maintenance/reindex" \
+  -H "X-Api-Key: $ADMIN_KEY")
+echo "Response: $REINDEX_RESPONSE"
+echo "$REINDEX_RESPONSE" | grep -q '"table":"log_messages"' && echo "PASS: indexes rebuilt" || echo "FAIL: unexpected reindex response"
+echo ""
+
+# Test 7b: Size-based reclaim with nodes tied for largest
+echo "Test 7b: node_reclaim_threshold trims equally sized nodes back under the threshold"
+if [ "$NODE_RECLAIM_THRESHOLD" -gt 0 ] && [ "$NODE_RECLAIM_THRESHOLD" -le 5000 ]; then
+  RECLAIM_TIMESTAMP=$(date -u +%Y-%m-%dT%H:%M:%SZ)
+  for RECLAIM_NODE in 987658 987659; do
+    seq 1 "$NODE_RECLAIM_THRESHOLD" | awk -v ts="$RECLAIM_TIMESTAMP" \
+      'BEGIN {printf "{\"logs\": ["} {printf "%s{\"timestamp\": \"%s\", \"message\": \"Reclaim row %d\"}", (NR > 1 ? "," : ""), ts, $1} END {print "]}"}' | \
+      curl -s -o /dev/null -X POST "$BASE_URL/update" \
+      -H "Content-Type: application/json" \
+      -H "X-Api-Key: $PROBE_KEY" \
+      -H "X-Node-ID: $RECLAIM_NODE" \
+      --data-binary @-
+  done
+  curl -s -o /dev/null -X POST "$BASE_URL/update" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $PROBE_KEY" \
+    -H "X-Node-ID: $NODE_ID" \
+    -d '{"logs": []}'
+  RECLAIM_RESPONSE=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true" \
+    -H "X-Api-Key: $COLLECTOR_KEY")
+  RECLAIM_TOTAL=$(echo "$RECLAIM_RESPONSE" | grep -o '"item_id"' | wc -l)
+  RECLAIM_SPLIT="$(echo "$RECLAIM_RESPONSE" | grep -o '"node_id":987658' | wc -l) $(echo "$RECLAIM_RESPONSE" | grep -o '"node_id":987659' | wc -l)"
+  [ "$RECLAIM_TOTAL" -le "$NODE_RECLAIM_THRESHOLD" ] && echo "PASS: $RECLAIM_TOTAL rows left (threshold $NODE_RECLAIM_THRESHOLD)" || echo "FAIL: $RECLAIM_TOTAL rows left above threshold $NODE_RECLAIM_THRESHOLD"
+  echo "Tied nodes now hold: $RECLAIM_SPLIT"
+else
+  echo "SKIP: set node_reclaim_threshold (1-5000) and cleanup_interval_minutes = 0, and export NODE_RECLAIM_THRESHOLD"
+fi
+echo ""
+
+# Test 8: Key-value store state
+echo "Test 8: Inspect key-value store state via /admin/kv endpoint"
+KV_RESPONSE=$(curl -s -w "\nHTTP Status: %{http_code}" -X GET "$BASE_URL/admin/kv" \
+  -H "X-Api-Key: $ADMIN_KEY")
+
+echo "$KV_RESPONSE"
+echo ""
+
+# Test 8a: Concurrent uploads that may all trigger cleanup
+echo "Test 8a: Fire concurrent uploads; at most one claims the cleanup"
+CONCURRENT_DIR=$(mktemp -d)
+for i in 1 2 3 4 5; do
+  curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/update" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $PROBE_KEY" \
+    -H "X-Node-ID: $NODE_ID" \
+    -d '{"logs": []}' > "$CONCURRENT_DIR/$i" &
+done
+wait
+CONCURRENT_STATUSES=$(cat "$CONCURRENT_DIR"/* | tr -d '\n')
+rm -rf "$CONCURRENT_DIR"
+[ "$CONCURRENT_STATUSES" = "200200200200200" ] && echo "PASS: every concurrent upload succeeded" || echo "FAIL: statuses $CONCURRENT_STATUSES"
+VACUUM_AFTER_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/maintenance/vacuum" \
+  -H "X-Api-Key: $ADMIN_KEY")
+[ "$VACUUM_AFTER_STATUS" = "200" ] && echo "PASS: maintenance lock released" || echo "FAIL: expected 200, got $VACUUM_AFTER_STATUS"
+# Concurrent maintenance calls: each either holds the lock or is turned away
+MAINTENANCE_DIR=$(mktemp -d)
+for i in 1 2 3 4 5; do
+  curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/maintenance/vacuum" \
+    -H "X-Api-Key: $ADMIN_KEY" > "$MAINTENANCE_DIR/$i" &
+done
+wait
+MAINTENANCE_STATUSES=$(cat "$MAINTENANCE_DIR"/* | tr -d '\n')
+rm -rf "$MAINTENANCE_DIR"
+echo "$MAINTENANCE_STATUSES" | grep -qE '^(200|409){5}$' && echo "PASS: concurrent maintenance answered 200 or 409" || echo "FAIL: statuses $MAINTENANCE_STATUSES"
+# While another run holds the lock, every concurrent caller is turned away
+if command -v sqlite3 > /dev/null && [ -f "$HUB_DB_PATH" ]; then
+  sqlite3 "$HUB_DB_PATH" "INSERT OR REPLACE INTO maintenance_lock (id, token, locked_at) VALUES (1, 'test-held', '$(date -u +%Y-%m-%dT%H:%M:%S.000Z)')"
+  HELD_DIR=$(mktemp -d)
+  for i in 1 2 3 4 5; do
+    curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/maintenance/vacuum" \
+      -H "X-Api-Key: $ADMIN_KEY" > "$HELD_DIR/$i" &
+  done
+  wait
+  HELD_STATUSES=$(cat "$HELD_DIR"/* | tr -d '\n')
+  rm -rf "$HELD_DIR"
+  sqlite3 "$HUB_DB_PATH" "DELETE FROM maintenance_lock WHERE token = 'test-held'"
+  [ "$HELD_STATUSES" = "409409409409409" ] && echo "PASS: held lock turned every caller away" || echo "FAIL: statuses $HELD_STATUSES"
+else
+  echo "SKIP: needs sqlite3 and the hub database at $HUB_DB_PATH"
+fi
+echo ""
+
+# Test 8b: Webhook circuit breaker
+echo "Test 8b: Repeated webhook failures open its circuit ($WEBHOOK_FAILURE_THRESHOLD failures)"
+if [ "$WEBHOOK_FAILURE_THRESHOLD" -gt 0 ]; then
+  # Port 9 is not in allowed_outbound_hosts, so every notification fails
+  CIRCUIT_URL="http://127.0.0.1:9/moonblokz-circuit-test"
+  curl -s -o /dev/null -X PUT "$BASE_URL/webhook/987656" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $CLI_KEY" \
+    -d "{\"url\": \"$CIRCUIT_URL\"}"
+  for i in $(seq 1 "$WEBHOOK_FAILURE_THRESHOLD"); do
+    curl -s -o /dev/null -X POST "$BASE_URL/update" \
+      -H "Content-Type: application/json" \
+      -H "X-Api-Key: $PROBE_KEY" \
+      -H "X-Node-ID: 987656" \
+      -d "{\"logs\": [{\"timestamp\": \"$(date -u +%Y-%m-%dT%H:%M:%SZ)\", \"message\": \"[ERROR] circuit test $i\"}]}"
+  done
+  CIRCUIT_STATE=$(curl -s -X GET "$BASE_URL/admin/kv" \
+    -H "X-Api-Key: $ADMIN_KEY")
+  echo "$CIRCUIT_STATE" | grep -q "\"webhook_circuit:$CIRCUIT_URL\":{\"consecutive_failures\":$WEBHOOK_FAILURE_THRESHOLD,\"opened_at\"" && echo "PASS: webhook circuit opened" || echo "FAIL: webhook circuit not open"
+  curl -s -o /dev/null -X DELETE "$BASE_URL/webhook/987656" -H "X-Api-Key: $CLI_KEY"
+else
+  echo "SKIP: webhook circuit breaker is disabled"
+fi
+echo ""
+
+# Test 9: Bulk import of historical logs
+echo "Test 9: Import historical logs via /import endpoint"
+IMPORT_RESPONSE=$(curl -s -X POST "$BASE_URL/import" \
+  -H "Content-Type: application/x-ndjson" \
+  -H "X-Api-Key: $ADMIN_KEY" \
+  --data-binary $'{"node_id": 21, "timestamp": "2024-03-01T08:00:00Z", "message": "Imported row", "level": "INFO"}\n{"node_id": 21, "timestamp": "not-a-time", "message": "Bad row"}\n')
+
+echo "Response: $IMPORT_RESPONSE"
+echo "$IMPORT_RESPONSE" | grep -q '"accepted":1,"rejected":1' && echo "PASS: import counts" || echo "FAIL: unexpected import counts"
+OFFSET_MARKER="offset-import-$(date +%s)"
+curl -s -o /dev/null -X POST "$BASE_URL/import" \
+  -H "Content-Type: application/x-ndjson" \
+  -H "X-Api-Key: $ADMIN_KEY" \
+  --data-binary "{\"node_id\": 21, \"timestamp\": \"2024-03-01T10:00:00.250+02:00\", \"message\": \"$OFFSET_MARKER\"}"
+OFFSET_STORED=$(curl -s -X GET "$BASE_URL/download?last_log_message_id=0&include_unsettled=true" \
+  -H "X-Api-Key: $COLLECTOR_KEY" | grep -o "\"timestamp\":\"[^\"]*\",\"node_id\":21,\"message\":\"$OFFSET_MARKER\"" | cut -d'"' -f4)
+[ "$OFFSET_STORED" = "2024-03-01T08:00:00.250Z" ] && echo "PASS: imported timestamp stored as UTC" || echo "FAIL: expected 2024-03-01T08:00:00.250Z, got $OFFSET_STORED"
+echo ""
+
+# Test 9a: Snapshot export
+echo "Test 9a: Export a gzipped snapshot via /export/snapshot endpoint"
+SNAPSHOT_HEADERS=$(mktemp)
+SNAPSHOT_FIRST_LINE=$(curl -s -D "$SNAPSHOT_HEADERS" "$BASE_URL/export/snapshot" \
+  -H "X-Api-Key: $ADMIN_KEY" | gunzip -c | head -n 1)
+
+echo "First line: $SNAPSHOT_FIRST_LINE"
+echo "$SNAPSHOT_FIRST_LINE" | grep -q '"snapshot":{"schema_version":' && echo "PASS: snapshot header line" || echo "FAIL: missing snapshot header"
+grep -qi '^x-has-more:' "$SNAPSHOT_HEADERS" && echo "PASS: X-Has-More header present" || echo "FAIL: missing X-Has-More header"
+rm -f "$SNAPSHOT_HEADERS"
+BAD_CURSOR_STATUS=$(curl -s -o /dev/null -w "%{http_code}" "$BASE_URL/export/snapshot?cursor=bogus" \
+  -H "X-Api-Key: $ADMIN_KEY")
+[ "$BAD_CURSOR_STATUS" = "400" ] && echo "PASS: invalid cursor rejected" || echo "FAIL: expected 400, got $BAD_CURSOR_STATUS"
+echo ""
+
+# Test 9b: Snapshot round trip through /import
+echo "Test 9b: Restore an exported snapshot page via /import, twice"
+SNAPSHOT_MARKER="snapshot-marker-$(date +%s)"
+curl -s -o /dev/null -X POST "$BASE_URL/import" \
+  -H "Content-Type: application/x-ndjson" \
+  -H "X-Api-Key: $ADMIN_KEY" \
+  --data-binary "{\"node_id\": 987660, \"timestamp\": \"$(date -u +%Y-%m-%dT%H:%M:%SZ)\", \"message\": \"$SNAPSHOT_MARKER\"}"
+SNAPSHOT_FILE=$(mktemp)
+SNAPSHOT_HEADERS=$(mktemp)
+SNAPSHOT_CURSOR=""
+for PAGE in $(seq 1 20); do
+  curl -s -D "$SNAPSHOT_HEADERS" -o "$SNAPSHOT_FILE" "$BASE_URL/export/snapshot?cursor=$SNAPSHOT_CURSOR" \
+    -H "X-Api-Key: $ADMIN_KEY"
+  gunzip -c "$SNAPSHOT_FILE" | grep -q "$SNAPSHOT_MARKER" && break
+  SNAPSHOT_CURSOR=$(grep -i '^x-next-cursor:' "$SNAPSHOT_HEADERS" | cut -d' ' -f2 | tr -d '\r')
+  [ -n "$SNAPSHOT_CURSOR" ] || break
+done
+SNAPSHOT_MARKER_ID=$(gunzip -c "$SNAPSHOT_FILE" | grep "$SNAPSHOT_MARKER" | grep -o '"id":[0-9]*' | cut -d: -f2)
+if [ -n "$SNAPSHOT_MARKER_ID" ]; then
+  curl -s -o /dev/null -X DELETE "$BASE_URL/logs?from=2000-01-01T00:00:00Z&to=2100-01-01T00:00:00Z&node_id=987660" \
+    -H "X-Api-Key: $ADMIN_KEY"
+  RESTORE_RESPONSE=$(curl -s -X POST "$BASE_URL/import" \
+    -H "Content-Type: application/x-ndjson" \
+    -H "Content-Encoding: gzip" \
+    -H "X-Api-Key: $ADMIN_KEY" \
+    --data-binary @"$SNAPSHOT_FILE")
+  echo "Response: $RESTORE_RESPONSE"
+  echo "$RESTORE_RESPONSE" | grep -q '"accepted":[1-9][0-9]*,"rejected":0' && echo "PASS: gzipped page restored unchanged" || echo "FAIL: unexpected restore response"
+  curl -s -X GET "$BASE_URL/download/$SNAPSHOT_MARKER_ID" \
+    -H "X-Api-Key: $COLLECTOR_KEY" | grep -q "$SNAPSHOT_MARKER" && echo "PASS: restored row keeps its id" || echo "FAIL: restored row not found under id $SNAPSHOT_MARKER_ID"
+  RETRY_RESPONSE=$(curl -s -X POST "$BASE_URL/import" \
+    -H "Content-Type: application/x-ndjson" \
+    -H "Content-Encoding: gzip" \
+    -H "X-Api-Key: $ADMIN_KEY" \
+    --data-binary @"$SNAPSHOT_FILE")
+  echo "$RETRY_RESPONSE" | grep -q '"accepted":0,"rejected":0,"commands":0,"duplicates":[1-9]' && echo "PASS: retried page adds nothing" || echo "FAIL: retry duplicated rows: $RETRY_RESPONSE"
+else
+  echo "FAIL: marker row not found in the snapshot"
+fi
+rm -f "$SNAPSHOT_FILE" "$SNAPSHOT_HEADERS"
+echo ""
+
+# Test 10: Timestamp renormalization
+echo "Test 10: Renormalize stored timestamps via /maintenance/renormalize endpoint"
+RENORMALIZE_RESPONSE=$(curl -s -X POST "$BASE_URL/maintenance/renormalize" \
+  -H "X-Api-Key: $ADMIN_KEY")
+
+echo "Response: $RENORMALIZE_RESPONSE"
+RENORMALIZE_AGAIN=$(curl -s -X POST "$BASE_URL/maintenance/renormalize" \
+  -H "X-Api-Key: $ADMIN_KEY")
+echo "$RENORMALIZE_AGAIN" | grep -q '"fixed":0' && echo "PASS: renormalization is idempotent" || echo "FAIL: second run rewrote rows"
+echo ""
+
+# Test 10a: Manual purge of a time window
+echo "Test 10a: Purge a window of logs via DELETE /logs endpoint"
+PURGE_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X DELETE "$BASE_URL/logs" \
+  -H "X-Api-Key: $ADMIN_KEY")
+[ "$PURGE_STATUS" = "400" ] && echo "PASS: purge without a range rejected" || echo "FAIL: expected 400, got $PURGE_STATUS"
+PURGE_RESPONSE=$(curl -s -X DELETE "$BASE_URL/logs?from=2024-03-01T00:00:00Z&to=2024-03-02T00:00:00Z&node_id=21" \
+  -H "X-Api-Key: $ADMIN_KEY")
+echo "Response: $PURGE_RESPONSE"
+echo "$PURGE_RESPONSE" | grep -q '"deleted":1,"complete":true' && echo "PASS: imported row purged" || echo "FAIL: unexpected purge response"
+echo ""
+
+# Test 11: Undelete soft-deleted logs
+echo "Test 11: Restore soft-deleted logs via /maintenance/undelete endpoint"
+UNDELETE_STATUS=$(curl -s -o /dev/null -w "%{http_code}" -X POST "$BASE_URL/maintenance/undelete?from=2024-03-01T00:00:00Z" \
+  -H "X-Api-Key: $ADMIN_KEY")
+[ "$UNDELETE_STATUS" = "400" ] && echo "PASS: missing to rejected" || echo "FAIL: expected 400, got $UNDELETE_STATUS"
+UNDELETE_RESPONSE=$(curl -s -X POST "$BASE_URL/maintenance/undelete?from=2024-03-01T00:00:00Z&to=2024-03-02T00:00:00Z" \
+  -H "X-Api-Key: $ADMIN_KEY")
+echo "Response: $UNDELETE_RESPONSE"
+echo "$UNDELETE_RESPONSE" | grep -q '"restored":' && echo "PASS: undelete reports restored count" || echo "FAIL: unexpected undelete response"
+echo ""
+
+# Test 11a: Cleanup drains a backlog larger than one batch
+echo "Test 11a: Cleanup deletes more than one batch of expired logs in a run"
+if [ "$CLEANUP_INTERVAL_MINUTES" = "0" ]; then
+  seq 1 25000 | awk '{printf "{\"node_id\": 4242, \"timestamp\": \"2020-01-01T00:00:00Z\", \"message\": \"Backlog row %d\"}\n", $1}' | \
+    curl -s -o /dev/null -X POST "$BASE_URL/import" \
+    -H "Content-Type: application/x-ndjson" \
+    -H "X-Api-Key: $ADMIN_KEY" \
+    --data-binary @-
+  curl -s -o /dev/null -X POST "$BASE_URL/update" \
+    -H "Content-Type: application/json" \
+    -H "X-Api-Key: $PROBE_KEY" \
+    -H "X-Node-ID: $NODE_ID" \
+    -d '{"logs": []}'
+  CLEANUP_STATE=$(curl -s -X GET "$BASE_URL/admin/kv" -H "X-Api-Key: $ADMIN_KEY")
+  CLEANUP_DELETED=$(echo "$CLEANUP_STATE" | grep -o '"logs_deleted":[0-9]*' | cut -d: -f2)
+  [ "${CLEANUP_DELETED:-0}" -ge 25000 ] && echo "PASS: cleanup deleted $CLEANUP_DELETED rows in one run" || echo "FAIL: cleanup deleted ${CLEANUP_DELETED:-no} rows"
+  echo "$CLEANUP_STATE" | grep -q '"logs_still_eligible":0' && echo "PASS: no eligible rows left" || echo "FAIL: eligible rows left after cleanup"
+else
+  echo "SKIP: set cleanup_interval_minutes = 0 and CLEANUP_INTERVAL_MINUTES=0 to run"
+fi
+echo ""
+
+# Test 12: End-to-end self-test
+echo "Test 12: Run the deployment self-test via /selftest endpoint"
+SELFTEST_RESPONSE=$(curl -s -X POST "$BASE_URL/selftest" \
+  -H "X-Api-Key: $ADMIN_KEY")
+
+echo "Response: $SELFTEST_RESPONSE"
+echo "$SELFTEST_RESPONSE" | grep -q '"passed":true,"steps"' && echo "PASS: self-test passed" || echo "FAIL: self-test reported a failed step"
+RESERVED_NODE_RESPONSE=$(curl -s -X POST "$BASE_URL/update" \
+  -H "Content-Type: application/json" \
+  -H "X-Api-Key: $PROBE_KEY" \
+  -H "X-Node-ID: 4294967295" \
+  -d '{"logs": []}')
+[ "$STRUCTURED_ERRORS" = "true" ] && EXPECTED_ERROR='"error":"reserved_node_id"' || EXPECTED_ERROR='Node id is reserved for /selftest'
+echo "$RESERVED_NODE_RESPONSE" | grep -q "$EXPECTED_ERROR" && echo "PASS: self-test node id refused for probes" || echo "FAIL: unexpected response: $RESERVED_NODE_RESPONSE"
+echo ""
+
 echo "=== Tests completed ==="